@@ -1,16 +1,75 @@
 use std::{
     error::Error,
-    io::{Cursor, ErrorKind, Read},
+    io::{ErrorKind, Read, Write},
     time::Duration,
 };
 
-use byteorder::{LittleEndian, ReadBytesExt};
 use cgmath::{InnerSpace, Vector3};
 
-use crate::{load_resource, mesh::Vertex, resource::GLOBAL_RESOURCES};
+use crate::{
+    load_resource,
+    lump::LumpReader,
+    mesh::{self, Vertex},
+    resource::GLOBAL_RESOURCES,
+};
+
+// Effect flags of a model, the EF_* bits of the original engine.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ModelFlags(pub u32);
+
+impl ModelFlags {
+    // Leaves a fire trail, e.g. rockets.
+    pub const ROCKET: Self = Self(1);
+    // Leaves a smoke trail, e.g. grenades.
+    pub const GRENADE: Self = Self(2);
+    // Leaves a blood trail, e.g. gibs.
+    pub const GIB: Self = Self(4);
+    // Spins around the vertical axis, e.g. pickups.
+    pub const ROTATE: Self = Self(8);
+    // Leaves a green split trail, the scrag spit.
+    pub const TRACER: Self = Self(16);
+    // Leaves a thin blood trail, the zombie gibs.
+    pub const ZOMBIE_GIB: Self = Self(32);
+    // Leaves an orange split trail, the death knight fire.
+    pub const TRACER2: Self = Self(64);
+    // Leaves a purple trail, the vore ball.
+    pub const TRACER3: Self = Self(128);
+    // Palette index 255 of the skins is see-through, an extension of later
+    // engines used by models with cutouts.
+    pub const HOLEY: Self = Self(1 << 14);
+
+    pub fn contains(&self, flags: Self) -> bool {
+        self.0 & flags.0 == flags.0
+    }
+
+    pub fn is_rotating(&self) -> bool {
+        self.contains(Self::ROTATE)
+    }
+
+    pub fn is_holey(&self) -> bool {
+        self.contains(Self::HOLEY)
+    }
+
+    // Any of the particle trail flags.
+    pub fn has_trail(&self) -> bool {
+        self.0 & !Self::ROTATE.0 & 0xff != 0
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct Mdl {
+    // Scale and origin used to decompress the packed frame vertices.
+    pub scale: [f32; 3],
+    pub origin: [f32; 3],
+    // Radius of a sphere around the origin enclosing every frame.
+    pub bounding_radius: f32,
+    // Eye position, only meaningful for player models.
+    pub eye_position: [f32; 3],
+    // 0 when frames are synchronized across entities, 1 when randomized.
+    pub sync_type: i32,
+    pub flags: ModelFlags,
+    // Average triangle area, historically used by the software lighting.
+    pub size: f32,
     pub skins: Box<[Skin]>,
     pub skin_width: u32,
     pub skin_height: u32,
@@ -27,7 +86,28 @@ impl Mdl {
     {
         debug!("Loading MDL file {}", name.as_ref());
 
-        Mdl::deserialize(&mut Cursor::new(load_resource!(name.as_ref())?))
+        Mdl::deserialize(
+            name.as_ref(),
+            &mut LumpReader::new(load_resource!(name.as_ref())?),
+        )
+    }
+
+    // Scale that fits the bounding sphere of the model into the given extent,
+    // handy for viewers that need to frame arbitrary models.
+    pub fn display_scale(&self, extent: f32) -> f32 {
+        if self.bounding_radius > 0.0 {
+            extent / (2.0 * self.bounding_radius)
+        } else {
+            1.0
+        }
+    }
+
+    pub fn skin_count(&self) -> usize {
+        self.skins.len()
+    }
+
+    pub fn num_triangles(&self) -> usize {
+        self.triangles.len()
     }
 
     pub fn vertices(&self, frame: &Frame) -> Box<[Vertex]> {
@@ -38,14 +118,11 @@ impl Mdl {
             for (i, index) in triangle.indices.iter().enumerate() {
                 face[i] = frame.vertices[*index as usize];
 
-                let skin_coord = &self.skin_coords[*index as usize];
-                let s = if !triangle.faces_front && skin_coord.is_on_seam {
-                    (skin_coord.s as f32 + self.skin_width as f32 / 2.0) + 0.5
-                } else {
-                    skin_coord.s as f32 + 0.5
-                } / self.skin_width as f32;
-                let t = (skin_coord.t as f32 + 0.5) / self.skin_height as f32;
-                skin_coords[i] = [s, t];
+                skin_coords[i] = self.skin_coords[*index as usize].texcoord(
+                    triangle.faces_front,
+                    self.skin_width,
+                    self.skin_height,
+                );
             }
 
             let normal = Vector3::cross(
@@ -65,8 +142,38 @@ impl Mdl {
         vertices.into_boxed_slice()
     }
 
-    fn deserialize(reader: &mut Cursor<Vec<u8>>) -> Result<Self, Box<dyn Error>> {
-        let ident = reader.read_i32::<LittleEndian>()?;
+    // The frame as a Wavefront OBJ, see mesh::write_obj.
+    pub fn export_obj<W>(&self, frame: &Frame, writer: &mut W) -> Result<(), Box<dyn Error>>
+    where
+        W: Write,
+    {
+        mesh::write_obj(writer, &self.vertices(frame))
+    }
+
+    // Frame positions as stored in the file, unrolled per triangle like
+    // vertices so both can be drawn with the same vertex count. They are
+    // rebuilt with the model scale and origin on the GPU.
+    pub fn packed_vertices(&self, frame: &Frame) -> Box<[[u8; 4]]> {
+        self.triangles
+            .iter()
+            .flat_map(|triangle| triangle.indices.iter())
+            .map(|index| frame.packed_vertices[*index as usize])
+            .collect()
+    }
+
+    const HEADER_SIZE: u64 = 84;
+
+    // The name only identifies the model in the log. Errors past the header
+    // say which section failed.
+    fn deserialize(name: &str, reader: &mut LumpReader) -> Result<Self, Box<dyn Error>> {
+        if reader.remaining() < Self::HEADER_SIZE {
+            return Err(Box::new(std::io::Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("truncated header: {} bytes", reader.remaining()),
+            )));
+        }
+
+        let ident = reader.read_i32()?;
         if ident != 0x4f504449 {
             return Err(Box::new(std::io::Error::new(
                 ErrorKind::InvalidData,
@@ -74,7 +181,7 @@ impl Mdl {
             )));
         }
 
-        let version = reader.read_i32::<LittleEndian>()?;
+        let version = reader.read_i32()?;
         if version != 6 {
             return Err(Box::new(std::io::Error::new(
                 ErrorKind::InvalidData,
@@ -82,80 +189,179 @@ impl Mdl {
             )));
         }
 
-        let mut scale = [0f32; 3];
-        reader.read_f32_into::<LittleEndian>(&mut scale)?;
+        let scale = reader.read_vec3()?;
 
-        let mut origin = [0f32; 3];
-        reader.read_f32_into::<LittleEndian>(&mut origin)?;
+        let origin = reader.read_vec3()?;
 
-        let bounding_radius = reader.read_f32::<LittleEndian>()?;
+        let bounding_radius = reader.read_f32()?;
 
-        let mut position = [0f32; 3];
-        reader.read_f32_into::<LittleEndian>(&mut position)?;
+        let position = reader.read_vec3()?;
 
-        let num_skins = reader.read_i32::<LittleEndian>()?;
-        let skin_width = reader.read_i32::<LittleEndian>()?;
-        let skin_height = reader.read_i32::<LittleEndian>()?;
-        let num_verts = reader.read_i32::<LittleEndian>()?;
-        let num_tris = reader.read_i32::<LittleEndian>()?;
-        let num_frames: i32 = reader.read_i32::<LittleEndian>()?;
-        let sync_type = reader.read_i32::<LittleEndian>()?;
-        let flags: i32 = reader.read_i32::<LittleEndian>()?;
-        let size = reader.read_f32::<LittleEndian>()?;
+        let num_skins = reader.read_i32()?;
+        let skin_width = reader.read_i32()?;
+        let skin_height = reader.read_i32()?;
+        let num_verts = reader.read_i32()?;
+        let num_tris = reader.read_i32()?;
+        let num_frames: i32 = reader.read_i32()?;
+        let sync_type = reader.read_i32()?;
+        let flags = ModelFlags(reader.read_i32()? as u32);
+        let size = reader.read_f32()?;
 
-        debug!("MDL Header:");
+        debug!("{}: MDL Header:", name);
         debug!(
-            "  Ident:                 {}",
+            "{}:   Ident:                 {}",
+            name,
             String::from_utf8(ident.to_le_bytes().to_vec())?
         );
-        debug!("  Version:               {:?}", version);
-        debug!("  Scale:                 {:?}", scale);
-        debug!("  Origin:                {:?}", origin);
-        debug!("  Bounding Radius:       {:?}", bounding_radius);
-        debug!("  Position:              {:?}", position);
-        debug!("  Number of Skins:       {:?}", num_skins);
-        debug!("  Skin Width:            {:?}", skin_width);
-        debug!("  Skin Height:           {:?}", skin_height);
-        debug!("  Number of Vertices:    {:?}", num_verts);
-        debug!("  Number of Triangles:   {:?}", num_tris);
-        debug!("  Number of Frames:      {:?}", num_frames);
-        debug!("  Sync Type:             {:?}", sync_type);
-        debug!("  Flags:                 {:x}", flags);
-        debug!("  Size:                  {:?}", size);
-
-        let mut skins = Vec::with_capacity(num_skins as usize);
-        for _ in 0..num_skins {
-            skins.push(Skin::deserialize(
-                reader,
-                (skin_width * skin_height) as usize,
-            )?);
-        }
-
-        let mut skin_coords = Vec::with_capacity(num_verts as usize);
-        for _ in 0..num_verts {
-            skin_coords.push(SkinCoord::deserialize(reader)?)
+        debug!("{}:   Version:               {:?}", name, version);
+        debug!("{}:   Scale:                 {:?}", name, scale);
+        debug!("{}:   Origin:                {:?}", name, origin);
+        debug!("{}:   Bounding Radius:       {:?}", name, bounding_radius);
+        debug!("{}:   Position:              {:?}", name, position);
+        debug!("{}:   Number of Skins:       {:?}", name, num_skins);
+        debug!("{}:   Skin Width:            {:?}", name, skin_width);
+        debug!("{}:   Skin Height:           {:?}", name, skin_height);
+        debug!("{}:   Number of Vertices:    {:?}", name, num_verts);
+        debug!("{}:   Number of Triangles:   {:?}", name, num_tris);
+        debug!("{}:   Number of Frames:      {:?}", name, num_frames);
+        debug!("{}:   Sync Type:             {:?}", name, sync_type);
+        debug!("{}:   Flags:                 {:x}", name, flags.0);
+        debug!("{}:   Size:                  {:?}", name, size);
+
+        let num_skins = Self::count("number of skins", num_skins)?;
+        let num_verts = Self::count("number of vertices", num_verts)?;
+        let num_tris = Self::count("number of triangles", num_tris)?;
+        let num_frames = Self::count("number of frames", num_frames)?;
+        // Entities show the first skin and start with the first frame.
+        if num_skins == 0 {
+            return Err(Box::new(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "model has no skins",
+            )));
         }
-
-        let mut triangles = Vec::with_capacity(num_tris as usize);
-        for _ in 0..num_tris {
-            triangles.push(Triangle::deserialize(reader)?);
+        if num_frames == 0 {
+            return Err(Box::new(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "model has no frames",
+            )));
         }
-
-        let mut keyframes = Vec::with_capacity(num_frames as usize);
-        for _ in 0..num_frames {
-            keyframes.push(Keyframe::deserialize(reader, num_verts, scale, origin)?);
+        let skin_size = Self::count("skin width", skin_width)?
+            .checked_mul(Self::count("skin height", skin_height)?)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid skin size: {}x{}", skin_width, skin_height),
+                )
+            })?;
+
+        let skins = reader
+            .read_entries(num_skins, |reader| Skin::deserialize(reader, skin_size))
+            .map_err(|err| format!("skins: {}", err))?;
+        let skin_coords = reader
+            .read_entries(num_verts, SkinCoord::deserialize)
+            .map_err(|err| format!("skin coordinates: {}", err))?;
+        let triangles = reader
+            .read_entries(num_tris, Triangle::deserialize)
+            .map_err(|err| format!("triangles: {}", err))?;
+        // Vertices and skin coordinates are looked up by these indices.
+        if let Some(index) = triangles
+            .iter()
+            .flat_map(|triangle| triangle.indices)
+            .find(|index| *index as usize >= num_verts)
+        {
+            return Err(format!("triangles: invalid vertex index: {}", index as i32).into());
         }
+        let keyframes = reader
+            .read_entries(num_frames, |reader| {
+                Keyframe::deserialize(reader, num_verts as i32, scale, origin)
+            })
+            .map_err(|err| format!("frames: {}", err))?;
 
         Ok(Self {
-            skins: skins.into_boxed_slice(),
+            scale,
+            origin,
+            bounding_radius,
+            eye_position: position,
+            sync_type,
+            flags,
+            size,
+            skins,
             skin_width: skin_width as u32,
             skin_height: skin_height as u32,
-            skin_coords: skin_coords.into_boxed_slice(),
+            skin_coords,
             num_verts: num_verts as u32,
-            triangles: triangles.into_boxed_slice(),
-            keyframes: keyframes.into_boxed_slice(),
+            triangles,
+            keyframes,
         })
     }
+
+    fn count(field: &str, value: i32) -> Result<usize, Box<dyn Error>> {
+        usize::try_from(value).map_err(|_| {
+            Box::new(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid {}: {}", field, value),
+            )) as Box<dyn Error>
+        })
+    }
+}
+
+// Skin and frame groups start with how many frames they hold.
+pub(crate) fn read_group_size(
+    reader: &mut LumpReader,
+    group: &str,
+) -> Result<usize, Box<dyn Error>> {
+    let size = reader.read_i32()?;
+    if size <= 0 {
+        return Err(Box::new(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid {} size: {}", group, size),
+        )));
+    }
+    Ok(size as usize)
+}
+
+// Group intervals are the times each frame ends at, counted from the start
+// of the group, turned here into how long each frame is shown. An interval
+// going back in time gives a frame lasting no time.
+pub(crate) fn read_durations(
+    reader: &mut LumpReader,
+    count: usize,
+) -> Result<Box<[Duration]>, Box<dyn Error>> {
+    let intervals = reader.read_entries(count, |reader| Ok(reader.read_f32()?))?;
+
+    let mut start = 0f32;
+    Ok(intervals
+        .iter()
+        .map(|end| {
+            let duration = Duration::from_micros(((end - start) * 1_000_000.0).round() as u64);
+            start = start.max(*end);
+            duration
+        })
+        .collect())
+}
+
+// Index of the frame shown at the given time, with the frame durations laid
+// end to end and looping. Groups lasting no time show their first frame.
+pub(crate) fn looped_frame_index<I>(durations: I, time: &Duration) -> usize
+where
+    I: Iterator<Item = Duration> + Clone,
+{
+    let total = durations.clone().fold(Duration::ZERO, |acc, d| acc + d);
+    if total.is_zero() {
+        return 0;
+    }
+
+    let mut time = time.as_nanos() % total.as_nanos();
+    let mut last = 0;
+    for (i, duration) in durations.enumerate() {
+        if time < duration.as_nanos() {
+            return i;
+        }
+        time -= duration.as_nanos();
+        last = i;
+    }
+
+    last
 }
 
 #[derive(Clone, Debug)]
@@ -166,25 +372,33 @@ pub enum Skin {
 
 impl Skin {
     pub fn indices(&self, time: &Duration) -> Box<[u8]> {
+        self.frame(self.frame_index(time)).into()
+    }
+
+    pub fn frame_count(&self) -> usize {
         match *self {
-            Skin::Static(ref s) => s.0.clone(),
-            Skin::Animated(ref s) => {
-                let total = s.0.iter().fold(Duration::ZERO, |acc, f| acc + f.duration);
-                let mut drift = time.as_millis() - total.as_millis();
-                for frame in s.0.iter() {
-                    drift -= frame.duration.as_millis();
-                    if drift <= 0 {
-                        return frame.indices.clone();
-                    }
-                }
-
-                unreachable!()
-            }
+            Skin::Static(_) => 1,
+            Skin::Animated(ref s) => s.0.len(),
+        }
+    }
+
+    pub fn frame_index(&self, time: &Duration) -> usize {
+        match *self {
+            Skin::Static(_) => 0,
+            Skin::Animated(ref s) => looped_frame_index(s.0.iter().map(|f| f.duration), time),
+        }
+    }
+
+    // Palette indices of a frame, clamped to the last one.
+    pub fn frame(&self, index: usize) -> &[u8] {
+        match *self {
+            Skin::Static(ref s) => &s.0,
+            Skin::Animated(ref s) => &s.0[index.min(s.0.len() - 1)].indices,
         }
     }
 
-    fn deserialize(reader: &mut Cursor<Vec<u8>>, size: usize) -> Result<Self, Box<dyn Error>> {
-        match reader.read_i32::<LittleEndian>()? {
+    fn deserialize(reader: &mut LumpReader, size: usize) -> Result<Self, Box<dyn Error>> {
+        match reader.read_i32()? {
             0 => Ok(Skin::Static(StaticSkin::deserialize(reader, size)?)),
             1 => Ok(Skin::Animated(AnimatedSkin::deserialize(reader, size)?)),
             ty => Err(Box::new(std::io::Error::new(
@@ -199,11 +413,13 @@ impl Skin {
 pub struct StaticSkin(Box<[u8]>);
 
 impl StaticSkin {
-    fn deserialize(reader: &mut Cursor<Vec<u8>>, size: usize) -> Result<Self, Box<dyn Error>> {
-        let mut indices = vec![0u8; size];
-        reader.read_exact(&mut indices)?;
+    // Palette indices of a single skin_width by skin_height image.
+    pub fn new(indices: Box<[u8]>) -> Self {
+        Self(indices)
+    }
 
-        Ok(Self(indices.into_boxed_slice()))
+    fn deserialize(reader: &mut LumpReader, size: usize) -> Result<Self, Box<dyn Error>> {
+        Ok(Self(reader.read_bytes(size)?))
     }
 }
 
@@ -211,27 +427,28 @@ impl StaticSkin {
 pub struct AnimatedSkin(Box<[AnimatedSkinFrame]>);
 
 impl AnimatedSkin {
-    fn deserialize(reader: &mut Cursor<Vec<u8>>, size: usize) -> Result<Self, Box<dyn Error>> {
-        let num_skin_frames = reader.read_f32::<LittleEndian>()? as usize;
-
-        let mut durations = Vec::with_capacity(num_skin_frames);
-        for _ in 0..num_skin_frames {
-            let duration = reader.read_f32::<LittleEndian>()?;
-            durations.push(Duration::from_micros((duration * 1_000_000.0) as u64));
-        }
-
-        let mut frames = Vec::with_capacity(num_skin_frames);
-        for i in 0..num_skin_frames {
-            let mut indices = vec![0u8; size];
-            reader.read_exact(&mut indices)?;
-
-            frames.push(AnimatedSkinFrame {
-                duration: durations[i],
-                indices: indices.into_boxed_slice(),
-            });
-        }
-
-        Ok(Self(frames.into_boxed_slice()))
+    fn deserialize(reader: &mut LumpReader, size: usize) -> Result<Self, Box<dyn Error>> {
+        // A count like the frame groups, it was read as a float.
+        let num_skin_frames = read_group_size(reader, "skin group")?;
+        let durations = read_durations(reader, num_skin_frames)?;
+        let frames = reader.read_entries(num_skin_frames, |reader| {
+            Ok(AnimatedSkinFrame {
+                duration: Duration::ZERO,
+                indices: reader.read_bytes(size)?,
+            })
+        })?;
+
+        Ok(Self(
+            frames
+                .into_vec()
+                .into_iter()
+                .zip(durations.iter())
+                .map(|(frame, duration)| AnimatedSkinFrame {
+                    duration: *duration,
+                    ..frame
+                })
+                .collect(),
+        ))
     }
 }
 
@@ -249,13 +466,27 @@ struct SkinCoord {
 }
 
 impl SkinCoord {
-    fn deserialize(reader: &mut Cursor<Vec<u8>>) -> Result<Self, Box<dyn Error>> {
-        let is_on_seam = match reader.read_i32::<LittleEndian>()? {
-            0x20 => true,
-            _ => false,
+    // Back-facing triangles use the right half of the skin, so their seam
+    // vertices are shifted by half the skin width.
+    fn texcoord(&self, faces_front: bool, skin_width: u32, skin_height: u32) -> [f32; 2] {
+        let s = if !faces_front && self.is_on_seam {
+            self.s as f32 + skin_width as f32 / 2.0
+        } else {
+            self.s as f32
         };
-        let s = reader.read_i32::<LittleEndian>()?;
-        let t = reader.read_i32::<LittleEndian>()?;
+
+        [
+            (s + 0.5) / skin_width as f32,
+            (self.t as f32 + 0.5) / skin_height as f32,
+        ]
+    }
+
+    fn deserialize(reader: &mut LumpReader) -> Result<Self, Box<dyn Error>> {
+        // The flag is 0x20 in the original tools, but the engine treats any
+        // non-zero value as being on the seam.
+        let is_on_seam = reader.read_i32()? != 0;
+        let s = reader.read_i32()?;
+        let t = reader.read_i32()?;
 
         Ok(Self { is_on_seam, s, t })
     }
@@ -268,15 +499,12 @@ struct Triangle {
 }
 
 impl Triangle {
-    fn deserialize(reader: &mut Cursor<Vec<u8>>) -> Result<Self, Box<dyn Error>> {
-        let faces_front = match reader.read_i32::<LittleEndian>()? {
-            1 => true,
-            _ => false,
-        };
+    fn deserialize(reader: &mut LumpReader) -> Result<Self, Box<dyn Error>> {
+        let faces_front = reader.read_i32()? != 0;
 
         let mut indices = [0; 3];
         for i in 0..3 {
-            indices[i] = reader.read_i32::<LittleEndian>()? as u32;
+            indices[i] = reader.read_i32()? as u32;
         }
 
         Ok(Self {
@@ -293,34 +521,33 @@ pub enum Keyframe {
 }
 
 impl Keyframe {
-    fn frame(&self, time: &Duration) -> Box<&Frame> {
+    // Box around every frame of the keyframe, in model space with the scale
+    // and origin applied.
+    pub fn bounds(&self) -> ([f32; 3], [f32; 3]) {
+        match *self {
+            Keyframe::Static(ref kf) => kf.0.bounds(),
+            Keyframe::Animated(ref kf) => (kf.min, kf.max),
+        }
+    }
+
+    // Frame shown at the given time, groups loop over their frames.
+    pub fn frame(&self, time: &Duration) -> &Frame {
         match *self {
-            Keyframe::Static(ref kf) => Box::new(&kf.0),
+            Keyframe::Static(ref kf) => &kf.0,
             Keyframe::Animated(ref kf) => {
-                let total = kf
-                    .subframes
-                    .iter()
-                    .fold(Duration::ZERO, |acc, f| acc + f.duration);
-                let mut drift = time.as_millis() - total.as_millis();
-                for frame in kf.subframes.iter() {
-                    drift -= frame.duration.as_millis();
-                    if drift <= 0 {
-                        return Box::new(&frame.frame);
-                    }
-                }
-
-                unreachable!()
+                let index = looped_frame_index(kf.subframes.iter().map(|f| f.duration), time);
+                &kf.subframes[index].frame
             }
         }
     }
 
     fn deserialize(
-        reader: &mut Cursor<Vec<u8>>,
+        reader: &mut LumpReader,
         num_verts: i32,
         scale: [f32; 3],
         origin: [f32; 3],
     ) -> Result<Self, Box<dyn Error>> {
-        match reader.read_i32::<LittleEndian>()? {
+        match reader.read_i32()? {
             0 => Ok(Keyframe::Static(StaticKeyframe(Frame::deserialize(
                 reader, num_verts, scale, origin,
             )?))),
@@ -346,38 +573,43 @@ pub struct AnimatedKeyframe {
 }
 
 impl AnimatedKeyframe {
+    // Each frame of the group with how long it is shown.
+    pub fn subframes(&self) -> impl Iterator<Item = (&Frame, Duration)> {
+        self.subframes
+            .iter()
+            .map(|subframe| (&subframe.frame, subframe.duration))
+    }
+
     fn deserialize(
-        reader: &mut Cursor<Vec<u8>>,
+        reader: &mut LumpReader,
         num_verts: i32,
         scale: [f32; 3],
         origin: [f32; 3],
     ) -> Result<Self, Box<dyn Error>> {
-        let num_subframes = reader.read_i32::<LittleEndian>()? as usize;
+        let num_subframes = read_group_size(reader, "frame group")?;
 
         let min = Vertex::read_packed_position(reader, scale, origin)?;
         reader.read_u8()?;
         let max = Vertex::read_packed_position(reader, scale, origin)?;
         reader.read_u8()?;
 
-        let mut durations = Vec::with_capacity(num_subframes);
-        for _ in 0..num_subframes {
-            let duration = reader.read_f32::<LittleEndian>()?;
-            durations.push(Duration::from_micros((duration * 1_000_000.0) as u64));
-        }
-
-        let mut subframes = Vec::with_capacity(num_subframes);
-        for i in 0..num_subframes {
-            let frame = Frame::deserialize(reader, num_verts, scale, origin)?;
-            subframes.push(AnimatedKeyframeFrame {
-                duration: durations[i],
-                frame,
-            });
-        }
+        let durations = read_durations(reader, num_subframes)?;
+        let frames = reader.read_entries(num_subframes, |reader| {
+            Frame::deserialize(reader, num_verts, scale, origin)
+        })?;
 
         Ok(Self {
             min,
             max,
-            subframes: subframes.into_boxed_slice(),
+            subframes: frames
+                .into_vec()
+                .into_iter()
+                .zip(durations.iter())
+                .map(|(frame, duration)| AnimatedKeyframeFrame {
+                    duration: *duration,
+                    frame,
+                })
+                .collect(),
         })
     }
 }
@@ -394,11 +626,18 @@ pub struct Frame {
     min: [f32; 3],
     max: [f32; 3],
     vertices: Box<[[f32; 3]]>,
+    // x, y and z in model units plus the light normal index.
+    packed_vertices: Box<[[u8; 4]]>,
 }
 
 impl Frame {
+    // In model space with the scale and origin applied.
+    pub fn bounds(&self) -> ([f32; 3], [f32; 3]) {
+        (self.min, self.max)
+    }
+
     fn deserialize(
-        reader: &mut Cursor<Vec<u8>>,
+        reader: &mut LumpReader,
         num_verts: i32,
         scale: [f32; 3],
         origin: [f32; 3],
@@ -408,19 +647,18 @@ impl Frame {
         let max = Vertex::read_packed_position(reader, scale, origin)?;
         reader.read_u8()?;
 
-        let mut name_buf = [0u8; 16];
-        reader.read_exact(&mut name_buf)?;
+        let name = reader.read_string_fixed(16)?;
 
-        let name = {
-            let len = name_buf.iter().position(|b| *b == 0).unwrap();
-            String::from_utf8_lossy(&name_buf[..len])
-        }
-        .to_string();
-
-        let mut vertices = Vec::with_capacity(num_verts as usize);
+        // Capped by the data left, four bytes per vertex, so a corrupt count
+        // cannot trigger a huge allocation.
+        let capacity = (num_verts as usize).min(reader.remaining() as usize / 4);
+        let mut vertices = Vec::with_capacity(capacity);
+        let mut packed_vertices = Vec::with_capacity(capacity);
         for _ in 0..num_verts {
-            vertices.push(Vertex::read_packed_position(reader, scale, origin)?);
-            reader.read_u8()?;
+            let mut packed = [0u8; 4];
+            reader.read_exact(&mut packed)?;
+            vertices.push(Vertex::unpack_position(packed, scale, origin));
+            packed_vertices.push(packed);
         }
 
         Ok(Self {
@@ -428,20 +666,322 @@ impl Frame {
             min,
             max,
             vertices: vertices.into_boxed_slice(),
+            packed_vertices: packed_vertices.into_boxed_slice(),
         })
     }
 }
 
 impl Vertex {
     fn read_packed_position(
-        reader: &mut Cursor<Vec<u8>>,
+        reader: &mut LumpReader,
         scale: [f32; 3],
         origin: [f32; 3],
     ) -> Result<[f32; 3], Box<dyn Error>> {
-        Ok([
-            reader.read_u8()? as f32 * scale[0] + origin[0],
-            reader.read_u8()? as f32 * scale[1] + origin[1],
-            reader.read_u8()? as f32 * scale[2] + origin[2],
-        ])
+        let mut packed = [0u8; 4];
+        reader.read_exact(&mut packed[..3])?;
+        Ok(Self::unpack_position(packed, scale, origin))
+    }
+
+    fn unpack_position(packed: [u8; 4], scale: [f32; 3], origin: [f32; 3]) -> [f32; 3] {
+        [
+            packed[0] as f32 * scale[0] + origin[0],
+            packed[1] as f32 * scale[1] + origin[1],
+            packed[2] as f32 * scale[2] + origin[2],
+        ]
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::test_log;
+
+    fn put_i32(data: &mut Vec<u8>, value: i32) {
+        data.extend(value.to_le_bytes());
+    }
+
+    fn put_f32(data: &mut Vec<u8>, value: f32) {
+        data.extend(value.to_le_bytes());
+    }
+
+    // A model with a single blank 2x2 skin, the skin coordinates all zero and
+    // the given frames, see static_frame.
+    pub(crate) fn mdl_bytes(
+        scale: [f32; 3],
+        origin: [f32; 3],
+        num_verts: i32,
+        triangles: &[[i32; 3]],
+        frames: &[Vec<u8>],
+    ) -> Vec<u8> {
+        let mut data = Vec::new();
+        put_i32(&mut data, 0x4f504449);
+        put_i32(&mut data, 6);
+        scale.iter().for_each(|v| put_f32(&mut data, *v));
+        origin.iter().for_each(|v| put_f32(&mut data, *v));
+        put_f32(&mut data, 1.0);
+        [0.0, 0.0, 22.0].iter().for_each(|v| put_f32(&mut data, *v));
+        for value in [
+            1,
+            2,
+            2,
+            num_verts,
+            triangles.len() as i32,
+            frames.len() as i32,
+            0,
+            0,
+        ] {
+            put_i32(&mut data, value);
+        }
+        put_f32(&mut data, 1.0);
+
+        put_i32(&mut data, 0);
+        data.extend([0u8; 4]);
+        for _ in 0..num_verts {
+            [0, 0, 0].iter().for_each(|v| put_i32(&mut data, *v));
+        }
+        for triangle in triangles {
+            put_i32(&mut data, 1);
+            triangle.iter().for_each(|v| put_i32(&mut data, *v));
+        }
+        frames.iter().for_each(|frame| data.extend(frame));
+        data
+    }
+
+    pub(crate) fn static_frame(vertices: &[[u8; 4]]) -> Vec<u8> {
+        let mut data = Vec::new();
+        put_i32(&mut data, 0);
+        data.extend(frame_bytes("stand1", vertices));
+        data
+    }
+
+    pub(crate) fn group_frame(intervals: &[f32], vertices: &[[u8; 4]]) -> Vec<u8> {
+        let mut data = Vec::new();
+        put_i32(&mut data, 1);
+        put_i32(&mut data, intervals.len() as i32);
+        data.extend([0u8; 8]);
+        intervals.iter().for_each(|v| put_f32(&mut data, *v));
+        for i in 0..intervals.len() {
+            data.extend(frame_bytes(&format!("run{}", i + 1), vertices));
+        }
+        data
+    }
+
+    pub(crate) fn frame_bytes(name: &str, vertices: &[[u8; 4]]) -> Vec<u8> {
+        let mut data = vec![0u8; 8];
+        let mut name = name.as_bytes().to_vec();
+        name.resize(16, 0);
+        data.extend(name);
+        vertices.iter().for_each(|vertex| data.extend(vertex));
+        data
+    }
+
+    pub(crate) fn deserialize(data: Vec<u8>) -> Result<Mdl, Box<dyn Error>> {
+        Mdl::deserialize("test.mdl", &mut LumpReader::new(data))
+    }
+
+    #[test]
+    fn truncated_buffer_is_an_error() {
+        let vertices = [[0, 0, 0, 0], [1, 0, 0, 0], [0, 1, 0, 0]];
+        let data = mdl_bytes(
+            [1.0; 3],
+            [0.0; 3],
+            3,
+            &[[0, 1, 2]],
+            &[static_frame(&vertices)],
+        );
+        assert!(deserialize(data.clone()).is_ok());
+
+        for len in [0, 40, 84, 100, data.len() - 1] {
+            assert!(deserialize(data[..len].to_vec()).is_err(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn out_of_range_vertex_index_is_an_error() {
+        let vertices = [[0, 0, 0, 0], [1, 0, 0, 0], [0, 1, 0, 0]];
+        for triangle in [[0, 1, 3], [-1, 1, 2]] {
+            let data = mdl_bytes(
+                [1.0; 3],
+                [0.0; 3],
+                3,
+                &[triangle],
+                &[static_frame(&vertices)],
+            );
+            let err = deserialize(data).unwrap_err();
+            assert!(err.to_string().contains("invalid vertex index"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn group_intervals_become_durations() {
+        let vertices = [[0, 0, 0, 0], [1, 0, 0, 0], [0, 1, 0, 0]];
+        let data = mdl_bytes(
+            [1.0; 3],
+            [0.0; 3],
+            3,
+            &[[0, 1, 2]],
+            &[group_frame(&[0.1, 0.3, 0.35, 0.2], &vertices)],
+        );
+        let mdl = deserialize(data).unwrap();
+
+        let Keyframe::Animated(keyframe) = &mdl.keyframes[0] else {
+            panic!("expected an animated keyframe");
+        };
+        let durations: Vec<u128> = keyframe
+            .subframes()
+            .map(|(_, duration)| duration.as_millis())
+            .collect();
+        assert_eq!(durations, vec![100, 200, 50, 0]);
+    }
+
+    #[test]
+    fn origin_offsets_vertices() {
+        let vertices = [[0, 0, 0, 0], [1, 0, 0, 0], [0, 3, 12, 0]];
+        let data = mdl_bytes(
+            [2.0, 2.0, 2.0],
+            [10.0, -4.0, -24.0],
+            3,
+            &[[0, 1, 2]],
+            &[static_frame(&vertices)],
+        );
+        let mdl = deserialize(data).unwrap();
+
+        let Keyframe::Static(keyframe) = &mdl.keyframes[0] else {
+            panic!("expected a static keyframe");
+        };
+        let positions: Vec<[f32; 3]> = mdl
+            .vertices(&keyframe.0)
+            .iter()
+            .map(|vertex| vertex.position)
+            .collect();
+        assert_eq!(
+            positions,
+            vec![[10.0, -4.0, -24.0], [12.0, -4.0, -24.0], [10.0, 2.0, 0.0]]
+        );
+    }
+
+    #[test]
+    fn keyframe_frame_loops_over_the_group() {
+        let vertices = [[0, 0, 0, 0], [1, 0, 0, 0], [0, 1, 0, 0]];
+        let data = mdl_bytes(
+            [1.0; 3],
+            [0.0; 3],
+            3,
+            &[[0, 1, 2]],
+            &[group_frame(&[0.1, 0.3], &vertices)],
+        );
+        let mdl = deserialize(data).unwrap();
+
+        for (millis, name) in [
+            (0, "run1"),
+            (150, "run2"),
+            (299, "run2"),
+            (300, "run1"),
+            (1000, "run2"),
+        ] {
+            let frame = mdl.keyframes[0].frame(&Duration::from_millis(millis));
+            assert_eq!(frame.name, name, "at {} ms", millis);
+        }
+    }
+
+    #[test]
+    fn skin_indices_loop_over_the_group() {
+        let mut data = Vec::new();
+        put_i32(&mut data, 1);
+        put_i32(&mut data, 2);
+        put_f32(&mut data, 0.1);
+        put_f32(&mut data, 0.3);
+        data.extend([1u8; 4]);
+        data.extend([2u8; 4]);
+        let skin = Skin::deserialize(&mut LumpReader::new(data), 4).unwrap();
+
+        for (millis, index) in [(0, 1), (150, 2), (299, 2), (300, 1), (1000, 2)] {
+            let indices = skin.indices(&Duration::from_millis(millis));
+            assert_eq!(*indices, [index; 4], "at {} ms", millis);
+        }
+    }
+
+    #[test]
+    fn back_facing_seam_vertices_use_the_right_half() {
+        let on_seam = SkinCoord {
+            is_on_seam: true,
+            s: 8,
+            t: 4,
+        };
+        let off_seam = SkinCoord {
+            is_on_seam: false,
+            ..on_seam.clone()
+        };
+
+        // Half a texel is added to sample texel centers.
+        let front = [8.5 / 64.0, 4.5 / 32.0];
+        let back = [(8.5 + 32.0) / 64.0, 4.5 / 32.0];
+        assert_eq!(on_seam.texcoord(true, 64, 32), front);
+        assert_eq!(on_seam.texcoord(false, 64, 32), back);
+        assert_eq!(back[0] - front[0], 0.5);
+        assert_eq!(off_seam.texcoord(true, 64, 32), front);
+        assert_eq!(off_seam.texcoord(false, 64, 32), front);
+    }
+
+    #[test]
+    fn header_size_is_parsed() {
+        let triangle = [[0, 0, 0, 0], [1, 0, 0, 0], [0, 1, 0, 0]];
+        let mut data = mdl_bytes(
+            [1.0; 3],
+            [0.0; 3],
+            3,
+            &[[0, 1, 2]],
+            &[static_frame(&triangle)],
+        );
+        assert_eq!(deserialize(data.clone()).unwrap().size, 1.0);
+
+        // The size ends the header, after the sync type and the flags.
+        data[80..84].copy_from_slice(&2.5f32.to_le_bytes());
+        let mdl = deserialize(data).unwrap();
+        assert_eq!(mdl.size, 2.5);
+        assert_eq!(mdl.sync_type, 0);
+        assert_eq!(mdl.flags, ModelFlags::default());
+        assert_eq!(mdl.eye_position, [0.0, 0.0, 22.0]);
+        assert_eq!(mdl.num_triangles(), 1);
+    }
+
+    #[test]
+    fn models_without_skins_or_frames_are_errors() {
+        let vertices = [[0, 0, 0, 0], [1, 0, 0, 0], [0, 1, 0, 0]];
+        let mut data = mdl_bytes(
+            [1.0; 3],
+            [0.0; 3],
+            3,
+            &[[0, 1, 2]],
+            &[static_frame(&vertices)],
+        );
+        // The number of skins, then the 2x2 skin itself.
+        data[48..52].copy_from_slice(&0i32.to_le_bytes());
+        data.drain(84..92);
+        let err = deserialize(data).unwrap_err();
+        assert!(err.to_string().contains("no skins"), "{}", err);
+
+        let data = mdl_bytes([1.0; 3], [0.0; 3], 3, &[[0, 1, 2]], &[]);
+        let err = deserialize(data).unwrap_err();
+        assert!(err.to_string().contains("no frames"), "{}", err);
+    }
+
+    #[test]
+    fn header_lines_name_the_model() {
+        test_log::init();
+        let vertices = [[0, 0, 0, 0], [1, 0, 0, 0], [0, 1, 0, 0]];
+        let data = mdl_bytes(
+            [1.0; 3],
+            [0.0; 3],
+            3,
+            &[[0, 1, 2]],
+            &[static_frame(&vertices)],
+        );
+        Mdl::deserialize("progs/logged.mdl", &mut LumpReader::new(data)).unwrap();
+
+        assert!(test_log::contains("DEBUG progs/logged.mdl: MDL Header:"));
+        assert!(test_log::contains(
+            "DEBUG progs/logged.mdl:   Number of Vertices:    3"
+        ));
     }
 }
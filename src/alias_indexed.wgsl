@@ -0,0 +1,128 @@
+struct Model {
+    matrix : mat4x4f,
+    tint   : vec4<f32>,
+    flags  : vec4<u32>,
+}
+
+struct Frame {
+    time              : f32,
+    liquid_warp_speed : f32,
+    liquid_warp       : u32,
+    padding           : u32,
+}
+
+@group(0) @binding(0) var<uniform> view_proj : mat4x4f;
+@group(1) @binding(0) var<uniform> model     : Model;
+@group(1) @binding(1) var<uniform> frame     : Frame;
+
+struct VertexInput {
+    @location(0) position : vec3<f32>,
+    @location(1) normal   : vec3<f32>,
+    @location(2) texcoord : vec2<f32>,
+    @location(3) current  : vec4<u32>,
+    @location(4) next     : vec4<u32>,
+    @location(5) scale    : vec4<f32>,
+    @location(6) origin   : vec4<f32>,
+}
+
+/* Transform of the drawn copy in the model space, the identity unless the
+ * entity is instanced.  */
+struct InstanceInput {
+    @location(7)  matrix_0 : vec4<f32>,
+    @location(8)  matrix_1 : vec4<f32>,
+    @location(9)  matrix_2 : vec4<f32>,
+    @location(10) matrix_3 : vec4<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip     : vec4<f32>,
+    @location(0)       normal   : vec3<f32>,
+    @location(1)       texcoord : vec2<f32>,
+}
+
+/* The Quake coordinate system defines X as the longitudinal axis, Y as the
+ * lateral axis, and Z as the vertical axis.  */
+fn from_quake_coords(coords: vec3<f32>) -> vec3<f32> {
+  return vec3<f32>(-coords.y, coords.z, -coords.x);
+}
+
+/* Packed frames hold byte positions in the model bounds, rebuilt with the
+ * model scale and origin and interpolated between the current and next frame.
+ * Meshes without them leave origin.w clear and use the vertex position.  */
+fn vertex_position(in: VertexInput) -> vec3<f32> {
+    if (in.origin.w == 0.0) {
+        return in.position;
+    }
+    let current = vec3<f32>(in.current.xyz) * in.scale.xyz + in.origin.xyz;
+    let next = vec3<f32>(in.next.xyz) * in.scale.xyz + in.origin.xyz;
+    return mix(current, next, in.scale.w);
+}
+
+/* Normals follow the inverse transpose of the model matrix, here its
+ * cofactor matrix, which only differs by a scale that normalizing removes.
+ * This keeps them perpendicular to the surface under non-uniform scales.  */
+fn transform_normal(matrix: mat4x4f, normal: vec3<f32>) -> vec3<f32> {
+    let m = mat3x3f(matrix[0].xyz, matrix[1].xyz, matrix[2].xyz);
+    let cofactor = mat3x3f(cross(m[1], m[2]), cross(m[2], m[0]), cross(m[0], m[1]));
+    return normalize(cofactor * normal);
+}
+
+@vertex fn vs_main(in: VertexInput, instance: InstanceInput) -> VertexOutput {
+    var out : VertexOutput;
+
+    let instance_matrix = mat4x4f(instance.matrix_0, instance.matrix_1, instance.matrix_2, instance.matrix_3);
+    let model_matrix = model.matrix * instance_matrix;
+    out.clip = view_proj * model_matrix * vec4<f32>(from_quake_coords(vertex_position(in)), 1.0);
+    out.normal = transform_normal(model_matrix, from_quake_coords(in.normal));
+    out.texcoord = in.texcoord;
+
+    return out;
+}
+
+
+@group(2) @binding(0) var index_texture   : texture_2d<u32>;
+@group(2) @binding(1) var palette_texture : texture_2d<f32>;
+
+const FULLBRIGHT_START : u32 = 224u;
+
+struct FragmentOutput {
+    @location(0) albedo_attachment : vec4<f32>,
+    @location(1) normal_attachment : vec4<f32>,
+}
+
+/* Quake turbulence: each coordinate is offset by a sine of the other one,
+ * an eighth of the texture wide, scrolling over time.  The result wraps since
+ * the material samplers clamp.  */
+fn warp_texcoord(texcoord: vec2<f32>) -> vec2<f32> {
+    if (model.flags.x == 0u || frame.liquid_warp == 0u) {
+        return texcoord;
+    }
+    return fract(texcoord + 0.125 * sin(texcoord.yx * 8.0 + frame.time * frame.liquid_warp_speed));
+}
+
+/* Fence textures are alpha tested: see-through texels are discarded so the
+ * rest still writes depth.  */
+const ALPHA_TEST_THRESHOLD : f32 = 0.5;
+
+/* Integer textures cannot be filtered, so the nearest index is fetched and
+ * resolved through the palette.  */
+@fragment fn fs_main(in: VertexOutput) -> FragmentOutput {
+    var out : FragmentOutput;
+
+    let size = vec2<i32>(textureDimensions(index_texture));
+    let texel = clamp(vec2<i32>(warp_texcoord(in.texcoord) * vec2<f32>(size)), vec2<i32>(0), size - 1);
+    let index = textureLoad(index_texture, texel, 0).r;
+    let albedo = textureLoad(palette_texture, vec2<i32>(i32(index), 0), 0);
+    /* The last palette rows are fullbright and drawn unlit.  */
+    let lit = albedo.rgb * model.tint.rgb * model.tint.a;
+    out.albedo_attachment = vec4<f32>(select(lit, albedo.rgb, index >= FULLBRIGHT_START), albedo.a);
+    /* Normals are in engine coordinates, biased into the unsigned attachment
+     * range.  */
+    out.normal_attachment = vec4<f32>(in.normal * 0.5 + 0.5, 1.0);
+
+    if (model.flags.y != 0u && albedo.a < ALPHA_TEST_THRESHOLD) {
+        discard;
+    }
+
+    return out;
+}
@@ -1,10 +1,70 @@
-use std::{collections::HashMap, time::Duration};
+use std::{
+    borrow::Cow, collections::HashMap, error::Error, io::ErrorKind, path::Path, sync::RwLock,
+    time::Duration,
+};
 
-use crate::mesh::Vertex;
+use cgmath::{InnerSpace, Vector2, Vector3, VectorSpace};
+use lazy_static::lazy_static;
+
+use crate::mesh::{self, Vertex};
+
+// Quake steps model frames ten times a second.
+pub const DEFAULT_FRAME_RATE: f32 = 10.0;
+
+lazy_static! {
+    pub static ref GLOBAL_FRAME_RATE: RwLock<f32> = RwLock::new(DEFAULT_FRAME_RATE);
+}
+
+pub fn frame_rate() -> f32 {
+    *GLOBAL_FRAME_RATE.read().unwrap()
+}
+
+// Only affects models loaded afterwards.
+pub fn set_frame_rate(frame_rate: f32) {
+    *GLOBAL_FRAME_RATE.write().unwrap() = frame_rate;
+}
+
+// How long a static model frame is shown.
+pub fn frame_duration() -> Duration {
+    Duration::from_secs_f32(1.0 / frame_rate())
+}
+
+// The outgoing animation of a blend_to, start is taken on the first
+// update_blend after it.
+struct Crossfade {
+    from: String,
+    from_start: Option<Duration>,
+    duration: Duration,
+    start: Option<Duration>,
+}
+
+struct FrameCallback {
+    animation: String,
+    index: usize,
+    callback: Box<dyn FnMut()>,
+}
 
 pub struct KeyframeAnimationComponent {
     pub animations: HashMap<String, Animation>,
     pub current_animation: Option<String>,
+    pub lerp: bool,
+    // Scales the time animations are sampled at, negative values play them
+    // in reverse and 0.0 pauses them.
+    pub speed: f32,
+    // Played on the vertices flagged in upper_body_mask while
+    // current_animation drives the others, e.g. shooting while running.
+    pub upper_animation: Option<String>,
+    pub upper_body_mask: Vec<bool>,
+
+    // When the current animation started, taken on the first
+    // update_frame_events after switching to it.
+    start: Option<Duration>,
+    crossfade: Option<Crossfade>,
+    frame_callbacks: Vec<FrameCallback>,
+    last_frame: Option<(String, usize)>,
+    finished_callbacks: Vec<Box<dyn FnMut()>>,
+    finished: bool,
+    just_finished: bool,
 }
 
 impl KeyframeAnimationComponent {
@@ -12,88 +72,852 @@ impl KeyframeAnimationComponent {
         Self {
             animations: HashMap::new(),
             current_animation: None,
+            lerp: true,
+            speed: 1.0,
+            upper_animation: None,
+            upper_body_mask: Vec::new(),
+
+            start: None,
+            crossfade: None,
+            frame_callbacks: Vec::new(),
+            last_frame: None,
+            finished_callbacks: Vec::new(),
+            finished: false,
+            just_finished: false,
+        }
+    }
+
+    pub fn on_frame<S, F>(&mut self, name: S, index: usize, callback: F)
+    where
+        S: AsRef<str>,
+        F: FnMut() + 'static,
+    {
+        self.frame_callbacks.push(FrameCallback {
+            animation: name.as_ref().to_string(),
+            index,
+            callback: Box::new(callback),
+        });
+    }
+
+    // Called once when a Once animation reaches its final keyframe, the
+    // first one when playing in reverse. Looping and ping-pong animations
+    // never finish.
+    pub fn on_finished<F>(&mut self, callback: F)
+    where
+        F: FnMut() + 'static,
+    {
+        self.finished_callbacks.push(Box::new(callback));
+    }
+
+    // True only for the update_frame_events call that finished the current
+    // animation, see on_finished.
+    pub fn just_finished(&self) -> bool {
+        self.just_finished
+    }
+
+    // Fires the callbacks of every frame entered since the previous call, in
+    // the direction played and wrapping around when the animation loops, then
+    // the finished ones.
+    pub fn update_frame_events(&mut self, time: &Duration) {
+        self.just_finished = false;
+        let Some(k) = self.current_animation.clone() else {
+            self.last_frame = None;
+            return;
+        };
+        self.start.get_or_insert(*time);
+        let Some(animation) = self.animations.get(&k) else {
+            return;
+        };
+        let Some(index) =
+            animation.frame_index(&self.animation_time(animation, &self.clip_time(time)))
+        else {
+            return;
+        };
+
+        let num_frames = animation.keyframes.len();
+        let entered = match &self.last_frame {
+            Some((last_k, last_index)) if *last_k == k => {
+                let forward = match animation.playback_mode {
+                    PlaybackMode::Loop => self.speed >= 0.0,
+                    PlaybackMode::Once => index >= *last_index,
+                    PlaybackMode::PingPong => animation
+                        .playing_forward(&self.animation_time(animation, &self.clip_time(time))),
+                };
+                animation.frames_between(*last_index, index, forward)
+            }
+            _ => vec![index],
+        };
+        self.last_frame = Some((k.clone(), index));
+
+        let final_index = if self.speed < 0.0 { 0 } else { num_frames - 1 };
+        let finished = animation.playback_mode == PlaybackMode::Once && index == final_index;
+
+        for i in entered {
+            for frame_callback in self.frame_callbacks.iter_mut() {
+                if frame_callback.animation == k && frame_callback.index == i {
+                    (frame_callback.callback)();
+                }
+            }
+        }
+
+        if finished && !self.finished {
+            self.finished = true;
+            self.just_finished = true;
+            for callback in self.finished_callbacks.iter_mut() {
+                callback();
+            }
+        }
+    }
+
+    // Time since the current animation started.
+    fn clip_time(&self, time: &Duration) -> Duration {
+        self.start
+            .map_or(Duration::ZERO, |start| time.saturating_sub(start))
+    }
+
+    pub fn set_upper_body<S>(&mut self, name: Option<S>, mask: Vec<bool>)
+    where
+        S: AsRef<str>,
+    {
+        self.upper_animation = name.map(|name| name.as_ref().to_string());
+        self.upper_body_mask = mask;
+    }
+
+    // Unknown names are reported along with the available ones. The
+    // animation plays from its start, any blend in progress is dropped and
+    // frame events start over.
+    pub fn set_current_animation(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+        if !self.animations.contains_key(name) {
+            let mut names: Vec<&str> = self.animations.keys().map(String::as_str).collect();
+            names.sort_unstable();
+            return Err(Box::new(std::io::Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "animation not found: {}, available: {}",
+                    name,
+                    names.join(", ")
+                ),
+            )));
+        }
+
+        self.current_animation = Some(name.to_string());
+        self.crossfade = None;
+        self.restart();
+
+        Ok(())
+    }
+
+    // Switches to another animation, fading the vertices out of the current
+    // one over the given duration instead of popping. Blending to the current
+    // animation does nothing.
+    pub fn blend_to(&mut self, name: String, duration: Duration) {
+        if self.current_animation.as_ref() == Some(&name) {
+            return;
+        }
+
+        let from = self.current_animation.replace(name);
+        let from_start = self.start;
+        self.crossfade = from.filter(|_| !duration.is_zero()).map(|from| Crossfade {
+            from,
+            from_start,
+            duration,
+            start: None,
+        });
+        self.restart();
+    }
+
+    fn restart(&mut self) {
+        self.start = None;
+        self.last_frame = None;
+        self.finished = false;
+    }
+
+    // Starts the pending blend at the given time and drops it once over.
+    pub fn update_blend(&mut self, time: &Duration) {
+        if let Some(crossfade) = self.crossfade.as_mut() {
+            let start = *crossfade.start.get_or_insert(*time);
+            if time.saturating_sub(start) >= crossfade.duration {
+                self.crossfade = None;
+            }
+        }
+    }
+
+    pub fn is_blending(&self) -> bool {
+        self.crossfade.is_some()
+    }
+
+    // While blending, the pose is lerped from the outgoing animation by how
+    // far the blend is.
+    pub fn animate(&self, time: &Duration) -> Option<Cow<'_, [Vertex]>> {
+        let pose = self.animate_pose(time);
+        let Some(crossfade) = &self.crossfade else {
+            return pose;
+        };
+        let t = match crossfade.start {
+            Some(start) => {
+                time.saturating_sub(start).as_secs_f32() / crossfade.duration.as_secs_f32()
+            }
+            None => 0.0,
+        };
+
+        let from_time = crossfade
+            .from_start
+            .map_or(Duration::ZERO, |start| time.saturating_sub(start));
+        match (self.animate_layer(Some(&crossfade.from), &from_time), pose) {
+            (Some(from), Some(pose)) => Some(Cow::Owned(lerp_vertices(&from, &pose, t.min(1.0)))),
+            (from, pose) => pose.or(from),
         }
     }
 
-    pub fn animate(&self, time: &Duration) -> Option<&Vec<Vertex>> {
+    // Vertices missing from the mask belong to the lower body. When only one
+    // of the two animations is playing it drives the whole mesh.
+    fn animate_pose(&self, time: &Duration) -> Option<Cow<'_, [Vertex]>> {
+        let lower = self.animate_layer(self.current_animation.as_ref(), &self.clip_time(time));
+        let upper = self.animate_layer(self.upper_animation.as_ref(), time);
+        match (lower, upper) {
+            (Some(lower), Some(upper)) => Some(Cow::Owned(
+                lower
+                    .iter()
+                    .zip(upper.iter())
+                    .enumerate()
+                    .map(|(i, (lower, upper))| {
+                        if self.upper_body_mask.get(i).copied().unwrap_or(false) {
+                            *upper
+                        } else {
+                            *lower
+                        }
+                    })
+                    .collect(),
+            )),
+            (lower, upper) => lower.or(upper),
+        }
+    }
+
+    // Dumps the pose at the given time, for inspection in a modeling tool.
+    pub fn export_obj<P>(&self, path: P, time: &Duration) -> Result<(), Box<dyn Error>>
+    where
+        P: AsRef<Path>,
+    {
+        let vertices = self
+            .animate(time)
+            .ok_or_else(|| std::io::Error::new(ErrorKind::NotFound, "no animation is playing"))?;
+        mesh::export_obj(path, &vertices)
+    }
+
+    // Packed frames to blend on the GPU and how far between them, when the
+    // current animation can be drawn that way. Body part layering and
+    // blending between animations need the vertices and are left to animate.
+    pub fn frame_blend(&self, time: &Duration) -> Option<(usize, usize, f32)> {
+        if self.upper_animation.is_some() || self.crossfade.is_some() {
+            return None;
+        }
+        let k = self.current_animation.as_ref()?;
+        let animation = self.animations.get(k)?;
+        animation.frame_blend(
+            &self.animation_time(animation, &self.clip_time(time)),
+            self.lerp,
+        )
+    }
+
+    fn animate_layer(&self, name: Option<&String>, time: &Duration) -> Option<Cow<'_, [Vertex]>> {
+        let animation = self.animations.get(name?)?;
+        animation.animate(&self.animation_time(animation, time), self.lerp)
+    }
+
+    pub fn current_frame_index(&self, time: &Duration) -> Option<usize> {
         let k = self.current_animation.as_ref()?;
-        self.animations.get(k)?.animate(time)
+        let animation = self.animations.get(k)?;
+        animation.frame_index(&self.animation_time(animation, &self.clip_time(time)))
     }
+
+    pub fn set_speed(&mut self, speed: f32) {
+        self.speed = speed;
+    }
+
+    // Playing in reverse runs the clip time backwards from its end, so a
+    // looping clip still loops and a one-shot one ends on its first frame.
+    fn animation_time(&self, animation: &Animation, time: &Duration) -> Duration {
+        let time = time.mul_f64(self.speed.abs() as f64);
+        let total_duration = animation.total_duration();
+        if self.speed >= 0.0 || total_duration.is_zero() {
+            return time;
+        }
+
+        match animation.playback_mode {
+            PlaybackMode::Loop => {
+                let time = time.as_nanos() % total_duration.as_nanos();
+                total_duration - Duration::from_nanos(time as u64)
+            }
+            PlaybackMode::Once => total_duration.saturating_sub(time),
+            PlaybackMode::PingPong => total_duration + time,
+        }
+    }
+
+    pub fn duration_of<S>(&self, name: S) -> Option<Duration>
+    where
+        S: AsRef<str>,
+    {
+        self.animations
+            .get(name.as_ref())
+            .map(|animation| animation.total_duration())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum PlaybackMode {
+    #[default]
+    Loop,
+    // Holds the last keyframe once all have played.
+    Once,
+    // Plays forward then backward.
+    PingPong,
 }
 
 pub struct Animation {
     keyframes: Vec<Keyframe>,
+    pub playback_mode: PlaybackMode,
 }
 
 impl Animation {
     pub fn new() -> Self {
         Animation {
             keyframes: Vec::new(),
+            playback_mode: PlaybackMode::default(),
         }
     }
 
     pub fn add_keyframe(&mut self, vertices: Vec<Vertex>, duration: Duration) {
-        let keyframe = Keyframe { vertices, duration };
+        self.push_keyframe(vertices, duration, None);
+    }
+
+    // packed_frame is the index of the keyframe in the packed frames of the
+    // mesh, see MeshComponent::with_packed_frames.
+    pub fn add_packed_keyframe(
+        &mut self,
+        vertices: Vec<Vertex>,
+        packed_frame: usize,
+        duration: Duration,
+    ) {
+        self.push_keyframe(vertices, duration, Some(packed_frame));
+    }
+
+    // Keyframes are laid end to end, each starting when the previous ends.
+    fn push_keyframe(
+        &mut self,
+        vertices: Vec<Vertex>,
+        duration: Duration,
+        packed_frame: Option<usize>,
+    ) {
+        let keyframe = Keyframe {
+            vertices,
+            start: self.total_duration(),
+            duration,
+            packed_frame,
+        };
         self.keyframes.push(keyframe);
     }
 
-    pub fn animate(&self, time: &Duration) -> Option<&Vec<Vertex>> {
-        let frame = self.keyframes.iter().next().unwrap();
-        return Some(&frame.vertices);
+    pub fn total_duration(&self) -> Duration {
+        self.keyframes.last().map_or(Duration::ZERO, Keyframe::end)
+    }
 
-        if self.keyframes.is_empty() {
-            return None;
+    // Time into the clip in nanoseconds, wrapped or clamped to the total
+    // duration by the playback mode, which must not be zero.
+    fn playback_time(&self, time: &Duration) -> u128 {
+        let total_duration = self.total_duration().as_nanos();
+        let time = time.as_nanos();
+        match self.playback_mode {
+            PlaybackMode::Loop => time % total_duration,
+            PlaybackMode::Once => time.min(total_duration),
+            PlaybackMode::PingPong => {
+                let time = time % (2 * total_duration);
+                if time > total_duration {
+                    2 * total_duration - time
+                } else {
+                    time
+                }
+            }
         }
+    }
 
-        // Find the current keyframes based on the given time
-        let (prev_keyframe, next_keyframe) = self.find_keyframes(time);
+    // Index of the keyframe shown at the given time, with keyframe durations
+    // laid end to end and the animation played as the playback mode says.
+    pub fn frame_index(&self, time: &Duration) -> Option<usize> {
+        let total_duration = self.total_duration();
+        if total_duration.is_zero() {
+            return if self.keyframes.is_empty() {
+                None
+            } else {
+                Some(0)
+            };
+        }
 
-        // Interpolate the animation state between the keyframes
-        let vertices = self.interpolate(prev_keyframe, next_keyframe, time);
+        // Keyframe ends never decrease, so the first one ending after the
+        // time is found by binary search.
+        let time = Duration::from_nanos(self.playback_time(time) as u64);
+        let index = self
+            .keyframes
+            .partition_point(|keyframe| keyframe.end() <= time);
 
-        Some(&vertices)
+        Some(index.min(self.keyframes.len() - 1))
     }
 
-    fn find_keyframes(&self, time: &Duration) -> (&Keyframe, &Keyframe) {
-        // Find the previous and next keyframes based on the given time
-        // You can use different algorithms to find the keyframes, such as binary search
-        // Here, a simple linear search is shown for demonstration purposes
-        let mut prev_keyframe = &self.keyframes[0];
-        let mut next_keyframe = &self.keyframes[0];
+    // False while a ping-pong clip plays backward, other clips only play
+    // backward at negative speeds.
+    fn playing_forward(&self, time: &Duration) -> bool {
+        let total_duration = self.total_duration().as_nanos();
+        self.playback_mode != PlaybackMode::PingPong
+            || total_duration == 0
+            || time.as_nanos() % (2 * total_duration) <= total_duration
+    }
 
-        for keyframe in &self.keyframes {
-            if keyframe.duration.as_millis() <= time.as_millis() {
-                prev_keyframe = keyframe;
-            } else {
-                next_keyframe = keyframe;
-                break;
+    // Keyframes entered going from one to another in the direction played,
+    // looping clips wrap around and ping-pong ones bounce off the end they
+    // passed.
+    fn frames_between(&self, from: usize, to: usize, forward: bool) -> Vec<usize> {
+        let num_frames = self.keyframes.len();
+        let mut frames = Vec::new();
+        if from == to {
+            return frames;
+        }
+
+        match self.playback_mode {
+            PlaybackMode::Loop => {
+                let mut i = from;
+                while i != to {
+                    i = if forward {
+                        (i + 1) % num_frames
+                    } else {
+                        (i + num_frames - 1) % num_frames
+                    };
+                    frames.push(i);
+                }
+            }
+            PlaybackMode::Once if to > from => frames.extend(from + 1..=to),
+            PlaybackMode::Once => frames.extend((to..from).rev()),
+            PlaybackMode::PingPong => match (forward, to > from) {
+                (true, true) => frames.extend(from + 1..=to),
+                (false, false) => frames.extend((to..from).rev()),
+                (true, false) => {
+                    frames.extend((0..from).rev());
+                    frames.extend(1..=to);
+                }
+                (false, true) => {
+                    frames.extend(from + 1..num_frames);
+                    frames.extend((to..num_frames - 1).rev());
+                }
+            },
+        }
+        frames
+    }
+
+    // Keyframe shown at the given time, the one after it and how far between
+    // them. Only looping clips blend the last keyframe into the first.
+    fn keyframe_blend(&self, time: &Duration) -> Option<(usize, usize, f32)> {
+        let index = self.frame_index(time)?;
+        let next = match self.playback_mode {
+            PlaybackMode::Loop => (index + 1) % self.keyframes.len(),
+            PlaybackMode::Once | PlaybackMode::PingPong => {
+                (index + 1).min(self.keyframes.len() - 1)
             }
+        };
+        let keyframe = &self.keyframes[index];
+        // A zero length keyframe has nothing to blend over.
+        if keyframe.duration.is_zero() {
+            return Some((index, next, 0.0));
         }
 
-        (prev_keyframe, next_keyframe)
+        let elapsed =
+            Duration::from_nanos(self.playback_time(time) as u64).saturating_sub(keyframe.start);
+        let t = (elapsed.as_secs_f32() / keyframe.duration.as_secs_f32()).min(1.0);
+
+        Some((index, next, t))
+    }
+
+    // None unless every keyframe has a packed frame.
+    pub fn frame_blend(&self, time: &Duration, lerp: bool) -> Option<(usize, usize, f32)> {
+        let (index, next, t) = self.keyframe_blend(time)?;
+        let current = self.keyframes[index].packed_frame?;
+        let next = self.keyframes[next].packed_frame?;
+        if !lerp {
+            return Some((current, current, 0.0));
+        }
+
+        Some((current, next, t))
     }
 
-    fn interpolate(
-        &self,
-        prev_keyframe: &Keyframe,
-        next_keyframe: &Keyframe,
-        time: &Duration,
-    ) -> Vec<Vertex> {
-        // Interpolate the animation state between the keyframes based on the time
-        // Perform interpolation for each property of the animation state
+    pub fn animate(&self, time: &Duration, lerp: bool) -> Option<Cow<'_, [Vertex]>> {
+        if !lerp {
+            if self.keyframes.is_empty() {
+                return None;
+            }
+
+            // Snap to the keyframe active at the given time, as the original
+            // engine does with r_lerpmodels disabled.
+            let index = self.frame_index(time)?;
+            return Some(Cow::Borrowed(&self.keyframes[index].vertices));
+        }
 
-        // Calculate the interpolation factor (e.g., linear interpolation)
-        let t = (time.as_millis() - prev_keyframe.duration.as_millis())
-            / (next_keyframe.duration.as_millis() - prev_keyframe.duration.as_millis());
+        let (index, next, t) = self.keyframe_blend(time)?;
+        if t == 0.0 {
+            return Some(Cow::Borrowed(&self.keyframes[index].vertices));
+        }
 
-        // Interpolate other properties of the animation state
+        Some(Cow::Owned(Self::interpolate(
+            &self.keyframes[index],
+            &self.keyframes[next],
+            t,
+        )))
+    }
 
-        // Return the interpolated animation state
-        vec![]
+    fn interpolate(prev_keyframe: &Keyframe, next_keyframe: &Keyframe, t: f32) -> Vec<Vertex> {
+        lerp_vertices(&prev_keyframe.vertices, &next_keyframe.vertices, t)
     }
 }
 
+// Positions and texture coordinates are lerped and normals renormalized.
+// Vertex lists of different lengths are cut to the shortest.
+fn lerp_vertices(prev: &[Vertex], next: &[Vertex], t: f32) -> Vec<Vertex> {
+    prev.iter()
+        .zip(next.iter())
+        .map(|(prev, next)| {
+            let normal = Vector3::from(prev.normal).lerp(Vector3::from(next.normal), t);
+            let normal = if normal.magnitude2() > 0.0 {
+                normal.normalize()
+            } else {
+                normal
+            };
+            Vertex {
+                position: Vector3::from(prev.position)
+                    .lerp(Vector3::from(next.position), t)
+                    .into(),
+                normal: normal.into(),
+                texcoord: Vector2::from(prev.texcoord)
+                    .lerp(Vector2::from(next.texcoord), t)
+                    .into(),
+            }
+        })
+        .collect()
+}
+
 pub struct Keyframe {
     vertices: Vec<Vertex>,
+    start: Duration,
     duration: Duration,
+    packed_frame: Option<usize>,
+}
+
+impl Keyframe {
+    fn end(&self) -> Duration {
+        self.start + self.duration
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use super::*;
+    use crate::random::Random;
+
+    // Four keyframes of 100 ms each, counting how often each is entered.
+    fn counted_animation(
+        playback_mode: PlaybackMode,
+        speed: f32,
+    ) -> (KeyframeAnimationComponent, Vec<Rc<Cell<u32>>>) {
+        let mut animation = Animation::new();
+        animation.playback_mode = playback_mode;
+        for _ in 0..4 {
+            animation.add_keyframe(Vec::new(), Duration::from_millis(100));
+        }
+
+        let mut animation_component = KeyframeAnimationComponent::new();
+        animation_component
+            .animations
+            .insert("walk".to_string(), animation);
+        animation_component.set_current_animation("walk").unwrap();
+        animation_component.speed = speed;
+
+        let counts: Vec<Rc<Cell<u32>>> = (0..4).map(|_| Rc::new(Cell::new(0))).collect();
+        for (index, count) in counts.iter().enumerate() {
+            let count = count.clone();
+            animation_component.on_frame("walk", index, move || count.set(count.get() + 1));
+        }
+        (animation_component, counts)
+    }
+
+    fn step(animation_component: &mut KeyframeAnimationComponent, millis: &[u64]) {
+        for millis in millis {
+            animation_component.update_frame_events(&Duration::from_millis(*millis));
+        }
+    }
+
+    fn count_values(counts: &[Rc<Cell<u32>>]) -> Vec<u32> {
+        counts.iter().map(|count| count.get()).collect()
+    }
+
+    #[test]
+    fn frame_callback_fires_once_forward() {
+        let (mut animation_component, counts) = counted_animation(PlaybackMode::Loop, 1.0);
+        step(&mut animation_component, &[0, 50]);
+        assert_eq!(count_values(&counts), vec![1, 0, 0, 0]);
+
+        step(&mut animation_component, &[150, 190]);
+        assert_eq!(count_values(&counts), vec![1, 1, 0, 0]);
+    }
+
+    #[test]
+    fn frame_callback_fires_once_on_loop_wrap() {
+        let (mut animation_component, counts) = counted_animation(PlaybackMode::Loop, 1.0);
+        step(&mut animation_component, &[0, 350]);
+        assert_eq!(count_values(&counts), vec![1, 1, 1, 1]);
+
+        step(&mut animation_component, &[420, 450]);
+        assert_eq!(count_values(&counts), vec![2, 1, 1, 1]);
+    }
+
+    #[test]
+    fn frame_callback_fires_once_in_reverse() {
+        let (mut animation_component, counts) = counted_animation(PlaybackMode::Loop, -1.0);
+        // Starts on the first keyframe and wraps back to the last one.
+        step(&mut animation_component, &[0, 50]);
+        assert_eq!(count_values(&counts), vec![1, 0, 0, 1]);
+
+        step(&mut animation_component, &[150, 160]);
+        assert_eq!(count_values(&counts), vec![1, 0, 1, 1]);
+    }
+
+    #[test]
+    fn frame_callback_fires_once_per_ping_pong_pass() {
+        let (mut animation_component, counts) = counted_animation(PlaybackMode::PingPong, 1.0);
+        step(&mut animation_component, &[0, 250, 350]);
+        assert_eq!(count_values(&counts), vec![1, 1, 1, 1]);
+
+        // Bounces off the last keyframe without entering it again.
+        step(&mut animation_component, &[450, 550]);
+        assert_eq!(count_values(&counts), vec![1, 1, 2, 1]);
+    }
+
+    // The scan frame_index did before the binary search.
+    fn linear_frame_index(animation: &Animation, time: &Duration) -> usize {
+        let time = Duration::from_nanos(animation.playback_time(time) as u64);
+        animation
+            .keyframes
+            .iter()
+            .position(|keyframe| time < keyframe.end())
+            .unwrap_or(animation.keyframes.len() - 1)
+    }
+
+    #[test]
+    fn frame_index_matches_linear_search() {
+        let mut random = Random::new(1526);
+        for playback_mode in [
+            PlaybackMode::Loop,
+            PlaybackMode::Once,
+            PlaybackMode::PingPong,
+        ] {
+            let mut animation = Animation::new();
+            animation.playback_mode = playback_mode;
+            // Some keyframes last no time.
+            for _ in 0..500 {
+                let millis = (random.next_u32() % 5) as u64 * 25;
+                animation.add_keyframe(Vec::new(), Duration::from_millis(millis));
+            }
+
+            let total_duration = animation.total_duration();
+            let times = (0..2000)
+                .map(|_| total_duration.mul_f32(random.range(0.0, 3.0)))
+                .chain([Duration::ZERO, total_duration, total_duration * 2]);
+            for time in times {
+                assert_eq!(
+                    animation.frame_index(&time),
+                    Some(linear_frame_index(&animation, &time)),
+                    "{:?} at {:?}",
+                    playback_mode,
+                    time
+                );
+            }
+        }
+    }
+
+    fn vertex(x: f32) -> Vertex {
+        Vertex {
+            position: [x, 0.0, 0.0],
+            normal: [0.0, 0.0, 1.0],
+            texcoord: [0.0; 2],
+        }
+    }
+
+    #[test]
+    fn midpoint_blends_halfway() {
+        let mut animation = Animation::new();
+        animation.playback_mode = PlaybackMode::Once;
+        animation.add_keyframe(vec![vertex(0.0)], Duration::from_millis(100));
+        animation.add_keyframe(vec![vertex(10.0)], Duration::from_millis(100));
+
+        let (index, next, t) = animation
+            .keyframe_blend(&Duration::from_millis(50))
+            .unwrap();
+        assert_eq!((index, next), (0, 1));
+        assert!((t - 0.5).abs() < 1e-6, "{}", t);
+
+        let vertices = animation.animate(&Duration::from_millis(50), true).unwrap();
+        assert!((vertices[0].position[0] - 5.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn zero_length_keyframe_does_not_blend() {
+        let mut animation = Animation::new();
+        animation.playback_mode = PlaybackMode::Once;
+        animation.add_keyframe(vec![vertex(0.0)], Duration::from_millis(100));
+        animation.add_keyframe(vec![vertex(10.0)], Duration::ZERO);
+
+        let blend = animation.keyframe_blend(&Duration::from_millis(200));
+        assert_eq!(blend, Some((1, 1, 0.0)));
+    }
+
+    #[test]
+    fn total_duration_sums_the_keyframes() {
+        let mut animation = Animation::new();
+        for _ in 0..3 {
+            animation.add_keyframe(Vec::new(), Duration::from_millis(100));
+        }
+        assert_eq!(animation.total_duration(), Duration::from_millis(300));
+
+        let mut animation_component = KeyframeAnimationComponent::new();
+        animation_component
+            .animations
+            .insert("death".to_string(), animation);
+        assert_eq!(
+            animation_component.duration_of("death"),
+            Some(Duration::from_millis(300))
+        );
+        assert_eq!(animation_component.duration_of("pain"), None);
+    }
+
+    fn pose_x(animation_component: &KeyframeAnimationComponent, time: &Duration) -> Vec<f32> {
+        animation_component
+            .animate(time)
+            .unwrap()
+            .iter()
+            .map(|vertex| vertex.position[0])
+            .collect()
+    }
+
+    #[test]
+    fn masked_vertices_take_the_upper_animation() {
+        let mut animation_component = KeyframeAnimationComponent::new();
+        for (name, x) in [("run", 1.0), ("shoot", 2.0)] {
+            let mut animation = Animation::new();
+            animation.add_keyframe(vec![vertex(x); 3], Duration::from_millis(100));
+            animation_component
+                .animations
+                .insert(name.to_string(), animation);
+        }
+        animation_component.set_current_animation("run").unwrap();
+        assert_eq!(pose_x(&animation_component, &Duration::ZERO), [1.0; 3]);
+
+        animation_component.set_upper_body(Some("shoot"), vec![true, false, true]);
+        assert_eq!(
+            pose_x(&animation_component, &Duration::ZERO),
+            [2.0, 1.0, 2.0]
+        );
+        // The combined pose cannot be blended on the GPU.
+        assert_eq!(animation_component.frame_blend(&Duration::ZERO), None);
+
+        // Vertices past the end of the mask are lower body.
+        animation_component.set_upper_body(Some("shoot"), vec![true]);
+        assert_eq!(
+            pose_x(&animation_component, &Duration::ZERO),
+            [2.0, 1.0, 1.0]
+        );
+
+        animation_component.set_upper_body(None::<&str>, Vec::new());
+        assert_eq!(pose_x(&animation_component, &Duration::ZERO), [1.0; 3]);
+    }
+
+    #[test]
+    fn lerp_off_snaps_to_a_keyframe() {
+        let mut animation = Animation::new();
+        animation.add_packed_keyframe(vec![vertex(0.0)], 0, Duration::from_millis(100));
+        animation.add_packed_keyframe(vec![vertex(10.0)], 1, Duration::from_millis(100));
+
+        for (millis, x) in [(0, 0.0), (50, 0.0), (99, 0.0), (100, 10.0), (150, 10.0)] {
+            let time = Duration::from_millis(millis);
+            let vertices = animation.animate(&time, false).unwrap();
+            assert!(matches!(vertices, Cow::Borrowed(_)), "{} ms", millis);
+            assert_eq!(vertices[0].position[0], x, "{} ms", millis);
+        }
+        let time = Duration::from_millis(50);
+        assert_eq!(animation.animate(&time, true).unwrap()[0].position[0], 5.0);
+
+        // The GPU path draws the same keyframe twice.
+        assert_eq!(animation.frame_blend(&time, false), Some((0, 0, 0.0)));
+        assert_eq!(
+            animation.frame_blend(&Duration::from_millis(150), false),
+            Some((1, 1, 0.0))
+        );
+        assert_eq!(animation.frame_blend(&time, true), Some((0, 1, 0.5)));
+    }
+
+    #[test]
+    fn current_frame_index_follows_the_clip_time() {
+        let (mut animation_component, _) = counted_animation(PlaybackMode::Once, 1.0);
+        assert_eq!(
+            animation_component.current_frame_index(&Duration::ZERO),
+            Some(0)
+        );
+
+        // The clip starts on the first update, here a second in.
+        let start = Duration::from_secs(1);
+        animation_component.update_frame_events(&start);
+        assert_eq!(animation_component.current_frame_index(&start), Some(0));
+        for (millis, index) in [(99, 0), (100, 1), (250, 2), (399, 3), (1000, 3)] {
+            let time = start + Duration::from_millis(millis);
+            assert_eq!(
+                animation_component.current_frame_index(&time),
+                Some(index),
+                "{} ms",
+                millis
+            );
+        }
+
+        animation_component.current_animation = None;
+        assert_eq!(animation_component.current_frame_index(&start), None);
+    }
+
+    #[test]
+    fn looping_clip_is_back_on_frame_0_after_one_cycle() {
+        let mut animation = Animation::new();
+        for x in [0.0, 10.0, 20.0] {
+            animation.add_keyframe(vec![vertex(x)], Duration::from_millis(100));
+        }
+        let cycle = animation.total_duration();
+        assert_eq!(cycle, Duration::from_millis(300));
+
+        assert_eq!(
+            animation.frame_index(&(cycle - Duration::from_nanos(1))),
+            Some(2)
+        );
+        assert_eq!(animation.frame_index(&cycle), Some(0));
+        assert_eq!(animation.frame_index(&(cycle * 2)), Some(0));
+        for lerp in [false, true] {
+            let vertices = animation.animate(&cycle, lerp).unwrap();
+            assert_eq!(vertices[0].position[0], 0.0);
+        }
+
+        // The same through the component, from when the clip starts.
+        let (mut animation_component, _) = counted_animation(PlaybackMode::Loop, 1.0);
+        let cycle = animation_component.duration_of("walk").unwrap();
+        let start = Duration::from_millis(50);
+        animation_component.update_frame_events(&start);
+        assert_eq!(
+            animation_component.current_frame_index(&(start + cycle - Duration::from_nanos(1))),
+            Some(3)
+        );
+        assert_eq!(
+            animation_component.current_frame_index(&(start + cycle)),
+            Some(0)
+        );
+    }
 }
@@ -0,0 +1,309 @@
+use cgmath::{EuclideanSpace, Matrix4, Point3, Transform, Vector3};
+
+use crate::camera::Plane;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Vector3<f32>,
+    pub max: Vector3<f32>,
+}
+
+impl Aabb {
+    pub fn new(min: Vector3<f32>, max: Vector3<f32>) -> Self {
+        Self { min, max }
+    }
+
+    pub fn from_points<I>(points: I) -> Option<Self>
+    where
+        I: IntoIterator<Item = Vector3<f32>>,
+    {
+        points.into_iter().fold(None, |aabb, point| match aabb {
+            Some(aabb) => Some(aabb.union(&Aabb::new(point, point))),
+            None => Some(Aabb::new(point, point)),
+        })
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb::new(
+            Vector3::new(
+                self.min.x.min(other.min.x),
+                self.min.y.min(other.min.y),
+                self.min.z.min(other.min.z),
+            ),
+            Vector3::new(
+                self.max.x.max(other.max.x),
+                self.max.y.max(other.max.y),
+                self.max.z.max(other.max.z),
+            ),
+        )
+    }
+
+    pub fn expand(&self, margin: f32) -> Aabb {
+        let margin = Vector3::new(margin, margin, margin);
+        Aabb::new(self.min - margin, self.max + margin)
+    }
+
+    pub fn contains(&self, other: &Aabb) -> bool {
+        self.min.x <= other.min.x
+            && self.min.y <= other.min.y
+            && self.min.z <= other.min.z
+            && self.max.x >= other.max.x
+            && self.max.y >= other.max.y
+            && self.max.z >= other.max.z
+    }
+
+    pub fn center(&self) -> Vector3<f32> {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn corners(&self) -> [Vector3<f32>; 8] {
+        [
+            Vector3::new(self.min.x, self.min.y, self.min.z),
+            Vector3::new(self.max.x, self.min.y, self.min.z),
+            Vector3::new(self.min.x, self.max.y, self.min.z),
+            Vector3::new(self.max.x, self.max.y, self.min.z),
+            Vector3::new(self.min.x, self.min.y, self.max.z),
+            Vector3::new(self.max.x, self.min.y, self.max.z),
+            Vector3::new(self.min.x, self.max.y, self.max.z),
+            Vector3::new(self.max.x, self.max.y, self.max.z),
+        ]
+    }
+
+    pub fn transform(&self, matrix: &Matrix4<f32>) -> Aabb {
+        Aabb::from_points(
+            self.corners()
+                .iter()
+                .map(|corner| matrix.transform_point(Point3::from_vec(*corner)).to_vec()),
+        )
+        .unwrap()
+    }
+
+    // Plane normals point inside, the box is only rejected when it lies fully
+    // behind one of them.
+    pub fn intersects_planes(&self, planes: &[Plane]) -> bool {
+        planes.iter().all(|plane| {
+            let positive = Vector3::new(
+                if plane.normal.x >= 0.0 {
+                    self.max.x
+                } else {
+                    self.min.x
+                },
+                if plane.normal.y >= 0.0 {
+                    self.max.y
+                } else {
+                    self.min.y
+                },
+                if plane.normal.z >= 0.0 {
+                    self.max.z
+                } else {
+                    self.min.z
+                },
+            );
+            plane.distance(positive) >= 0.0
+        })
+    }
+
+    pub fn intersects_segment(&self, start: Vector3<f32>, end: Vector3<f32>) -> bool {
+        let direction = end - start;
+        let mut t_min = 0f32;
+        let mut t_max = 1f32;
+        for axis in 0..3 {
+            if direction[axis].abs() < f32::EPSILON {
+                if start[axis] < self.min[axis] || start[axis] > self.max[axis] {
+                    return false;
+                }
+                continue;
+            }
+
+            let t0 = (self.min[axis] - start[axis]) / direction[axis];
+            let t1 = (self.max[axis] - start[axis]) / direction[axis];
+            t_min = t_min.max(t0.min(t1));
+            t_max = t_max.min(t0.max(t1));
+            if t_min > t_max {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+enum BvhNode {
+    Leaf {
+        aabb: Aabb,
+        index: usize,
+    },
+    Branch {
+        aabb: Aabb,
+        left: usize,
+        right: usize,
+    },
+}
+
+impl BvhNode {
+    fn aabb(&self) -> &Aabb {
+        match self {
+            BvhNode::Leaf { aabb, .. } => aabb,
+            BvhNode::Branch { aabb, .. } => aabb,
+        }
+    }
+}
+
+// Leaves are stored enlarged by MARGIN so that small movements do not
+// require rebuilding the hierarchy; see needs_rebuild.
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    leaves: Vec<Aabb>,
+}
+
+impl Bvh {
+    const MARGIN: f32 = 16.0;
+
+    pub fn new() -> Self {
+        Self {
+            nodes: Vec::new(),
+            leaves: Vec::new(),
+        }
+    }
+
+    pub fn build(aabbs: &[Aabb]) -> Self {
+        let leaves: Vec<Aabb> = aabbs.iter().map(|aabb| aabb.expand(Self::MARGIN)).collect();
+        let mut bvh = Self {
+            nodes: Vec::with_capacity(leaves.len() * 2),
+            leaves,
+        };
+
+        let mut indices: Vec<usize> = (0..bvh.leaves.len()).collect();
+        if !indices.is_empty() {
+            bvh.build_node(&mut indices);
+        }
+        bvh
+    }
+
+    pub fn needs_rebuild(&self, aabbs: &[Aabb]) -> bool {
+        aabbs.len() != self.leaves.len()
+            || self
+                .leaves
+                .iter()
+                .zip(aabbs)
+                .any(|(leaf, aabb)| !leaf.contains(aabb))
+    }
+
+    pub fn query_planes(&self, planes: &[Plane]) -> Vec<usize> {
+        self.query(|aabb| aabb.intersects_planes(planes))
+    }
+
+    pub fn query_segment(&self, start: Vector3<f32>, end: Vector3<f32>) -> Vec<usize> {
+        self.query(|aabb| aabb.intersects_segment(start, end))
+    }
+
+    // Results are conservative: leaves are tested with their enlarged boxes.
+    pub fn query<F>(&self, predicate: F) -> Vec<usize>
+    where
+        F: Fn(&Aabb) -> bool,
+    {
+        let mut indices = Vec::new();
+        if self.nodes.is_empty() {
+            return indices;
+        }
+
+        let mut stack = vec![0];
+        while let Some(node) = stack.pop() {
+            let node = &self.nodes[node];
+            if !predicate(node.aabb()) {
+                continue;
+            }
+
+            match node {
+                BvhNode::Leaf { index, .. } => indices.push(*index),
+                BvhNode::Branch { left, right, .. } => {
+                    stack.push(*left);
+                    stack.push(*right);
+                }
+            }
+        }
+        indices.sort_unstable();
+        indices
+    }
+
+    fn build_node(&mut self, indices: &mut [usize]) -> usize {
+        let aabb = indices
+            .iter()
+            .skip(1)
+            .fold(self.leaves[indices[0]], |aabb, index| {
+                aabb.union(&self.leaves[*index])
+            });
+
+        if indices.len() == 1 {
+            self.nodes.push(BvhNode::Leaf {
+                aabb,
+                index: indices[0],
+            });
+            return self.nodes.len() - 1;
+        }
+
+        // Median split along the longest axis of the node.
+        let extent = aabb.max - aabb.min;
+        let axis = if extent.x >= extent.y && extent.x >= extent.z {
+            0
+        } else if extent.y >= extent.z {
+            1
+        } else {
+            2
+        };
+        indices.sort_unstable_by(|a, b| {
+            self.leaves[*a].center()[axis].total_cmp(&self.leaves[*b].center()[axis])
+        });
+
+        let node = self.nodes.len();
+        self.nodes.push(BvhNode::Leaf { aabb, index: 0 });
+        let (left_indices, right_indices) = indices.split_at_mut(indices.len() / 2);
+        let left = self.build_node(left_indices);
+        let right = self.build_node(right_indices);
+        self.nodes[node] = BvhNode::Branch { aabb, left, right };
+        node
+    }
+}
+
+impl Default for Bvh {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{camera::Camera, random::Random};
+
+    #[test]
+    fn query_planes_matches_brute_force() {
+        let mut random = Random::new(1401);
+        let aabbs: Vec<Aabb> = (0..1000)
+            .map(|_| {
+                let min = Vector3::new(
+                    random.range(-2000.0, 2000.0),
+                    random.range(-2000.0, 2000.0),
+                    random.range(-2000.0, 2000.0),
+                );
+                let size = Vector3::new(
+                    random.range(1.0, 64.0),
+                    random.range(1.0, 64.0),
+                    random.range(1.0, 64.0),
+                );
+                Aabb::new(min, min + size)
+            })
+            .collect();
+        let bvh = Bvh::build(&aabbs);
+        let planes = Camera::new(800, 600).frustum_planes();
+
+        let found = bvh.query_planes(&planes);
+        let brute_force: Vec<usize> = (0..aabbs.len())
+            .filter(|i| aabbs[*i].expand(Bvh::MARGIN).intersects_planes(&planes))
+            .collect();
+        assert_eq!(found, brute_force);
+        assert!(!found.is_empty() && found.len() < aabbs.len());
+        assert!((0..aabbs.len())
+            .filter(|i| aabbs[*i].intersects_planes(&planes))
+            .all(|i| found.contains(&i)));
+    }
+}
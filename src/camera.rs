@@ -1,21 +1,113 @@
-use std::f32::consts::FRAC_PI_2;
+use std::{f32::consts::FRAC_PI_2, time::Duration};
 
-use cgmath::{Matrix4, Point3, Rad, Vector3};
+use cgmath::{
+    Deg, EuclideanSpace, InnerSpace, Matrix, Matrix3, Matrix4, Point3, Rad, Vector3, Vector4, Zero,
+};
 
-use crate::hid::HIDEvent;
+use crate::{
+    hid::HIDEvent,
+    transform::{from_quake_coords, to_quake_coords},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Plane {
+    pub normal: Vector3<f32>,
+    pub d: f32,
+}
+
+impl Plane {
+    fn from_coefficients(coefficients: Vector4<f32>) -> Self {
+        let normal = coefficients.truncate();
+        let length = normal.magnitude();
+
+        Self {
+            normal: normal / length,
+            d: coefficients.w / length,
+        }
+    }
+
+    // Signed distance, positive on the side the normal points to.
+    pub fn distance(&self, point: Vector3<f32>) -> f32 {
+        self.normal.dot(point) + self.d
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Frustum {
+    pub planes: [Plane; 6],
+}
+
+impl Frustum {
+    // Conservative, spheres near the corners may pass while outside.
+    pub fn contains_sphere(&self, center: Vector3<f32>, radius: f32) -> bool {
+        self.planes
+            .iter()
+            .all(|plane| plane.distance(center) >= -radius)
+    }
+
+    pub fn contains_point(&self, point: Vector3<f32>) -> bool {
+        self.contains_sphere(point, 0.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum FovMode {
+    #[default]
+    Vertical,
+    // Quake's fov cvar is horizontal, so it has to be narrowed vertically on
+    // wide aspect ratios.
+    Horizontal,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ProjectionMode {
+    #[default]
+    Perspective,
+    // Height of the view volume in world units, its width follows the aspect
+    // ratio. Useful for model viewers and debugging geometry.
+    Orthographic {
+        height: f32,
+    },
+}
+
+// How much each movement key is held, see HIDEvent.
+#[derive(Clone, Copy, Debug, Default)]
+struct MovementInput {
+    forward: f32,
+    backward: f32,
+    left: f32,
+    right: f32,
+    up: f32,
+    down: f32,
+}
 
 pub struct Camera {
     field_of_view: Rad<f32>,
+    fov_mode: FovMode,
+    projection_mode: ProjectionMode,
     aspect_ratio: f32,
     near_clip_plane: f32,
     far_clip_plane: f32,
+    roll: Rad<f32>,
+    yaw: Rad<f32>,
+    pitch: Rad<f32>,
+    movement: MovementInput,
 
     pub eye: Point3<f32>,
     pub center: Point3<f32>,
     pub up: Vector3<f32>,
+    // Looking straight up or down would flip the view.
+    pub pitch_limit: Rad<f32>,
 }
 
 impl Camera {
+    // Quake units per second, Quake's cl_forwardspeed.
+    pub const MOVE_SPEED: f32 = 200.0;
+    // Radians turned per unit of Motion.
+    pub const LOOK_SPEED: f32 = 0.0025;
+    pub const MIN_FOV: Deg<f32> = Deg(10.0);
+    pub const MAX_FOV: Deg<f32> = Deg(170.0);
+
     pub fn new(width: i32, height: i32) -> Self {
         let field_of_view = Rad(FRAC_PI_2);
         let aspect_ratio = width as f32 / height as f32;
@@ -24,44 +116,400 @@ impl Camera {
 
         Self {
             field_of_view,
+            fov_mode: FovMode::default(),
+            projection_mode: ProjectionMode::default(),
             aspect_ratio,
             near_clip_plane: near,
             far_clip_plane: far,
+            roll: Rad::zero(),
+            yaw: Rad::zero(),
+            pitch: Rad::zero(),
+            movement: MovementInput::default(),
 
             eye: Point3::new(0f32, 0f32, 0.0f32),
-            center: Point3::new(0f32, 0f32, 0f32),
+            // Looking along the Quake x axis, see forward.
+            center: Point3::new(0f32, 0f32, -1f32),
             up: Vector3::unit_y(),
+            pitch_limit: Deg(89.0).into(),
         }
     }
 
+    // A zero height, as when the window is minimized, keeps the previous
+    // aspect ratio rather than dividing by zero.
+    pub fn resize(&mut self, width: u32, height: u32) {
+        if height == 0 {
+            return;
+        }
+        self.aspect_ratio = width as f32 / height as f32;
+    }
+
+    // Motion turns the view right away. Move events only record how much
+    // each key is held, the camera moves when apply_movement is called every
+    // frame.
     pub fn update(&mut self, event: HIDEvent) {
         match event {
-            HIDEvent::MoveForward(delta) => self.eye.z += delta,
-            HIDEvent::MoveBackward(delta) => self.eye.z -= delta,
-            HIDEvent::MoveLeft(delta) => {
-                self.eye.x += delta;
-                self.center.x += delta;
+            HIDEvent::MoveForward(value) => self.movement.forward = value,
+            HIDEvent::MoveBackward(value) => self.movement.backward = value,
+            HIDEvent::MoveLeft(value) => self.movement.left = value,
+            HIDEvent::MoveRight(value) => self.movement.right = value,
+            HIDEvent::MoveUp(value) => self.movement.up = value,
+            HIDEvent::MoveDown(value) => self.movement.down = value,
+            // Moving the mouse right turns right and moving it down looks
+            // down.
+            HIDEvent::Motion(x, y) => {
+                self.yaw -= Rad(x * Self::LOOK_SPEED);
+                self.pitch = self.clamp_pitch(self.pitch - Rad(y * Self::LOOK_SPEED));
+                self.center = self.eye + self.forward();
             }
-            HIDEvent::MoveRight(delta) => {
-                self.eye.x -= delta;
-                self.center.x -= delta;
+            HIDEvent::Attack(_) | HIDEvent::Jump(_) | HIDEvent::Use(_) => (),
+        }
+    }
+
+    // Held keys combined, x towards the right, y up and z forward, each in
+    // [-1, 1]. Opposite keys cancel out.
+    pub fn movement_input(&self) -> Vector3<f32> {
+        Vector3::new(
+            self.movement.right - self.movement.left,
+            self.movement.up - self.movement.down,
+            self.movement.forward - self.movement.backward,
+        )
+    }
+
+    // Flies along the view direction, so looking up and moving forward
+    // climbs.
+    pub fn apply_movement(&mut self, delta_time: Duration) {
+        let input = self.movement_input();
+        let forward = self.forward();
+        let right = forward.cross(self.up).normalize();
+        let offset = (right * input.x + self.up * input.y + forward * input.z)
+            * Self::MOVE_SPEED
+            * delta_time.as_secs_f32();
+        self.eye += offset;
+        self.center = self.eye + forward;
+    }
+
+    // Unit view direction from the yaw and pitch.
+    pub fn forward(&self) -> Vector3<f32> {
+        let (yaw_sin, yaw_cos) = self.yaw.0.sin_cos();
+        let (pitch_sin, pitch_cos) = self.pitch.0.sin_cos();
+        from_quake_coords(Vector3::new(
+            yaw_cos * pitch_cos,
+            yaw_sin * pitch_cos,
+            pitch_sin,
+        ))
+    }
+
+    // Yaw is counterclockwise from the Quake x axis, positive pitch looks up.
+    pub fn yaw_pitch(&self) -> (Rad<f32>, Rad<f32>) {
+        (self.yaw, self.pitch)
+    }
+
+    pub fn set_yaw_pitch<A: Into<Rad<f32>>>(&mut self, yaw: A, pitch: A) {
+        self.yaw = yaw.into();
+        self.pitch = self.clamp_pitch(pitch.into());
+        self.center = self.eye + self.forward();
+    }
+
+    fn clamp_pitch(&self, pitch: Rad<f32>) -> Rad<f32> {
+        let limit = self.pitch_limit.0.abs();
+        Rad(pitch.0.clamp(-limit, limit))
+    }
+
+    pub fn set_field_of_view<A: Into<Rad<f32>>>(&mut self, field_of_view: A, fov_mode: FovMode) {
+        self.fov_mode = fov_mode;
+        self.set_fov(field_of_view);
+    }
+
+    // The field of view in the current FovMode, clamped to [MIN_FOV,
+    // MAX_FOV] since the perspective degenerates towards 0 and 180 degrees.
+    pub fn set_fov<A: Into<Rad<f32>>>(&mut self, fov: A) {
+        let min = Rad::from(Self::MIN_FOV).0;
+        let max = Rad::from(Self::MAX_FOV).0;
+        self.field_of_view = Rad(fov.into().0.clamp(min, max));
+    }
+
+    pub fn fov(&self) -> Rad<f32> {
+        self.field_of_view
+    }
+
+    pub fn vertical_field_of_view(&self) -> Rad<f32> {
+        match self.fov_mode {
+            FovMode::Vertical => self.field_of_view,
+            FovMode::Horizontal => {
+                Rad(2.0 * ((self.field_of_view.0 / 2.0).tan() / self.aspect_ratio).atan())
             }
-            HIDEvent::Motion(x, y) => {
-                self.center.x -= x;
-                self.center.y -= y;
+        }
+    }
+
+    // Moves the eye back along the current view direction until the sphere
+    // around the box fits in the narrowest field of view.
+    pub fn frame_bounds(&mut self, min: [f32; 3], max: [f32; 3]) {
+        let min = Point3::from(min);
+        let max = Point3::from(max);
+        let center = min.midpoint(max);
+        let radius = (max - min).magnitude() / 2.0;
+
+        let vertical_field_of_view = self.vertical_field_of_view().0;
+        let horizontal_field_of_view =
+            2.0 * ((vertical_field_of_view / 2.0).tan() * self.aspect_ratio).atan();
+        let field_of_view = vertical_field_of_view.min(horizontal_field_of_view);
+        let distance = (radius / (field_of_view / 2.0).sin()).max(self.near_clip_plane + radius);
+
+        let direction = self.center - self.eye;
+        let direction = if direction.magnitude2() > 0.0 {
+            direction.normalize()
+        } else {
+            -Vector3::unit_z()
+        };
+        self.eye = center - direction * distance;
+        self.center = center;
+    }
+
+    // Places the eye at a Quake position looking along a Quake yaw, in degrees
+    // counterclockwise from the x axis.
+    pub fn set_quake_view(&mut self, origin: [f32; 3], yaw: f32) {
+        self.eye = Point3::from_vec(from_quake_coords(origin.into()));
+        self.set_yaw_pitch(Deg(yaw), Deg(0.0));
+    }
+
+    pub fn quake_position(&self) -> [f32; 3] {
+        to_quake_coords(self.eye.to_vec()).into()
+    }
+
+    pub fn roll(&self) -> Rad<f32> {
+        self.roll
+    }
+
+    // Positive roll tilts the head to the right, like Quake's strafe tilt and
+    // damage kicks.
+    pub fn set_roll<A: Into<Rad<f32>>>(&mut self, roll: A) {
+        self.roll = roll.into();
+    }
+
+    pub fn add_roll<A: Into<Rad<f32>>>(&mut self, roll: A) {
+        self.roll += roll.into();
+    }
+
+    // The up vector rotated by the roll about the view direction.
+    pub fn rolled_up(&self) -> Vector3<f32> {
+        let direction = self.center - self.eye;
+        if self.roll.is_zero() || direction.magnitude2() == 0.0 {
+            return self.up;
+        }
+
+        Matrix3::from_axis_angle(direction.normalize(), self.roll) * self.up
+    }
+
+    pub fn view_matrix(&self) -> Matrix4<f32> {
+        cgmath::Matrix4::look_at_rh(self.eye, self.center, self.rolled_up())
+    }
+
+    pub fn projection_mode(&self) -> ProjectionMode {
+        self.projection_mode
+    }
+
+    pub fn set_projection_mode(&mut self, projection_mode: ProjectionMode) {
+        self.projection_mode = projection_mode;
+    }
+
+    pub fn projection_matrix(&self) -> Matrix4<f32> {
+        match self.projection_mode {
+            ProjectionMode::Perspective => {
+                self.projection_matrix_with_fov(self.vertical_field_of_view())
+            }
+            ProjectionMode::Orthographic { height } => {
+                let top = height / 2.0;
+                let right = top * self.aspect_ratio;
+                cgmath::ortho(
+                    -right,
+                    right,
+                    -top,
+                    top,
+                    self.near_clip_plane,
+                    self.far_clip_plane,
+                )
             }
         }
     }
 
-    pub fn view_projection_matrix(&self) -> Matrix4<f32> {
-        let view_matrix = cgmath::Matrix4::look_at_rh(self.eye, self.center, self.up);
-        let projection_matrix = cgmath::perspective(
-            self.field_of_view,
+    // Same clip planes and aspect ratio as the camera with a different
+    // vertical field of view, used by the viewmodel.
+    pub fn projection_matrix_with_fov<A: Into<Rad<f32>>>(&self, field_of_view: A) -> Matrix4<f32> {
+        cgmath::perspective(
+            field_of_view.into(),
             self.aspect_ratio,
             self.near_clip_plane,
             self.far_clip_plane,
-        );
+        )
+    }
+
+    pub fn view_projection_matrix(&self) -> Matrix4<f32> {
+        self.projection_matrix() * self.view_matrix()
+    }
+
+    // Gribb-Hartmann extraction with the normals pointing inside, ordered
+    // left, right, bottom, top, near, far.
+    pub fn frustum_planes(&self) -> [Plane; 6] {
+        let m = self.view_projection_matrix();
+        [
+            m.row(3) + m.row(0),
+            m.row(3) - m.row(0),
+            m.row(3) + m.row(1),
+            m.row(3) - m.row(1),
+            m.row(3) + m.row(2),
+            m.row(3) - m.row(2),
+        ]
+        .map(Plane::from_coefficients)
+    }
+
+    pub fn frustum(&self) -> Frustum {
+        Frustum {
+            planes: self.frustum_planes(),
+        }
+    }
+
+    // Same as view_projection_matrix but ignoring the eye position, which is
+    // what geometry at infinite distance such as the sky needs.
+    pub fn rotation_projection_matrix(&self) -> Matrix4<f32> {
+        let rotation_matrix =
+            cgmath::Matrix4::look_to_rh(Point3::origin(), self.center - self.eye, self.rolled_up());
+
+        self.projection_matrix() * rotation_matrix
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resize_updates_the_projection() {
+        let mut camera = Camera::new(1280, 720);
+        let before = camera.projection_matrix();
+
+        camera.resize(640, 720);
+        let after = camera.projection_matrix();
 
-        projection_matrix * view_matrix
+        // Halving the aspect ratio doubles the horizontal scale.
+        assert!((after.x.x - 2.0 * before.x.x).abs() < 1e-5);
+        assert!((after.y.y - before.y.y).abs() < 1e-5);
+    }
+
+    #[test]
+    fn resize_ignores_a_zero_height() {
+        let mut camera = Camera::new(1280, 720);
+        let before = camera.projection_matrix();
+
+        camera.resize(1280, 0);
+
+        assert_eq!(camera.projection_matrix(), before);
+    }
+
+    #[test]
+    fn large_motion_never_flips_the_pitch() {
+        let mut camera = Camera::new(1280, 720);
+        let forward = (camera.center - camera.eye).normalize();
+        for y in [-1.0e6, -1.0e3, 1.0e6, 1.0e3, -1.0e6] {
+            for _ in 0..10 {
+                camera.update(HIDEvent::Motion(0.0, y));
+
+                let direction = (camera.center - camera.eye).normalize();
+                // Never straight along the up axis, and never past it onto
+                // the other side.
+                assert!(direction.dot(camera.up).abs() < 0.9999, "{:?}", direction);
+                let horizontal = direction - camera.up * direction.dot(camera.up);
+                assert!(horizontal.dot(forward) > 0.0, "{:?}", direction);
+            }
+        }
+    }
+
+    #[test]
+    fn orthographic_projection_keeps_w() {
+        let mut camera = Camera::new(1280, 720);
+        camera.set_projection_mode(ProjectionMode::Orthographic { height: 512.0 });
+        let matrix = camera.projection_matrix();
+
+        for point in [
+            Vector4::new(0.0, 0.0, -10.0, 1.0),
+            Vector4::new(100.0, -50.0, -1000.0, 1.0),
+        ] {
+            assert!(((matrix * point).w - 1.0).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn horizontal_fov_narrows_on_widescreen() {
+        let mut camera = Camera::new(1920, 1080);
+        camera.set_field_of_view(Deg(90.0), FovMode::Horizontal);
+        let vertical = Deg::from(camera.vertical_field_of_view()).0;
+        assert!((vertical - 58.7155).abs() < 1e-3, "{}", vertical);
+
+        camera.set_field_of_view(Deg(90.0), FovMode::Vertical);
+        let vertical = Deg::from(camera.vertical_field_of_view()).0;
+        assert!((vertical - 90.0).abs() < 1e-4, "{}", vertical);
+    }
+
+    #[test]
+    fn roll_rotates_the_up_vector() {
+        let mut camera = Camera::new(1280, 720);
+        assert_eq!(camera.rolled_up(), camera.up);
+
+        // Looking down -z, so the right of the view is +x.
+        camera.set_roll(Deg(90.0));
+        assert!((camera.rolled_up() - Vector3::unit_x()).magnitude() < 1e-5);
+
+        camera.add_roll(Deg(90.0));
+        assert!((camera.rolled_up() + Vector3::unit_y()).magnitude() < 1e-5);
+
+        camera.set_roll(Deg(30.0));
+        let up = camera.rolled_up();
+        assert!((up.dot(Vector3::unit_y()) - 30f32.to_radians().cos()).abs() < 1e-5);
+        assert!(up.dot(Vector3::unit_z()).abs() < 1e-5);
+    }
+
+    #[test]
+    fn frustum_planes_bound_the_view() {
+        let camera = Camera::new(1280, 720);
+        let planes = camera.frustum_planes();
+        let frustum = camera.frustum();
+
+        // The camera looks down -z with the clip planes at 4 and 4096.
+        let inside = Vector3::new(10.0, -5.0, -100.0);
+        assert!(planes.iter().all(|plane| plane.distance(inside) > 0.0));
+        assert!(frustum.contains_point(inside));
+
+        let behind = Vector3::new(0.0, 0.0, 100.0);
+        assert!(planes[4].distance(behind) < 0.0);
+        assert!(!frustum.contains_point(behind));
+
+        let past_far = Vector3::new(0.0, 0.0, -5000.0);
+        assert!(planes[5].distance(past_far) < 0.0);
+        assert!(!frustum.contains_point(past_far));
+    }
+
+    #[test]
+    fn framing_keeps_a_unit_cube_on_screen() {
+        let mut camera = Camera::new(1920, 1080);
+        camera.set_field_of_view(Deg(90.0), FovMode::Horizontal);
+        camera.frame_bounds([0.0; 3], [1.0; 3]);
+
+        let center = Point3::new(0.5, 0.5, 0.5);
+        assert_eq!(camera.center, center);
+        // Still looking down -z, from far enough for the bounding sphere.
+        let offset = camera.eye - center;
+        assert!(offset.x.abs() < 1e-5 && offset.y.abs() < 1e-5 && offset.z > 0.0);
+        let radius = 3f32.sqrt() / 2.0;
+        let half_fov = camera.vertical_field_of_view().0 / 2.0;
+        assert!(offset.magnitude() >= radius / half_fov.sin() - 1e-4);
+
+        let frustum = camera.frustum();
+        for corner in 0..8 {
+            let point = Vector3::new(
+                (corner & 1) as f32,
+                ((corner >> 1) & 1) as f32,
+                ((corner >> 2) & 1) as f32,
+            );
+            assert!(frustum.contains_point(point), "{:?}", point);
+        }
     }
 }
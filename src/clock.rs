@@ -0,0 +1,132 @@
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug)]
+enum ClockSource {
+    // Wall-clock time since the previous tick.
+    Real(Instant),
+    // The same step on every tick, so runs such as demos are reproducible.
+    Fixed(Duration),
+    // Only moves when advanced explicitly.
+    Manual,
+}
+
+// Game time handed to the scene, so updates do not depend on reading the
+// system clock themselves.
+#[derive(Clone, Copy, Debug)]
+pub struct Clock {
+    source: ClockSource,
+    elapsed: Duration,
+    delta: Duration,
+}
+
+impl Clock {
+    pub fn real() -> Self {
+        Self::with_source(ClockSource::Real(Instant::now()))
+    }
+
+    pub fn fixed(step: Duration) -> Self {
+        Self::with_source(ClockSource::Fixed(step))
+    }
+
+    pub fn manual() -> Self {
+        Self::with_source(ClockSource::Manual)
+    }
+
+    fn with_source(source: ClockSource) -> Self {
+        Self {
+            source,
+            elapsed: Duration::ZERO,
+            delta: Duration::ZERO,
+        }
+    }
+
+    // Starts a new frame and returns its delta, which is zero for manual
+    // clocks.
+    pub fn tick(&mut self) -> Duration {
+        let delta = match &mut self.source {
+            ClockSource::Real(last_tick) => {
+                let now = Instant::now();
+                let delta = now - *last_tick;
+                *last_tick = now;
+                delta
+            }
+            ClockSource::Fixed(step) => *step,
+            ClockSource::Manual => Duration::ZERO,
+        };
+        self.advance(delta);
+        delta
+    }
+
+    // Starts a new frame lasting exactly delta, whatever the source.
+    pub fn advance(&mut self, delta: Duration) {
+        self.elapsed += delta;
+        self.delta = delta;
+    }
+
+    // Time since the clock was created, used to sample animations.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    // Duration of the current frame, used to integrate motion.
+    pub fn delta(&self) -> Duration {
+        self.delta
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::real()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        animation::{Animation, KeyframeAnimationComponent},
+        mesh::Vertex,
+    };
+
+    fn poses(steps: &[Duration]) -> Vec<f32> {
+        let vertex = |x| Vertex {
+            position: [x, 0.0, 0.0],
+            normal: [0.0, 0.0, 1.0],
+            texcoord: [0.0; 2],
+        };
+        let mut animation = Animation::new();
+        animation.add_keyframe(vec![vertex(0.0)], Duration::from_millis(100));
+        animation.add_keyframe(vec![vertex(10.0)], Duration::from_millis(100));
+        let mut animation_component = KeyframeAnimationComponent::new();
+        animation_component
+            .animations
+            .insert("walk".to_string(), animation);
+        animation_component.set_current_animation("walk").unwrap();
+
+        let mut clock = Clock::manual();
+        steps
+            .iter()
+            .map(|step| {
+                clock.advance(*step);
+                // A manual clock does not move on its own.
+                assert_eq!(clock.tick(), Duration::ZERO);
+                animation_component.update_frame_events(&clock.elapsed());
+                animation_component.animate(&clock.elapsed()).unwrap()[0].position[0]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn manual_clock_gives_the_same_poses_every_run() {
+        let steps = [10, 15, 25, 50, 40, 60].map(Duration::from_millis);
+        let first = poses(&steps);
+        assert_eq!(first, poses(&steps));
+
+        // The clip starts on the first update, 10 ms in, and loops back to
+        // the first keyframe.
+        let expected = [0.0, 1.5, 4.0, 9.0, 7.0, 1.0];
+        for (x, expected) in first.iter().zip(expected) {
+            assert!((x - expected).abs() < 1e-4, "{:?}", first);
+        }
+    }
+}
@@ -0,0 +1,78 @@
+// Player skins reserve two 16 color ramps of the palette for the shirt and
+// the pants, which are swapped for the ramps of the chosen colors.
+pub struct ColorRemapComponent {
+    pub top_color: u8,
+    pub bottom_color: u8,
+}
+
+impl ColorRemapComponent {
+    const TOP_RANGE: usize = 16;
+    const BOTTOM_RANGE: usize = 96;
+
+    pub fn new(top_color: u8, bottom_color: u8) -> Self {
+        Self {
+            top_color,
+            bottom_color,
+        }
+    }
+
+    pub fn remap(&self, indices: &[u8]) -> Box<[u8]> {
+        let translation = self.translation_table();
+        indices.iter().map(|i| translation[*i as usize]).collect()
+    }
+
+    fn translation_table(&self) -> [u8; 256] {
+        let mut translation = [0u8; 256];
+        for (i, index) in translation.iter_mut().enumerate() {
+            *index = i as u8;
+        }
+
+        for (range, color) in [
+            (Self::TOP_RANGE, self.top_color),
+            (Self::BOTTOM_RANGE, self.bottom_color),
+        ] {
+            // Ramps past the first eight run from bright to dark, so they are
+            // reversed to keep the shading of the skin.
+            let ramp = ((color & 0x0f) as usize) << 4;
+            for i in 0..16 {
+                translation[range + i] = if ramp < 128 {
+                    (ramp + i) as u8
+                } else {
+                    (ramp + 15 - i) as u8
+                };
+            }
+        }
+
+        translation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shirt_range_takes_the_chosen_row() {
+        let indices: Vec<u8> = (0..=255).collect();
+        let remapped = ColorRemapComponent::new(4, 0).remap(&indices);
+
+        for (index, color) in remapped.iter().enumerate() {
+            match index {
+                16..=31 => assert_eq!(*color as usize, 64 + index - 16),
+                96..=111 => assert_eq!(*color as usize, index - 96),
+                _ => assert_eq!(*color as usize, index),
+            }
+        }
+    }
+
+    #[test]
+    fn bright_to_dark_rows_are_reversed() {
+        let indices: Vec<u8> = (0..=255).collect();
+        let remapped = ColorRemapComponent::new(0, 9).remap(&indices);
+
+        assert_eq!(remapped[96], 159);
+        assert_eq!(remapped[111], 144);
+        assert_eq!(remapped[16], 0);
+        assert_eq!(remapped[112], 112);
+    }
+}
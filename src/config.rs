@@ -0,0 +1,128 @@
+use std::{
+    error::Error,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub base_dir: PathBuf,
+    pub game: Option<String>,
+    pub map: String,
+    pub lod_bias: f32,
+}
+
+impl Config {
+    pub const DEFAULT_BASE_DIR: &'static str = "res";
+    pub const DEFAULT_MAP: &'static str = "e1m1";
+
+    // Accepts the Quake style options -basedir <path>, -game <mod>, +map <name>
+    // and +r_lodbias <bias>; anything else is ignored like the original engine
+    // does.
+    pub fn from_args<I>(args: I) -> Result<Self, Box<dyn Error>>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut config = Self::default();
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-basedir" => config.base_dir = PathBuf::from(Self::value_of(&arg, args.next())?),
+                "-game" => config.game = Some(Self::value_of(&arg, args.next())?),
+                "+map" => config.map = Self::value_of(&arg, args.next())?,
+                "+r_lodbias" => {
+                    let value = Self::value_of(&arg, args.next())?;
+                    config.lod_bias = value.parse().map_err(|_| {
+                        std::io::Error::new(
+                            ErrorKind::InvalidInput,
+                            format!("invalid value for {}: {}", arg, value),
+                        )
+                    })?;
+                }
+                _ => warn!("Ignoring unknown argument {}", arg),
+            }
+        }
+
+        Ok(config)
+    }
+
+    pub fn pak_path(&self) -> PathBuf {
+        let game_dir = match &self.game {
+            Some(game) => self.base_dir.join(game),
+            None => self.base_dir.clone(),
+        };
+        game_dir.join(Path::new("PAK0.PAK"))
+    }
+
+    fn value_of(arg: &str, value: Option<String>) -> Result<String, Box<dyn Error>> {
+        value.ok_or_else(|| {
+            Box::new(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("missing value for {}", arg),
+            )) as Box<dyn Error>
+        })
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            base_dir: PathBuf::from(Self::DEFAULT_BASE_DIR),
+            game: None,
+            map: Self::DEFAULT_MAP.to_string(),
+            lod_bias: 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_basedir_game_and_map() {
+        let args = [
+            "-basedir",
+            "/opt/quake",
+            "-game",
+            "hipnotic",
+            "+map",
+            "hip1m1",
+        ]
+        .map(String::from);
+        let config = Config::from_args(args).unwrap();
+        assert_eq!(
+            config,
+            Config {
+                base_dir: PathBuf::from("/opt/quake"),
+                game: Some("hipnotic".to_string()),
+                map: "hip1m1".to_string(),
+                ..Config::default()
+            }
+        );
+        assert_eq!(
+            config.pak_path(),
+            PathBuf::from("/opt/quake/hipnotic/PAK0.PAK")
+        );
+
+        // Unknown arguments are skipped, missing values are an error.
+        let args = ["-width", "+map", "e2m1"].map(String::from);
+        assert_eq!(Config::from_args(args).unwrap().map, "e2m1");
+        let args = ["+map"].map(String::from);
+        assert!(Config::from_args(args).is_err());
+
+        let config = Config::from_args(Vec::new()).unwrap();
+        assert_eq!(config, Config::default());
+        assert_eq!(config.pak_path(), PathBuf::from("res/PAK0.PAK"));
+    }
+
+    #[test]
+    fn parses_lod_bias() {
+        let args = ["+r_lodbias", "-0.5"].map(String::from);
+        assert_eq!(Config::from_args(args).unwrap().lod_bias, -0.5);
+
+        let args = ["+r_lodbias", "sharp"].map(String::from);
+        assert!(Config::from_args(args).is_err());
+    }
+}
@@ -2,24 +2,84 @@ use std::any::{Any, TypeId};
 use std::collections::HashMap;
 
 use crate::animation::KeyframeAnimationComponent;
+use crate::colormap::ColorRemapComponent;
+use crate::fence::FenceComponent;
+use crate::liquid::LiquidComponent;
 use crate::material::MaterialComponent;
-use crate::mesh::MeshComponent;
+use crate::mesh::{InstanceComponent, MeshComponent, SubMeshComponent};
+use crate::render_state::RenderStateComponent;
+use crate::shadow::ShadowComponent;
+use crate::skin::SkinComponent;
 use crate::transform::TransformComponent;
+use crate::viewmodel::ViewModelComponent;
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum ComponentType {
     Transform,
     Mesh,
     Material,
     KeyframeAnimation,
+    RenderState,
+    ColorRemap,
+    Shadow,
+    SubMesh,
+    ViewModel,
+    Liquid,
+    Skin,
+    Fence,
+    Instance,
 }
 
 impl ComponentType {
+    pub const ALL: [ComponentType; 13] = [
+        ComponentType::Transform,
+        ComponentType::Mesh,
+        ComponentType::Material,
+        ComponentType::KeyframeAnimation,
+        ComponentType::RenderState,
+        ComponentType::ColorRemap,
+        ComponentType::Shadow,
+        ComponentType::SubMesh,
+        ComponentType::ViewModel,
+        ComponentType::Liquid,
+        ComponentType::Skin,
+        ComponentType::Fence,
+        ComponentType::Instance,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ComponentType::ColorRemap => "ColorRemap",
+            ComponentType::Fence => "Fence",
+            ComponentType::Instance => "Instance",
+            ComponentType::KeyframeAnimation => "KeyframeAnimation",
+            ComponentType::Liquid => "Liquid",
+            ComponentType::Material => "Material",
+            ComponentType::Mesh => "Mesh",
+            ComponentType::Transform => "Transform",
+            ComponentType::RenderState => "RenderState",
+            ComponentType::Shadow => "Shadow",
+            ComponentType::Skin => "Skin",
+            ComponentType::SubMesh => "SubMesh",
+            ComponentType::ViewModel => "ViewModel",
+        }
+    }
+
     fn get_type_id(&self) -> TypeId {
         match self {
+            ComponentType::ColorRemap => TypeId::of::<ColorRemapComponent>(),
+            ComponentType::Fence => TypeId::of::<FenceComponent>(),
+            ComponentType::Instance => TypeId::of::<InstanceComponent>(),
             ComponentType::KeyframeAnimation => TypeId::of::<KeyframeAnimationComponent>(),
+            ComponentType::Liquid => TypeId::of::<LiquidComponent>(),
             ComponentType::Material => TypeId::of::<MaterialComponent>(),
             ComponentType::Mesh => TypeId::of::<MeshComponent>(),
             ComponentType::Transform => TypeId::of::<TransformComponent>(),
+            ComponentType::RenderState => TypeId::of::<RenderStateComponent>(),
+            ComponentType::Shadow => TypeId::of::<ShadowComponent>(),
+            ComponentType::Skin => TypeId::of::<SkinComponent>(),
+            ComponentType::SubMesh => TypeId::of::<SubMeshComponent>(),
+            ComponentType::ViewModel => TypeId::of::<ViewModelComponent>(),
         }
     }
 }
@@ -28,12 +88,36 @@ pub trait Component: Any {
     fn get_type() -> ComponentType;
 }
 
+impl Component for ColorRemapComponent {
+    fn get_type() -> ComponentType {
+        ComponentType::ColorRemap
+    }
+}
+
+impl Component for FenceComponent {
+    fn get_type() -> ComponentType {
+        ComponentType::Fence
+    }
+}
+
+impl Component for InstanceComponent {
+    fn get_type() -> ComponentType {
+        ComponentType::Instance
+    }
+}
+
 impl Component for KeyframeAnimationComponent {
     fn get_type() -> ComponentType {
         ComponentType::KeyframeAnimation
     }
 }
 
+impl Component for LiquidComponent {
+    fn get_type() -> ComponentType {
+        ComponentType::Liquid
+    }
+}
+
 impl Component for MaterialComponent {
     fn get_type() -> ComponentType {
         ComponentType::Material
@@ -46,12 +130,42 @@ impl Component for MeshComponent {
     }
 }
 
+impl Component for RenderStateComponent {
+    fn get_type() -> ComponentType {
+        ComponentType::RenderState
+    }
+}
+
+impl Component for ShadowComponent {
+    fn get_type() -> ComponentType {
+        ComponentType::Shadow
+    }
+}
+
+impl Component for SkinComponent {
+    fn get_type() -> ComponentType {
+        ComponentType::Skin
+    }
+}
+
+impl Component for SubMeshComponent {
+    fn get_type() -> ComponentType {
+        ComponentType::SubMesh
+    }
+}
+
 impl Component for TransformComponent {
     fn get_type() -> ComponentType {
         ComponentType::Transform
     }
 }
 
+impl Component for ViewModelComponent {
+    fn get_type() -> ComponentType {
+        ComponentType::ViewModel
+    }
+}
+
 pub struct Entity {
     components: HashMap<TypeId, Box<dyn Any>>,
 }
@@ -74,4 +188,23 @@ impl Entity {
             .map(|component| component.downcast_ref::<T>())
             .flatten()
     }
+
+    pub fn remove_component<T: Component>(&mut self) -> Option<T> {
+        self.components
+            .remove(&T::get_type().get_type_id())
+            .and_then(|component| component.downcast::<T>().ok())
+            .map(|component| *component)
+    }
+
+    pub fn component_types(&self) -> impl Iterator<Item = ComponentType> + '_ {
+        ComponentType::ALL
+            .into_iter()
+            .filter(|ty| self.components.contains_key(&ty.get_type_id()))
+    }
+
+    pub fn get_component_mut<T: Component>(&mut self) -> Option<&mut T> {
+        self.components
+            .get_mut(&T::get_type().get_type_id())
+            .and_then(|component| component.downcast_mut::<T>())
+    }
 }
@@ -0,0 +1,16 @@
+// Tags surfaces drawn with alpha testing, like the Quake fence textures named
+// with a leading '{' whose palette index 255 is see-through. Unlike blending,
+// the opaque texels still write depth.
+pub struct FenceComponent;
+
+impl FenceComponent {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Default for FenceComponent {
+    fn default() -> Self {
+        Self::new()
+    }
+}
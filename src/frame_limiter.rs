@@ -0,0 +1,63 @@
+use std::time::{Duration, Instant};
+
+// Paces redraws to a target rate. The event loop sleeps until next_frame and
+// only then asks the window for a redraw.
+pub struct FrameLimiter {
+    frame_time: Duration,
+    next_frame: Instant,
+}
+
+impl FrameLimiter {
+    pub fn new(target_fps: u32, now: Instant) -> Self {
+        Self {
+            frame_time: Duration::from_secs_f64(1.0 / target_fps as f64),
+            next_frame: now,
+        }
+    }
+
+    pub fn next_frame(&self) -> Instant {
+        self.next_frame
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        now >= self.next_frame
+    }
+
+    // Frames are scheduled on a fixed grid so the rate does not drift, but a
+    // frame that ran late by more than a whole period restarts the grid from
+    // now instead of bursting to catch up.
+    pub fn frame_rendered(&mut self, now: Instant) {
+        self.next_frame += self.frame_time;
+        if self.next_frame + self.frame_time < now {
+            self.next_frame = now + self.frame_time;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_frame_stays_on_the_grid_unless_late() {
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        let mut frame_limiter = FrameLimiter::new(50, start);
+        assert!(frame_limiter.is_due(start));
+
+        frame_limiter.frame_rendered(start + ms(3));
+        assert_eq!(frame_limiter.next_frame(), start + ms(20));
+        assert!(!frame_limiter.is_due(start + ms(19)));
+        assert!(frame_limiter.is_due(start + ms(20)));
+
+        // Late by less than a period, the grid is kept.
+        frame_limiter.frame_rendered(start + ms(35));
+        assert_eq!(frame_limiter.next_frame(), start + ms(40));
+
+        // Late by more than a period, the grid restarts from now.
+        frame_limiter.frame_rendered(start + ms(100));
+        assert_eq!(frame_limiter.next_frame(), start + ms(120));
+        frame_limiter.frame_rendered(start + ms(121));
+        assert_eq!(frame_limiter.next_frame(), start + ms(140));
+    }
+}
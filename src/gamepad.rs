@@ -0,0 +1,94 @@
+use std::{error::Error, time::Duration};
+
+use gilrs::{Axis, Gilrs};
+
+use crate::hid::{HIDEvent, GLOBAL_HID_EVENT_BUS};
+
+// Publishes the sticks of the first connected gamepad on the HID event bus:
+// the left one as move events with the stick deflection as their value, the
+// right one as motion, like the mouse.
+pub struct Gamepad {
+    // Stick deflection under which input is ignored, from 0.0 to 1.0.
+    pub dead_zone: f32,
+    // Scales the stick deflection, strafing then moving forward.
+    pub move_sensitivity: [f32; 2],
+    // Motion units per second at full deflection, horizontal then vertical.
+    pub look_sensitivity: [f32; 2],
+
+    gilrs: Gilrs,
+    last_move: [f32; 2],
+}
+
+impl Gamepad {
+    pub fn new() -> Result<Self, Box<dyn Error>> {
+        let gilrs = Gilrs::new().map_err(|err| err.to_string())?;
+
+        Ok(Self {
+            dead_zone: 0.15,
+            move_sensitivity: [1.0, 1.0],
+            look_sensitivity: [400.0, 400.0],
+
+            gilrs,
+            last_move: [0.0; 2],
+        })
+    }
+
+    // Call once per frame. Move events are only sent when the left stick
+    // changes, motion is sent while the right stick is deflected.
+    pub fn poll(&mut self, delta_time: Duration) {
+        while self.gilrs.next_event().is_some() {}
+
+        let (left, right) = match self.gilrs.gamepads().next() {
+            Some((_, gamepad)) => (
+                [
+                    gamepad.value(Axis::LeftStickX),
+                    gamepad.value(Axis::LeftStickY),
+                ],
+                [
+                    gamepad.value(Axis::RightStickX),
+                    gamepad.value(Axis::RightStickY),
+                ],
+            ),
+            None => ([0.0; 2], [0.0; 2]),
+        };
+        let left = self.apply_dead_zone(left);
+        let right = self.apply_dead_zone(right);
+
+        let movement = [0, 1].map(|i| (left[i] * self.move_sensitivity[i]).clamp(-1.0, 1.0));
+        if movement != self.last_move {
+            self.last_move = movement;
+            let [x, y] = movement;
+            Self::publish(HIDEvent::MoveRight(x.max(0.0)));
+            Self::publish(HIDEvent::MoveLeft((-x).max(0.0)));
+            Self::publish(HIDEvent::MoveForward(y.max(0.0)));
+            Self::publish(HIDEvent::MoveBackward((-y).max(0.0)));
+        }
+
+        if right != [0.0; 2] {
+            let dt = delta_time.as_secs_f32();
+            // Stick up is positive while mouse motion is positive downwards.
+            Self::publish(HIDEvent::Motion(
+                right[0] * self.look_sensitivity[0] * dt,
+                -right[1] * self.look_sensitivity[1] * dt,
+            ));
+        }
+    }
+
+    // Radial, so diagonals are not cut off, and rescaled so the output still
+    // starts at zero past the dead zone.
+    fn apply_dead_zone(&self, stick: [f32; 2]) -> [f32; 2] {
+        let magnitude = stick[0].hypot(stick[1]);
+        if magnitude <= self.dead_zone || self.dead_zone >= 1.0 {
+            return [0.0; 2];
+        }
+
+        let scale = ((magnitude - self.dead_zone) / (1.0 - self.dead_zone)).min(1.0) / magnitude;
+        stick.map(|value| value * scale)
+    }
+
+    fn publish(event: HIDEvent) {
+        if let Some(bus) = GLOBAL_HID_EVENT_BUS.read().unwrap().as_ref() {
+            bus.publish(event);
+        }
+    }
+}
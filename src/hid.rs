@@ -1,57 +1,543 @@
-use std::thread;
+use std::{
+    any::Any,
+    collections::{HashMap, VecDeque},
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Mutex, RwLock, Weak},
+    thread::{self, JoinHandle},
+};
 
-use crossbeam_channel::{Receiver, Sender};
+use crossbeam_channel::{Receiver, Sender, TrySendError};
 use lazy_static::lazy_static;
-use once_cell::sync::OnceCell;
+use winit::event::VirtualKeyCode;
 
 lazy_static! {
-    pub static ref GLOBAL_HID_EVENT_BUS: OnceCell<HIDEventBus> = OnceCell::new();
+    pub static ref GLOBAL_HID_EVENT_BUS: RwLock<Option<HIDEventBus>> = RwLock::new(None);
+    pub static ref GLOBAL_MOUSE_CONFIG: RwLock<MouseConfig> = RwLock::new(MouseConfig::new());
 }
 
 #[macro_export]
 macro_rules! send_hid_event {
     ($event: expr) => {
-        GLOBAL_HID_EVENT_BUS.get().unwrap().publish($event)
+        GLOBAL_HID_EVENT_BUS
+            .read()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .publish($event)
     };
 }
 
 pub fn init() {
-    GLOBAL_HID_EVENT_BUS.get_or_init(|| HIDEventBus::new());
+    let mut bus = GLOBAL_HID_EVENT_BUS.write().unwrap();
+    if bus.is_none() {
+        *bus = Some(HIDEventBus::new());
+    }
+}
+
+// Dropping the previous bus disconnects the channels of every subscriber
+// registered on it, which ends their threads.
+pub fn reset() {
+    *GLOBAL_HID_EVENT_BUS.write().unwrap() = Some(HIDEventBus::new());
 }
 
-#[derive(Clone, Copy, Debug)]
+// Move events carry how much their key is held, from 0.0 when released to
+// 1.0 when fully pressed, never negative: the direction is the variant. Motion
+// is a relative pointer delta.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum HIDEvent {
     MoveForward(f32),
     MoveBackward(f32),
     MoveLeft(f32),
     MoveRight(f32),
+    MoveUp(f32),
+    MoveDown(f32),
     Motion(f32, f32),
+    // Buttons, true while pressed.
+    Attack(bool),
+    Jump(bool),
+    Use(bool),
+}
+
+// Applied to raw mouse deltas before they are published as Motion. The
+// default passes them through unchanged.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MouseConfig {
+    pub sensitivity: f32,
+    pub invert_y: bool,
+}
+
+impl MouseConfig {
+    pub fn new() -> Self {
+        Self {
+            sensitivity: 1.0,
+            invert_y: false,
+        }
+    }
+
+    pub fn motion(&self, dx: f32, dy: f32) -> HIDEvent {
+        let dy = if self.invert_y { -dy } else { dy };
+        HIDEvent::Motion(dx * self.sensitivity, dy * self.sensitivity)
+    }
+}
+
+impl Default for MouseConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn mouse_config() -> MouseConfig {
+    *GLOBAL_MOUSE_CONFIG.read().unwrap()
+}
+
+pub fn set_mouse_config(mouse_config: MouseConfig) {
+    *GLOBAL_MOUSE_CONFIG.write().unwrap() = mouse_config;
+}
+
+pub fn set_mouse_sensitivity(sensitivity: f32) {
+    GLOBAL_MOUSE_CONFIG.write().unwrap().sensitivity = sensitivity;
+}
+
+// Publishes a raw mouse delta scaled by the mouse configuration.
+pub fn publish_mouse_motion(dx: f32, dy: f32) {
+    let event = mouse_config().motion(dx, dy);
+    if let Some(bus) = GLOBAL_HID_EVENT_BUS.read().unwrap().as_ref() {
+        bus.publish(event);
+    }
+}
+
+// What a bound key does, independently of which key it is.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Action {
+    MoveForward,
+    MoveBackward,
+    MoveLeft,
+    MoveRight,
+    MoveUp,
+    MoveDown,
+    Attack,
+    Jump,
+    Use,
+}
+
+impl Action {
+    // The event sent for the action, value being how much its key is held.
+    pub fn event(&self, value: f32) -> HIDEvent {
+        match self {
+            Action::MoveForward => HIDEvent::MoveForward(value),
+            Action::MoveBackward => HIDEvent::MoveBackward(value),
+            Action::MoveLeft => HIDEvent::MoveLeft(value),
+            Action::MoveRight => HIDEvent::MoveRight(value),
+            Action::MoveUp => HIDEvent::MoveUp(value),
+            Action::MoveDown => HIDEvent::MoveDown(value),
+            Action::Attack => HIDEvent::Attack(value > 0.0),
+            Action::Jump => HIDEvent::Jump(value > 0.0),
+            Action::Use => HIDEvent::Use(value > 0.0),
+        }
+    }
+}
+
+// Several keys may trigger the same action, but a key triggers one action at
+// most.
+pub struct KeyBindings {
+    bindings: HashMap<VirtualKeyCode, Action>,
+}
+
+impl KeyBindings {
+    // WASD to move, R and F to go up and down, Space to jump and E to use.
+    pub fn new() -> Self {
+        let mut key_bindings = Self::empty();
+        key_bindings.bind(VirtualKeyCode::W, Action::MoveForward);
+        key_bindings.bind(VirtualKeyCode::S, Action::MoveBackward);
+        key_bindings.bind(VirtualKeyCode::A, Action::MoveLeft);
+        key_bindings.bind(VirtualKeyCode::D, Action::MoveRight);
+        key_bindings.bind(VirtualKeyCode::R, Action::MoveUp);
+        key_bindings.bind(VirtualKeyCode::F, Action::MoveDown);
+        key_bindings.bind(VirtualKeyCode::Space, Action::Jump);
+        key_bindings.bind(VirtualKeyCode::E, Action::Use);
+        key_bindings
+    }
+
+    pub fn empty() -> Self {
+        Self {
+            bindings: HashMap::new(),
+        }
+    }
+
+    // Returns the action the key was bound to before, if any.
+    pub fn bind(&mut self, key: VirtualKeyCode, action: Action) -> Option<Action> {
+        self.bindings.insert(key, action)
+    }
+
+    pub fn unbind(&mut self, key: VirtualKeyCode) -> Option<Action> {
+        self.bindings.remove(&key)
+    }
+
+    pub fn action(&self, key: VirtualKeyCode) -> Option<Action> {
+        self.bindings.get(&key).copied()
+    }
+
+    // Keys bound to the action, in no particular order.
+    pub fn keys(&self, action: Action) -> Vec<VirtualKeyCode> {
+        self.bindings
+            .iter()
+            .filter(|(_, bound)| **bound == action)
+            .map(|(key, _)| *key)
+            .collect()
+    }
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// What publishing does when a subscriber of a bounded bus is lagging behind
+// with its channel full. Publishing never waits for room: events other than
+// Motion are never dropped, those that do not fit are kept on the bus and
+// sent in order on later publishes.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum OverflowPolicy {
+    // Keep every event.
+    #[default]
+    Queue,
+    // Drop the oldest Motion event still waiting to be handled, which is the
+    // new one when no other is waiting.
+    DropMotion,
+    // Add the deltas of consecutive Motion events that do not fit into one.
+    CoalesceMotion,
 }
 
 pub struct HIDEventBus {
-    sender: Sender<HIDEvent>,
-    receiver: Receiver<HIDEvent>,
+    subscribers: Mutex<Vec<Subscriber>>,
+    capacity: Option<usize>,
+    overflow_policy: OverflowPolicy,
 }
 
 impl HIDEventBus {
     pub fn new() -> Self {
-        let (sender, receiver) = crossbeam_channel::unbounded();
-        Self { sender, receiver }
+        Self {
+            subscribers: Mutex::new(Vec::new()),
+            capacity: None,
+            overflow_policy: OverflowPolicy::default(),
+        }
+    }
+
+    // Channels hold at most capacity events per subscriber, see OverflowPolicy
+    // for what happens to the others.
+    pub fn bounded(capacity: usize, overflow_policy: OverflowPolicy) -> Self {
+        Self {
+            subscribers: Mutex::new(Vec::new()),
+            capacity: Some(capacity),
+            overflow_policy,
+        }
     }
 
-    pub fn subscribe<F>(&self, mut callback: F)
+    // Every subscriber gets each event on a channel and thread of its own,
+    // until its handle is cancelled or dropped. A callback that panics is
+    // logged and dropped, since whatever it held is likely poisoned; the bus
+    // and the other subscribers keep running.
+    #[must_use = "the subscription ends when the handle is dropped"]
+    pub fn subscribe<F>(&self, mut callback: F) -> SubscriptionHandle
     where
         F: FnMut(HIDEvent) + Send + 'static,
     {
-        let receiver = self.receiver.clone();
-        thread::spawn(move || {
-            receiver.into_iter().for_each(|event| {
-                callback(event);
-            });
+        let (sender, receiver) = match self.capacity {
+            Some(capacity) => crossbeam_channel::bounded(capacity),
+            None => crossbeam_channel::unbounded(),
+        };
+        let (cancel_sender, cancel_receiver) = crossbeam_channel::bounded::<()>(0);
+        // The bus keeps a receiver to drop old events, so the channel cannot
+        // tell when the thread has ended; this token can.
+        let alive = Arc::new(());
+        self.subscribers.lock().unwrap().push(Subscriber {
+            sender,
+            receiver: receiver.clone(),
+            alive: Arc::downgrade(&alive),
+            overflow: VecDeque::new(),
         });
+
+        let thread = thread::spawn(move || {
+            let _alive = alive;
+            loop {
+                crossbeam_channel::select! {
+                    recv(receiver) -> event => {
+                        let Ok(event) = event else {
+                            break;
+                        };
+                        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| callback(event))) {
+                            error!(
+                                "HID subscriber panicked handling {:?}, unsubscribing it: {}",
+                                event,
+                                panic_message(&*payload)
+                            );
+                            break;
+                        }
+                    }
+                    // Nothing is ever sent, the channel only disconnects.
+                    recv(cancel_receiver) -> _ => break,
+                }
+            }
+        });
+
+        SubscriptionHandle {
+            cancel_sender: Some(cancel_sender),
+            thread: Some(thread),
+        }
     }
 
+    // Subscribers whose thread has ended are forgotten.
     pub fn publish(&self, event: HIDEvent) {
-        self.sender.send(event).unwrap()
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain_mut(|subscriber| subscriber.send(event, self.overflow_policy))
+    }
+}
+
+struct Subscriber {
+    sender: Sender<HIDEvent>,
+    receiver: Receiver<HIDEvent>,
+    alive: Weak<()>,
+    // Events that did not fit in the channel, oldest first.
+    overflow: VecDeque<HIDEvent>,
+}
+
+impl Subscriber {
+    // Returns false once the subscriber thread has ended. Never waits for
+    // room in the channel, see OverflowPolicy.
+    fn send(&mut self, event: HIDEvent, overflow_policy: OverflowPolicy) -> bool {
+        if self.alive.strong_count() == 0 {
+            return false;
+        }
+
+        self.flush_overflow();
+        if self.overflow.is_empty() {
+            match self.sender.try_send(event) {
+                Ok(()) => return true,
+                Err(TrySendError::Disconnected(_)) => return false,
+                Err(TrySendError::Full(_)) => {}
+            }
+        }
+
+        match (event, overflow_policy, self.overflow.back_mut()) {
+            (
+                HIDEvent::Motion(dx, dy),
+                OverflowPolicy::CoalesceMotion,
+                Some(HIDEvent::Motion(x, y)),
+            ) => {
+                *x += dx;
+                *y += dy;
+            }
+            (HIDEvent::Motion(..), OverflowPolicy::DropMotion, _) => {
+                self.overflow.push_back(event);
+                self.drop_oldest_motion();
+            }
+            _ => self.overflow.push_back(event),
+        }
+        true
+    }
+
+    // Events still in the channel are older than the overflow, so they are
+    // taken back to find the oldest Motion and sent again without it. Nothing
+    // else sends meanwhile and the thread only takes events, so they keep
+    // their order and fit.
+    fn drop_oldest_motion(&mut self) {
+        let mut events: VecDeque<HIDEvent> = self.receiver.try_iter().collect();
+        events.append(&mut self.overflow);
+        if let Some(i) = events
+            .iter()
+            .position(|event| matches!(event, HIDEvent::Motion(..)))
+        {
+            events.remove(i);
+        }
+        self.overflow = events;
+        self.flush_overflow();
+    }
+
+    fn flush_overflow(&mut self) {
+        while let Some(event) = self.overflow.pop_front() {
+            if let Err(err) = self.sender.try_send(event) {
+                self.overflow.push_front(err.into_inner());
+                break;
+            }
+        }
+    }
+}
+
+pub struct SubscriptionHandle {
+    cancel_sender: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SubscriptionHandle {
+    // Waits for the subscriber thread, so the callback is no longer running
+    // once this returns.
+    pub fn cancel(mut self) {
+        self.stop();
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.thread.as_ref().is_none_or(JoinHandle::is_finished)
+    }
+
+    fn stop(&mut self) {
+        self.cancel_sender.take();
+        if let Some(thread) = self.thread.take() {
+            // A callback dropping its own handle cannot wait for itself.
+            if thread.thread().id() != thread::current().id() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+impl Drop for SubscriptionHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::test_log;
+
+    // The token stands in for a running subscriber thread.
+    fn subscriber(capacity: usize) -> (Subscriber, Arc<()>) {
+        let (sender, receiver) = crossbeam_channel::bounded(capacity);
+        let alive = Arc::new(());
+        let subscriber = Subscriber {
+            sender,
+            receiver,
+            alive: Arc::downgrade(&alive),
+            overflow: VecDeque::new(),
+        };
+        (subscriber, alive)
+    }
+
+    fn received(subscriber: &Subscriber) -> Vec<HIDEvent> {
+        subscriber.receiver.try_iter().collect()
+    }
+
+    #[test]
+    fn drop_motion_drops_the_oldest() {
+        let (mut subscriber, _alive) = subscriber(2);
+        for event in [
+            HIDEvent::Motion(1.0, 0.0),
+            HIDEvent::Jump(true),
+            HIDEvent::Motion(2.0, 0.0),
+        ] {
+            assert!(subscriber.send(event, OverflowPolicy::DropMotion));
+        }
+        assert_eq!(
+            received(&subscriber),
+            vec![HIDEvent::Jump(true), HIDEvent::Motion(2.0, 0.0)]
+        );
+    }
+
+    #[test]
+    fn drop_motion_keeps_other_events() {
+        let (mut subscriber, _alive) = subscriber(1);
+        for event in [
+            HIDEvent::Jump(true),
+            HIDEvent::Jump(false),
+            HIDEvent::Motion(1.0, 0.0),
+        ] {
+            assert!(subscriber.send(event, OverflowPolicy::DropMotion));
+        }
+        assert_eq!(received(&subscriber), vec![HIDEvent::Jump(true)]);
+
+        assert!(subscriber.send(HIDEvent::Use(true), OverflowPolicy::DropMotion));
+        assert_eq!(received(&subscriber), vec![HIDEvent::Jump(false)]);
+        assert!(subscriber.send(HIDEvent::Use(false), OverflowPolicy::DropMotion));
+        assert_eq!(received(&subscriber), vec![HIDEvent::Use(true)]);
+    }
+
+    #[test]
+    fn coalesce_motion_adds_deltas() {
+        let (mut subscriber, _alive) = subscriber(1);
+        for event in [
+            HIDEvent::Motion(1.0, 1.0),
+            HIDEvent::Motion(2.0, 0.0),
+            HIDEvent::Motion(3.0, -1.0),
+            HIDEvent::Jump(true),
+        ] {
+            assert!(subscriber.send(event, OverflowPolicy::CoalesceMotion));
+        }
+        assert_eq!(received(&subscriber), vec![HIDEvent::Motion(1.0, 1.0)]);
+
+        assert!(subscriber.send(HIDEvent::Motion(1.0, 0.0), OverflowPolicy::CoalesceMotion));
+        assert_eq!(received(&subscriber), vec![HIDEvent::Motion(5.0, -1.0)]);
+        assert!(subscriber.send(HIDEvent::Jump(false), OverflowPolicy::CoalesceMotion));
+        assert_eq!(received(&subscriber), vec![HIDEvent::Jump(true)]);
+    }
+
+    #[test]
+    fn ended_subscriber_is_forgotten() {
+        let (mut subscriber, alive) = subscriber(1);
+        drop(alive);
+        assert!(!subscriber.send(HIDEvent::Jump(true), OverflowPolicy::Queue));
+    }
+
+    #[test]
+    fn cancel_ends_the_subscriber_thread() {
+        let bus = HIDEventBus::new();
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let handle = bus.subscribe(move |event| sender.send(event).unwrap());
+
+        bus.publish(HIDEvent::Jump(true));
+        assert_eq!(receiver.recv(), Ok(HIDEvent::Jump(true)));
+        assert!(!handle.is_finished());
+
+        // The callback, and the sender it holds, go away with the thread.
+        handle.cancel();
+        assert!(receiver.recv().is_err());
+        bus.publish(HIDEvent::Jump(false));
+        assert!(bus.subscribers.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn panicking_subscriber_is_logged_and_dropped() {
+        test_log::init();
+        let bus = HIDEventBus::new();
+        let panicking = bus.subscribe(|event| {
+            if event == HIDEvent::Use(true) {
+                panic!("use is not bound");
+            }
+        });
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let _healthy = bus.subscribe(move |event| sender.send(event).unwrap());
+
+        bus.publish(HIDEvent::Use(true));
+        assert_eq!(receiver.recv(), Ok(HIDEvent::Use(true)));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !panicking.is_finished() {
+            assert!(
+                Instant::now() < deadline,
+                "the subscriber thread is still running"
+            );
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(test_log::contains(
+            "HID subscriber panicked handling Use(true), unsubscribing it: use is not bound"
+        ));
+
+        bus.publish(HIDEvent::Jump(true));
+        assert_eq!(receiver.recv(), Ok(HIDEvent::Jump(true)));
+        assert_eq!(bus.subscribers.lock().unwrap().len(), 1);
     }
 }
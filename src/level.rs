@@ -1,15 +1,20 @@
-use std::{
-    error::Error,
-    io::{Cursor, ErrorKind},
-};
+use std::{error::Error, io::ErrorKind};
 
-use byteorder::{LittleEndian, ReadBytesExt};
+use cgmath::{InnerSpace, Vector3};
 use int_enum::IntEnum;
 
-use crate::{load_resource, resource::GLOBAL_RESOURCES};
+use crate::{load_resource, lump::LumpReader, resource::GLOBAL_RESOURCES};
 
 #[derive(Clone, Debug)]
-pub struct Bsp {}
+pub struct Bsp {
+    planes: Box<[Plane]>,
+    nodes: Box<[Node]>,
+    leaves: Box<[Leaf]>,
+    entities: Box<[BspEntity]>,
+    textures: Box<[Option<MipTexture>]>,
+    lightmaps: Box<[u8]>,
+    colored_lightmaps: Option<Box<[u8]>>,
+}
 
 impl Bsp {
     pub fn load<S>(name: S) -> Result<Self, Box<dyn Error>>
@@ -18,11 +23,174 @@ impl Bsp {
     {
         debug!("Loading BSP file {}", name.as_ref());
 
-        Bsp::deserialize(&mut Cursor::new(load_resource!(name.as_ref())?))
+        let mut bsp = Bsp::deserialize(&mut LumpReader::new(load_resource!(name.as_ref())?))?;
+
+        let lit_name = format!("{}.lit", name.as_ref().trim_end_matches(".bsp"));
+        match load_resource!(&lit_name) {
+            Ok(lit) => {
+                if let Err(err) = bsp.set_colored_lightmaps(&lit) {
+                    warn!("Ignoring LIT file {}: {}", lit_name, err);
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => (),
+            Err(err) => return Err(Box::new(err)),
+        }
+
+        Ok(bsp)
+    }
+
+    // A level without geometry, where every point is empty space.
+    pub fn empty() -> Self {
+        Self {
+            planes: Box::new([]),
+            nodes: Box::new([]),
+            leaves: Box::new([]),
+            entities: Box::new([]),
+            textures: Box::new([]),
+            lightmaps: Box::new([]),
+            colored_lightmaps: None,
+        }
+    }
+
+    // Colored lightmaps from a .lit file take precedence over the grayscale
+    // ones embedded in the BSP; either way three bytes per luxel are returned.
+    pub fn lightmaps_rgb(&self) -> Box<[u8]> {
+        match &self.colored_lightmaps {
+            Some(colored_lightmaps) => colored_lightmaps.clone(),
+            None => self.lightmaps.iter().flat_map(|l| [*l; 3]).collect(),
+        }
+    }
+
+    pub fn set_colored_lightmaps(&mut self, lit: &[u8]) -> Result<(), Box<dyn Error>> {
+        let mut reader = LumpReader::new(lit.to_vec());
+
+        let ident = reader.read_bytes(4)?;
+        if &*ident != b"QLIT" {
+            return Err(Box::new(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "invalid signature",
+            )));
+        }
+
+        let version = reader.read_i32()?;
+        if version != 1 {
+            return Err(Box::new(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid version: {}", version),
+            )));
+        }
+
+        let colored_lightmaps = reader.read_bytes(reader.remaining() as usize)?;
+        if colored_lightmaps.len() != self.lightmaps.len() * 3 {
+            return Err(Box::new(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "lightmap size mismatch: expected {} bytes, found {}",
+                    self.lightmaps.len() * 3,
+                    colored_lightmaps.len()
+                ),
+            )));
+        }
+        self.colored_lightmaps = Some(colored_lightmaps);
+
+        Ok(())
+    }
+
+    pub fn entities(&self) -> &[BspEntity] {
+        &self.entities
+    }
+
+    // Origin and yaw in degrees of the first info_player_start.
+    pub fn spawn_point(&self) -> Option<([f32; 3], f32)> {
+        let entity = self
+            .entities
+            .iter()
+            .find(|entity| entity.classname() == Some("info_player_start"))?;
+        let origin = entity.origin()?;
+        let angle = entity
+            .get("angle")
+            .and_then(|angle| angle.trim().parse().ok())
+            .unwrap_or(0.0);
+
+        Some((origin, angle))
+    }
+
+    // Indexed like the BSP texture directory; entries missing from the file
+    // are None.
+    pub fn textures(&self) -> &[Option<MipTexture>] {
+        &self.textures
+    }
+
+    pub fn leaf_contents(&self, point: [f32; 3]) -> Contents {
+        if self.nodes.is_empty() {
+            return Contents::Empty;
+        }
+
+        let mut child = 0i32;
+        while child >= 0 {
+            let node = &self.nodes[child as usize];
+            let plane = &self.planes[node.plane_id as usize];
+            let distance = plane.normal[0] * point[0]
+                + plane.normal[1] * point[1]
+                + plane.normal[2] * point[2]
+                - plane.distance;
+            child = if distance >= 0.0 {
+                node.children[0]
+            } else {
+                node.children[1]
+            };
+        }
+
+        match self.leaves.get((-(child + 1)) as usize) {
+            Some(leaf) => leaf.contents,
+            None => Contents::Solid,
+        }
+    }
+
+    // Returns the first point where the segment enters solid space.
+    pub fn trace(&self, start: [f32; 3], end: [f32; 3]) -> Option<[f32; 3]> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        self.trace_node(0, start.into(), end.into())
+            .map(|point| point.into())
+    }
+
+    fn trace_node(
+        &self,
+        child: i32,
+        start: Vector3<f32>,
+        end: Vector3<f32>,
+    ) -> Option<Vector3<f32>> {
+        if child < 0 {
+            return match self.leaves.get((-(child + 1)) as usize) {
+                Some(leaf) if leaf.contents != Contents::Solid => None,
+                _ => Some(start),
+            };
+        }
+
+        let node = &self.nodes[child as usize];
+        let plane = &self.planes[node.plane_id as usize];
+        let normal = Vector3::from(plane.normal);
+        let start_distance = normal.dot(start) - plane.distance;
+        let end_distance = normal.dot(end) - plane.distance;
+        if start_distance >= 0.0 && end_distance >= 0.0 {
+            return self.trace_node(node.children[0], start, end);
+        }
+        if start_distance < 0.0 && end_distance < 0.0 {
+            return self.trace_node(node.children[1], start, end);
+        }
+
+        // The segment crosses the plane, check the near side first.
+        let side = (start_distance < 0.0) as usize;
+        let middle = start + (end - start) * (start_distance / (start_distance - end_distance));
+        self.trace_node(node.children[side], start, middle)
+            .or_else(|| self.trace_node(node.children[1 - side], middle, end))
     }
 
-    fn deserialize(reader: &mut Cursor<Vec<u8>>) -> Result<Self, Box<dyn Error>> {
-        let version = reader.read_i32::<LittleEndian>().unwrap();
+    fn deserialize(reader: &mut LumpReader) -> Result<Self, Box<dyn Error>> {
+        let version = reader.read_i32()?;
         if version != 29 {
             return Err(Box::new(std::io::Error::new(
                 ErrorKind::InvalidData,
@@ -51,7 +219,289 @@ impl Bsp {
         let edge_list_section = sections[SectionId::EdgeList.int_value()];
         let models_section = sections[SectionId::Models.int_value()];
 
-        Ok(Self {})
+        let planes = planes_section.read_entries(reader, Plane::SIZE, Plane::deserialize)?;
+        let nodes = render_nodes_section.read_entries(reader, Node::SIZE, Node::deserialize)?;
+        let leaves = leaves_section.read_entries(reader, Leaf::SIZE, Leaf::deserialize)?;
+        let textures = MipTexture::deserialize_all(reader, &textures_section)?;
+        let entities = BspEntity::parse_all(&entities_section.read_bytes(reader)?)?;
+        let lightmaps = lightmaps_section.read_bytes(reader)?;
+
+        Ok(Self {
+            planes,
+            nodes,
+            leaves,
+            entities,
+            textures,
+            lightmaps,
+            colored_lightmaps: None,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Contents {
+    Empty,
+    Solid,
+    Water,
+    Slime,
+    Lava,
+    Sky,
+}
+
+impl Contents {
+    fn from_raw(contents: i32) -> Self {
+        match contents {
+            -2 => Contents::Solid,
+            // Water currents (-9 to -14) behave as plain water.
+            -3 | -14..=-9 => Contents::Water,
+            -4 => Contents::Slime,
+            -5 => Contents::Lava,
+            -6 => Contents::Sky,
+            _ => Contents::Empty,
+        }
+    }
+
+    pub fn color_shift(&self) -> [f32; 4] {
+        match self {
+            Contents::Water => [130.0 / 255.0, 80.0 / 255.0, 50.0 / 255.0, 128.0 / 255.0],
+            Contents::Slime => [0.0, 25.0 / 255.0, 5.0 / 255.0, 150.0 / 255.0],
+            Contents::Lava => [1.0, 80.0 / 255.0, 0.0, 150.0 / 255.0],
+            _ => [0.0; 4],
+        }
+    }
+}
+
+// Key/value pairs of one entry of the entity lump, in file order.
+#[derive(Clone, Debug, Default)]
+pub struct BspEntity {
+    properties: Vec<(String, String)>,
+}
+
+impl BspEntity {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.properties
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn classname(&self) -> Option<&str> {
+        self.get("classname")
+    }
+
+    pub fn origin(&self) -> Option<[f32; 3]> {
+        let mut components = self.get("origin")?.split_whitespace().map(str::parse);
+        match (components.next(), components.next(), components.next()) {
+            (Some(Ok(x)), Some(Ok(y)), Some(Ok(z))) => Some([x, y, z]),
+            _ => None,
+        }
+    }
+
+    // The lump is plain text: a list of { "key" "value" ... } blocks,
+    // terminated by a NUL byte.
+    pub fn parse_all(lump: &[u8]) -> Result<Box<[Self]>, Box<dyn Error>> {
+        let len = lump.iter().position(|c| *c == 0).unwrap_or(lump.len());
+        let text = String::from_utf8_lossy(&lump[..len]);
+        let mut tokens = Self::tokenize(&text).into_iter();
+
+        let mut entities = Vec::new();
+        while let Some(token) = tokens.next() {
+            if token != "{" {
+                return Err(Self::parse_error(format!(
+                    "expected '{{', found {:?}",
+                    token
+                )));
+            }
+
+            let mut entity = BspEntity::default();
+            loop {
+                match tokens.next() {
+                    Some(token) if token == "}" => break,
+                    Some(key) => {
+                        let value = tokens
+                            .next()
+                            .ok_or_else(|| Self::parse_error(format!("no value for {:?}", key)))?;
+                        entity.properties.push((key, value));
+                    }
+                    None => return Err(Self::parse_error("unterminated entity".to_string())),
+                }
+            }
+            entities.push(entity);
+        }
+
+        Ok(entities.into_boxed_slice())
+    }
+
+    fn tokenize(text: &str) -> Vec<String> {
+        let mut tokens = Vec::new();
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' | '}' => tokens.push(c.to_string()),
+                '"' => tokens.push(chars.by_ref().take_while(|c| *c != '"').collect()),
+                c if c.is_whitespace() => (),
+                c => {
+                    let mut token = c.to_string();
+                    while let Some(c) = chars.next_if(|c| !c.is_whitespace() && *c != '"') {
+                        token.push(c);
+                    }
+                    tokens.push(token);
+                }
+            }
+        }
+        tokens
+    }
+
+    fn parse_error(message: String) -> Box<dyn Error> {
+        Box::new(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid entity lump: {}", message),
+        ))
+    }
+}
+
+// A wall texture with the four mip levels authored in the BSP, each one half
+// the size of the previous, stored as palette indices.
+#[derive(Clone, Debug)]
+pub struct MipTexture {
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+    pub mips: [Box<[u8]>; MipTexture::MIP_LEVELS],
+}
+
+impl MipTexture {
+    pub const MIP_LEVELS: usize = 4;
+
+    pub fn is_liquid(&self) -> bool {
+        self.name.starts_with('*')
+    }
+
+    // Fence textures are alpha tested, with palette index 255 see-through.
+    pub fn is_fence(&self) -> bool {
+        self.name.starts_with('{')
+    }
+
+    pub fn mip_size(&self, level: usize) -> (u32, u32) {
+        ((self.width >> level).max(1), (self.height >> level).max(1))
+    }
+
+    fn deserialize_all(
+        reader: &mut LumpReader,
+        section: &DEntry,
+    ) -> Result<Box<[Option<Self>]>, Box<dyn Error>> {
+        if section.size == 0 {
+            return Ok(Box::new([]));
+        }
+
+        reader.set_position(section.offset as u64);
+        let num_textures = reader.read_i32()?;
+        let offsets =
+            reader.read_entries(
+                num_textures.max(0) as usize,
+                |reader| Ok(reader.read_i32()?),
+            )?;
+
+        let mut textures = Vec::with_capacity(offsets.len());
+        for offset in offsets.iter().copied() {
+            if offset < 0 {
+                textures.push(None);
+                continue;
+            }
+            reader.set_position(section.offset as u64 + offset as u64);
+            textures.push(Some(Self::deserialize(reader)?));
+        }
+
+        Ok(textures.into_boxed_slice())
+    }
+
+    fn deserialize(reader: &mut LumpReader) -> Result<Self, Box<dyn Error>> {
+        let start = reader.position();
+
+        let name = reader.read_string_fixed(16)?;
+
+        let width = reader.read_u32()?;
+        let height = reader.read_u32()?;
+        if width == 0 || height == 0 || width % 8 != 0 || height % 8 != 0 {
+            return Err(Box::new(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid texture size for {}: {}x{}", name, width, height),
+            )));
+        }
+
+        let mut offsets = [0u32; Self::MIP_LEVELS];
+        for offset in offsets.iter_mut() {
+            *offset = reader.read_u32()?;
+        }
+
+        let mut mips: [Box<[u8]>; Self::MIP_LEVELS] = Default::default();
+        for (level, (mip, offset)) in mips.iter_mut().zip(offsets).enumerate() {
+            reader.set_position(start + offset as u64);
+            *mip = reader.read_bytes(((width >> level) * (height >> level)) as usize)?;
+        }
+
+        Ok(Self {
+            name,
+            width,
+            height,
+            mips,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Plane {
+    normal: [f32; 3],
+    distance: f32,
+}
+
+impl Plane {
+    const SIZE: usize = 20;
+
+    fn deserialize(reader: &mut LumpReader) -> Result<Self, Box<dyn Error>> {
+        let normal = reader.read_vec3()?;
+        let distance = reader.read_f32()?;
+        reader.read_i32()?;
+
+        Ok(Self { normal, distance })
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Node {
+    plane_id: u32,
+    children: [i32; 2],
+}
+
+impl Node {
+    const SIZE: usize = 24;
+
+    fn deserialize(reader: &mut LumpReader) -> Result<Self, Box<dyn Error>> {
+        let plane_id = reader.read_u32()?;
+        let front = reader.read_i16()? as i32;
+        let back = reader.read_i16()? as i32;
+        reader.skip(16);
+
+        Ok(Self {
+            plane_id,
+            children: [front, back],
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Leaf {
+    contents: Contents,
+}
+
+impl Leaf {
+    const SIZE: usize = 28;
+
+    fn deserialize(reader: &mut LumpReader) -> Result<Self, Box<dyn Error>> {
+        let contents = Contents::from_raw(reader.read_i32()?);
+        reader.skip(24);
+
+        Ok(Self { contents })
     }
 }
 
@@ -62,12 +512,30 @@ struct DEntry {
 }
 
 impl DEntry {
-    fn deserialize(reader: &mut Cursor<Vec<u8>>) -> Result<Self, Box<dyn Error>> {
-        let offset = reader.read_i32::<LittleEndian>().unwrap();
-        let size = reader.read_i32::<LittleEndian>().unwrap();
+    fn deserialize(reader: &mut LumpReader) -> Result<Self, Box<dyn Error>> {
+        let offset = reader.read_i32()?;
+        let size = reader.read_i32()?;
 
         Ok(Self { offset, size })
     }
+
+    fn read_bytes(&self, reader: &mut LumpReader) -> Result<Box<[u8]>, Box<dyn Error>> {
+        reader.set_position(self.offset as u64);
+        Ok(reader.read_bytes(self.size as usize)?)
+    }
+
+    fn read_entries<T, F>(
+        &self,
+        reader: &mut LumpReader,
+        entry_size: usize,
+        deserialize: F,
+    ) -> Result<Box<[T]>, Box<dyn Error>>
+    where
+        F: Fn(&mut LumpReader) -> Result<T, Box<dyn Error>>,
+    {
+        reader.set_position(self.offset as u64);
+        reader.read_entries(self.size as usize / entry_size, deserialize)
+    }
 }
 
 #[repr(usize)]
@@ -89,3 +557,150 @@ pub enum SectionId {
     EdgeList = 13,
     Models = 14,
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // Air above z = 0, below it water where x >= 0 and lava elsewhere.
+    pub(crate) fn water_bsp() -> Bsp {
+        let plane = |normal, distance| Plane { normal, distance };
+        Bsp {
+            planes: Box::new([plane([0.0, 0.0, 1.0], 0.0), plane([1.0, 0.0, 0.0], 0.0)]),
+            nodes: Box::new([
+                Node {
+                    plane_id: 0,
+                    children: [-1, 1],
+                },
+                Node {
+                    plane_id: 1,
+                    children: [-2, -3],
+                },
+            ]),
+            leaves: [Contents::Empty, Contents::Water, Contents::Lava]
+                .map(|contents| Leaf { contents })
+                .into(),
+            ..Bsp::empty()
+        }
+    }
+
+    // A texture filled with index 0xff, its four mip levels right after the
+    // header.
+    fn mip_texture(name: &str, width: u32, height: u32) -> MipTexture {
+        let mut bytes = [0u8; 16].to_vec();
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
+        bytes.extend_from_slice(&width.to_le_bytes());
+        bytes.extend_from_slice(&height.to_le_bytes());
+        let mut offset = 40u32;
+        for level in 0..MipTexture::MIP_LEVELS {
+            bytes.extend_from_slice(&offset.to_le_bytes());
+            offset += (width >> level) * (height >> level);
+        }
+        bytes.resize(offset as usize, 0xff);
+
+        MipTexture::deserialize(&mut LumpReader::new(bytes)).unwrap()
+    }
+
+    #[test]
+    fn grate_textures_are_alpha_tested() {
+        let grate = mip_texture("{grate", 8, 8);
+        assert_eq!(grate.name, "{grate");
+        assert!(grate.is_fence());
+        assert!(!grate.is_liquid());
+
+        assert!(!mip_texture("*water0", 8, 8).is_fence());
+        assert!(!mip_texture("wall14_5", 8, 8).is_fence());
+    }
+
+    #[test]
+    fn spawn_point_comes_from_info_player_start() {
+        let lump = br#"
+{
+"classname" "worldspawn"
+"wad" "gfx/base.wad"
+}
+{
+"classname" "light"
+"origin" "0 0 0"
+}
+{
+"classname" "info_player_start"
+"origin" "480 -352 88"
+"angle" "90"
+}
+"#;
+        let bsp = Bsp {
+            entities: BspEntity::parse_all(lump).unwrap(),
+            ..Bsp::empty()
+        };
+        assert_eq!(bsp.entities().len(), 3);
+        assert_eq!(bsp.spawn_point(), Some(([480.0, -352.0, 88.0], 90.0)));
+
+        // The angle defaults to facing along x.
+        let lump = br#"{ "classname" "info_player_start" "origin" "16 32 -8" }"#;
+        let bsp = Bsp {
+            entities: BspEntity::parse_all(lump).unwrap(),
+            ..Bsp::empty()
+        };
+        assert_eq!(bsp.spawn_point(), Some(([16.0, 32.0, -8.0], 0.0)));
+
+        assert_eq!(Bsp::empty().spawn_point(), None);
+    }
+
+    #[test]
+    fn leaf_contents_follow_the_nodes() {
+        let bsp = water_bsp();
+        assert_eq!(bsp.leaf_contents([0.0, 0.0, 10.0]), Contents::Empty);
+        assert_eq!(bsp.leaf_contents([32.0, 8.0, -10.0]), Contents::Water);
+        assert_eq!(bsp.leaf_contents([-32.0, 8.0, -10.0]), Contents::Lava);
+
+        assert_eq!(Bsp::empty().leaf_contents([0.0; 3]), Contents::Empty);
+    }
+
+    fn lit_bytes(ident: &[u8; 4], version: i32, luxels: &[u8]) -> Vec<u8> {
+        let mut lit = ident.to_vec();
+        lit.extend_from_slice(&version.to_le_bytes());
+        lit.extend_from_slice(luxels);
+        lit
+    }
+
+    #[test]
+    fn lit_files_must_match_the_lightmaps() {
+        let mut bsp = Bsp {
+            lightmaps: Box::new([10, 20]),
+            ..Bsp::empty()
+        };
+        let grayscale: Box<[u8]> = Box::new([10, 10, 10, 20, 20, 20]);
+        let colored = [1, 2, 3, 4, 5, 6];
+
+        for lit in [
+            lit_bytes(b"QLIX", 1, &colored),
+            lit_bytes(b"QLIT", 2, &colored),
+            lit_bytes(b"QLIT", 1, &colored[..5]),
+            lit_bytes(b"QLIT", 1, &[0; 9]),
+            b"QLI".to_vec(),
+        ] {
+            assert!(bsp.set_colored_lightmaps(&lit).is_err(), "{:?}", lit);
+            assert_eq!(bsp.lightmaps_rgb(), grayscale);
+        }
+
+        bsp.set_colored_lightmaps(&lit_bytes(b"QLIT", 1, &colored))
+            .unwrap();
+        assert_eq!(&*bsp.lightmaps_rgb(), &colored);
+    }
+
+    #[test]
+    fn mip_levels_halve_the_size() {
+        let texture = mip_texture("wbrick1_5", 64, 64);
+        let sizes: Vec<(u32, u32)> = (0..MipTexture::MIP_LEVELS)
+            .map(|level| texture.mip_size(level))
+            .collect();
+        assert_eq!(sizes, vec![(64, 64), (32, 32), (16, 16), (8, 8)]);
+        for (mip, (width, height)) in texture.mips.iter().zip(sizes) {
+            assert_eq!(mip.len(), (width * height) as usize);
+        }
+
+        let texture = mip_texture("sky4", 256, 128);
+        assert_eq!(texture.mip_size(3), (32, 16));
+    }
+}
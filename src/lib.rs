@@ -2,15 +2,36 @@
 extern crate log;
 
 pub mod animation;
+pub mod bvh;
 pub mod camera;
+pub mod clock;
+pub mod colormap;
+pub mod config;
 pub mod entity;
+pub mod fence;
+pub mod frame_limiter;
+#[cfg(feature = "gamepad")]
+pub mod gamepad;
 pub mod hid;
 pub mod level;
+pub mod liquid;
+pub mod lump;
 pub mod material;
 pub mod mesh;
+pub mod particle;
 pub mod alias;
 pub mod pipeline;
+pub mod profiler;
+pub mod random;
+pub mod render_state;
 pub mod renderer;
 pub mod resource;
 pub mod scene;
+pub mod shadow;
+pub mod skin;
+pub mod sprite;
+#[cfg(test)]
+mod test_log;
 pub mod transform;
+pub mod units;
+pub mod viewmodel;
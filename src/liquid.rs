@@ -0,0 +1,15 @@
+// Tags surfaces whose texture coordinates are warped over time like Quake
+// water, slime and lava, whose textures are named with a leading '*'.
+pub struct LiquidComponent;
+
+impl LiquidComponent {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Default for LiquidComponent {
+    fn default() -> Self {
+        Self::new()
+    }
+}
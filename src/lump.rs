@@ -0,0 +1,143 @@
+use std::{
+    error::Error,
+    io::{self, Cursor, ErrorKind, Read},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+// Little-endian reader over an in-memory lump shared by the BSP, MDL and PAK
+// loaders. Every read fails with an error instead of panicking when the data
+// runs out.
+pub struct LumpReader {
+    cursor: Cursor<Vec<u8>>,
+}
+
+impl LumpReader {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            cursor: Cursor::new(data),
+        }
+    }
+
+    pub fn position(&self) -> u64 {
+        self.cursor.position()
+    }
+
+    pub fn set_position(&mut self, position: u64) {
+        self.cursor.set_position(position);
+    }
+
+    pub fn remaining(&self) -> u64 {
+        (self.cursor.get_ref().len() as u64).saturating_sub(self.cursor.position())
+    }
+
+    pub fn skip(&mut self, count: u64) {
+        self.cursor.set_position(self.cursor.position() + count);
+    }
+
+    pub fn read_u8(&mut self) -> io::Result<u8> {
+        self.cursor.read_u8()
+    }
+
+    pub fn read_i16(&mut self) -> io::Result<i16> {
+        self.cursor.read_i16::<LittleEndian>()
+    }
+
+    pub fn read_i32(&mut self) -> io::Result<i32> {
+        self.cursor.read_i32::<LittleEndian>()
+    }
+
+    pub fn read_u32(&mut self) -> io::Result<u32> {
+        self.cursor.read_u32::<LittleEndian>()
+    }
+
+    pub fn read_f32(&mut self) -> io::Result<f32> {
+        self.cursor.read_f32::<LittleEndian>()
+    }
+
+    pub fn read_vec3(&mut self) -> io::Result<[f32; 3]> {
+        Ok([self.read_f32()?, self.read_f32()?, self.read_f32()?])
+    }
+
+    // Reads a NUL padded string stored in a field of `len` bytes; anything
+    // after the first NUL is ignored.
+    pub fn read_string_fixed(&mut self, len: usize) -> io::Result<String> {
+        let bytes = self.read_bytes(len)?;
+        let end = bytes.iter().position(|c| *c == 0).unwrap_or(bytes.len());
+        Ok(String::from_utf8_lossy(&bytes[..end]).into_owned())
+    }
+
+    // Checked against the remaining data first, so a corrupt length cannot
+    // trigger a huge allocation.
+    pub fn read_bytes(&mut self, len: usize) -> io::Result<Box<[u8]>> {
+        if len as u64 > self.remaining() {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                format!(
+                    "{} bytes requested at offset {}, only {} left",
+                    len,
+                    self.position(),
+                    self.remaining()
+                ),
+            ));
+        }
+
+        let mut bytes = vec![0u8; len];
+        self.cursor.read_exact(&mut bytes)?;
+        Ok(bytes.into_boxed_slice())
+    }
+
+    pub fn read_entries<T, F>(
+        &mut self,
+        count: usize,
+        mut deserialize: F,
+    ) -> Result<Box<[T]>, Box<dyn Error>>
+    where
+        F: FnMut(&mut Self) -> Result<T, Box<dyn Error>>,
+    {
+        let mut entries = Vec::with_capacity(count.min(self.remaining() as usize));
+        for _ in 0..count {
+            entries.push(deserialize(self)?);
+        }
+        Ok(entries.into_boxed_slice())
+    }
+}
+
+impl Read for LumpReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.cursor.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_vec3_is_little_endian() {
+        let data = [1.0f32, -2.5, 1024.0]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        let mut reader = LumpReader::new(data);
+        assert_eq!(reader.read_vec3().unwrap(), [1.0, -2.5, 1024.0]);
+        assert_eq!(reader.remaining(), 0);
+        assert!(reader.read_vec3().is_err());
+    }
+
+    #[test]
+    fn read_string_fixed_stops_at_the_first_nul() {
+        let mut reader = LumpReader::new(b"stand\0\0x1\0\0\0\0\0\0\0walk".to_vec());
+        assert_eq!(reader.read_string_fixed(16).unwrap(), "stand");
+        assert_eq!(reader.position(), 16);
+        assert_eq!(reader.read_string_fixed(4).unwrap(), "walk");
+        assert!(reader.read_string_fixed(1).is_err());
+    }
+
+    #[test]
+    fn read_string_fixed_without_nul_takes_the_whole_field() {
+        let mut reader = LumpReader::new(b"e1m1e1m2".to_vec());
+        assert_eq!(reader.read_string_fixed(4).unwrap(), "e1m1");
+        assert_eq!(reader.read_string_fixed(4).unwrap(), "e1m2");
+    }
+}
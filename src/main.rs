@@ -1,27 +1,34 @@
 use std::{
+    env,
     sync::{Arc, RwLock},
-    thread,
-    time::{Duration, Instant},
+    time::Instant,
 };
 
+#[cfg(feature = "gamepad")]
+use quake_rs::gamepad::Gamepad;
 use quake_rs::{
     camera::Camera,
-    hid::{self, HIDEvent, GLOBAL_HID_EVENT_BUS},
+    clock::Clock,
+    config::Config,
+    frame_limiter::FrameLimiter,
+    hid::{self, Action, KeyBindings, GLOBAL_HID_EVENT_BUS},
     renderer, resource,
     scene::Scene,
     send_hid_event,
 };
 use winit::{
     dpi::PhysicalSize,
-    event::{DeviceEvent, ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent},
+    event::{DeviceEvent, ElementState, Event, KeyboardInput, MouseButton, WindowEvent},
     event_loop::{ControlFlow, EventLoop},
     window::WindowBuilder,
 };
 
 fn main() {
     env_logger::init();
+    let config = Config::from_args(env::args().skip(1)).unwrap();
+
     hid::init();
-    resource::init("res/PAK0.PAK");
+    resource::init(config.pak_path()).unwrap();
 
     let width = 1280;
     let height = 720;
@@ -29,95 +36,139 @@ fn main() {
     let window = WindowBuilder::new()
         .with_title("Quake::rs")
         .with_inner_size(PhysicalSize::new(width, height))
-        .with_resizable(false)
         .build(&event_loop)
         .unwrap();
 
-    let renderer = renderer::Renderer::new(&window).unwrap();
+    let mut renderer = renderer::Renderer::new(&window).unwrap();
+    renderer.lod_bias = config.lod_bias;
 
     let camera = Arc::new(RwLock::new(Camera::new(width, height)));
-    {
+    // Held for as long as the event loop runs.
+    let _camera_subscription = {
         let camera_ref = camera.clone();
         GLOBAL_HID_EVENT_BUS
-            .get()
+            .read()
             .unwrap()
-            .subscribe(move |event| camera_ref.write().unwrap().update(event));
-    }
+            .as_ref()
+            .unwrap()
+            .subscribe(move |event| camera_ref.write().unwrap().update(event))
+    };
 
-    let mut scene = Scene::load(&renderer, "").unwrap();
+    let mut scene = Scene::load(&renderer, &config.map).unwrap();
+    if let Some((origin, yaw)) = scene.spawn_point() {
+        // The player eyes are 22 units above the spawn origin.
+        let eye = [origin[0], origin[1], origin[2] + 22.0];
+        camera.write().unwrap().set_quake_view(eye, yaw);
+    }
 
     let target_fps = 60;
-    let target_frame_time = Duration::from_secs_f64(1.0 / target_fps as f64);
-    let mut last_frame_time = Instant::now();
+    let mut frame_limiter = FrameLimiter::new(target_fps, Instant::now());
+    let mut clock = Clock::real();
+    let key_bindings = KeyBindings::new();
+    #[cfg(feature = "gamepad")]
+    let mut gamepad = Gamepad::new()
+        .map_err(|err| eprintln!("Gamepad input is unavailable: {}", err))
+        .ok();
 
     event_loop.run(move |event, _, control_flow| {
-        *control_flow = ControlFlow::Poll;
-
-        // Calculate delta time
-        let delta_time = last_frame_time.elapsed();
-        last_frame_time = Instant::now();
-
-        // Handle input events
         match event {
+            // Handle input events
             Event::WindowEvent { event, window_id } if window_id == window.id() => match event {
                 WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
-                WindowEvent::KeyboardInput { input, .. } => handle_keyboard_input(input),
+                WindowEvent::Resized(size) => {
+                    renderer.resize(size.width, size.height);
+                    camera.write().unwrap().resize(size.width, size.height);
+                }
+                WindowEvent::KeyboardInput { input, .. } => {
+                    handle_keyboard_input(&key_bindings, input)
+                }
+                WindowEvent::MouseInput { state, button, .. } => {
+                    handle_mouse_button_input(state, button)
+                }
                 _ => (),
             },
             Event::DeviceEvent { event, .. } => handle_mouse_input(event),
-            _ => (),
-        }
 
-        // Update game logic
-        scene.update(&renderer.queue, &delta_time);
-
-        // Render game state
-        renderer
-            .render(
-                &camera.read().unwrap(),
-                scene.visible_entities(&camera.read().unwrap()),
-            )
-            .unwrap();
-
-        // Control frame rate
-        let elapsed_frame_time = last_frame_time.elapsed();
-        if elapsed_frame_time < target_frame_time {
-            let sleep_duration = target_frame_time - elapsed_frame_time;
-            thread::sleep(sleep_duration);
+            // Control frame rate, sleeping until the next frame is due
+            Event::MainEventsCleared => {
+                if frame_limiter.is_due(Instant::now()) {
+                    window.request_redraw();
+                }
+                if *control_flow != ControlFlow::Exit {
+                    *control_flow = ControlFlow::WaitUntil(frame_limiter.next_frame());
+                }
+            }
+
+            Event::RedrawRequested(window_id) if window_id == window.id() => {
+                // Advance game time by the time between rendered frames
+                let now = Instant::now();
+                clock.tick();
+
+                // Update game logic
+                #[cfg(feature = "gamepad")]
+                if let Some(gamepad) = gamepad.as_mut() {
+                    gamepad.poll(clock.delta());
+                }
+                camera.write().unwrap().apply_movement(clock.delta());
+                scene.update(&renderer.queue, &clock);
+
+                // Render game state
+                renderer
+                    .set_screen_tint(scene.camera_contents(&camera.read().unwrap()).color_shift());
+                let result = renderer.render(
+                    &camera.read().unwrap(),
+                    &scene.visible_entities(&camera.read().unwrap()),
+                    scene.particles(),
+                );
+
+                // Rebuild the renderer and reload the scene, whose GPU
+                // resources were created on the lost device
+                if renderer.is_device_lost() {
+                    renderer.recreate(&window).unwrap();
+                    scene = Scene::load(&renderer, &config.map).unwrap();
+                } else {
+                    result.unwrap();
+                }
+
+                frame_limiter.frame_rendered(now);
+            }
+            _ => (),
         }
     });
 }
 
-fn handle_keyboard_input(input: KeyboardInput) {
-    match input {
-        KeyboardInput {
-            state: ElementState::Pressed,
-            virtual_keycode: Some(VirtualKeyCode::W),
-            ..
-        } => send_hid_event!(HIDEvent::MoveForward(1.0)),
-        KeyboardInput {
-            state: ElementState::Pressed,
-            virtual_keycode: Some(VirtualKeyCode::S),
-            ..
-        } => send_hid_event!(HIDEvent::MoveBackward(1.0)),
-        KeyboardInput {
-            state: ElementState::Pressed,
-            virtual_keycode: Some(VirtualKeyCode::A),
-            ..
-        } => send_hid_event!(HIDEvent::MoveLeft(1.0)),
-        KeyboardInput {
-            state: ElementState::Pressed,
-            virtual_keycode: Some(VirtualKeyCode::D),
-            ..
-        } => send_hid_event!(HIDEvent::MoveRight(1.0)),
-        _ => (),
+// Bound keys send 1.0 when pressed and 0.0 when released.
+fn handle_keyboard_input(key_bindings: &KeyBindings, input: KeyboardInput) {
+    let value = match input.state {
+        ElementState::Pressed => 1.0,
+        ElementState::Released => 0.0,
+    };
+    if let Some(action) = input
+        .virtual_keycode
+        .and_then(|key| key_bindings.action(key))
+    {
+        send_hid_event!(action.event(value));
     }
 }
 
+// Left button attacks and right button jumps.
+fn handle_mouse_button_input(state: ElementState, button: MouseButton) {
+    let action = match button {
+        MouseButton::Left => Action::Attack,
+        MouseButton::Right => Action::Jump,
+        _ => return,
+    };
+    let value = match state {
+        ElementState::Pressed => 1.0,
+        ElementState::Released => 0.0,
+    };
+    send_hid_event!(action.event(value));
+}
+
 fn handle_mouse_input(event: DeviceEvent) {
     match event {
         DeviceEvent::MouseMotion { delta } => {
-            send_hid_event!(HIDEvent::Motion(delta.0 as f32, delta.1 as f32))
+            hid::publish_mouse_motion(delta.0 as f32, delta.1 as f32)
         }
         _ => (),
     }
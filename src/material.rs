@@ -1,22 +1,122 @@
 use wgpu::BindGroupLayout;
 
-use crate::renderer::Renderer;
+use crate::{level::MipTexture, pipeline, renderer::Renderer, resource};
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum MaterialMode {
+    // Palette colors resolved on the CPU into an RGBA texture.
+    #[default]
+    Rgba,
+    // Raw palette indices looked up in a 256x1 palette texture by the shader,
+    // so palette effects can be applied at runtime.
+    Indexed,
+}
 
 pub struct MaterialComponent {
     pub bind_group: wgpu::BindGroup,
 
+    mode: MaterialMode,
     size: wgpu::Extent3d,
     texture: wgpu::Texture,
     view: wgpu::TextureView,
-    sampler: wgpu::Sampler,
+    sampler: Option<wgpu::Sampler>,
+    palette_texture: Option<wgpu::Texture>,
+    fullbright_texture: Option<wgpu::Texture>,
 }
 
 impl MaterialComponent {
+    const PALETTE_SIZE: wgpu::Extent3d = wgpu::Extent3d {
+        width: 256,
+        height: 1,
+        depth_or_array_layers: 1,
+    };
+
     pub fn new(
         renderer: &Renderer,
         bind_group_layout: &BindGroupLayout,
         width: u32,
         height: u32,
+    ) -> Self {
+        Self::new_mipmapped(
+            renderer,
+            bind_group_layout,
+            width,
+            height,
+            mip_level_count(width, height),
+        )
+    }
+
+    // Also binds a fullbright mask, for layouts that expect one after the
+    // sampler. Pixels set in the mask are drawn unlit.
+    pub fn new_fullbright(
+        renderer: &Renderer,
+        bind_group_layout: &BindGroupLayout,
+        width: u32,
+        height: u32,
+    ) -> Self {
+        Self::create(
+            renderer,
+            bind_group_layout,
+            width,
+            height,
+            mip_level_count(width, height),
+            true,
+        )
+    }
+
+    // Uploads the mip levels authored in the BSP instead of generating them.
+    pub fn from_miptex(
+        renderer: &Renderer,
+        bind_group_layout: &BindGroupLayout,
+        miptex: &MipTexture,
+    ) -> Self {
+        let material_component = Self::create(
+            renderer,
+            bind_group_layout,
+            miptex.width,
+            miptex.height,
+            MipTexture::MIP_LEVELS as u32,
+            true,
+        );
+        for (level, mip) in miptex.mips.iter().enumerate() {
+            material_component.update_texture_mip(
+                &renderer.queue,
+                level as u32,
+                &resource::palette_index_to_rgba(mip),
+            );
+            material_component.update_fullbright_mip(
+                &renderer.queue,
+                level as u32,
+                &resource::fullbright_mask(mip),
+            );
+        }
+        material_component
+    }
+
+    pub fn new_mipmapped(
+        renderer: &Renderer,
+        bind_group_layout: &BindGroupLayout,
+        width: u32,
+        height: u32,
+        mip_level_count: u32,
+    ) -> Self {
+        Self::create(
+            renderer,
+            bind_group_layout,
+            width,
+            height,
+            mip_level_count,
+            false,
+        )
+    }
+
+    fn create(
+        renderer: &Renderer,
+        bind_group_layout: &BindGroupLayout,
+        width: u32,
+        height: u32,
+        mip_level_count: u32,
+        fullbright: bool,
     ) -> Self {
         let size = wgpu::Extent3d {
             width,
@@ -26,27 +126,109 @@ impl MaterialComponent {
         let texture = renderer.device.create_texture(&wgpu::TextureDescriptor {
             label: None,
             size,
-            mip_level_count: 1,
+            mip_level_count,
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
             format: renderer.config.format,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING
+                | wgpu::TextureUsages::COPY_DST
+                | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = renderer
+            .device
+            .create_sampler(&sampler_descriptor(renderer.lod_bias, mip_level_count));
+        // Left zeroed, so nothing is fullbright until a mask is uploaded.
+        let fullbright_texture = fullbright.then(|| {
+            renderer.device.create_texture(&wgpu::TextureDescriptor {
+                label: None,
+                size,
+                mip_level_count,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::R8Unorm,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+                view_formats: &[],
+            })
+        });
+        let fullbright_view = fullbright_texture
+            .as_ref()
+            .map(|texture| texture.create_view(&wgpu::TextureViewDescriptor::default()));
+        let mut entries = vec![
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(&sampler),
+            },
+        ];
+        if let Some(fullbright_view) = &fullbright_view {
+            entries.push(wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::TextureView(fullbright_view),
+            });
+        }
+        let bind_group = renderer
+            .device
+            .create_bind_group(&wgpu::BindGroupDescriptor {
+                layout: bind_group_layout,
+                entries: &entries,
+                label: None,
+            });
+
+        Self {
+            bind_group,
+
+            mode: MaterialMode::Rgba,
+            size,
+            texture,
+            view,
+            sampler: Some(sampler),
+            palette_texture: None,
+            fullbright_texture,
+        }
+    }
+
+    pub fn new_indexed(
+        renderer: &Renderer,
+        bind_group_layout: &BindGroupLayout,
+        width: u32,
+        height: u32,
+    ) -> Self {
+        let size = wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        };
+        let texture = renderer.device.create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::R8Uint,
             usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
             view_formats: &[],
         });
         let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let sampler = renderer.device.create_sampler(&wgpu::SamplerDescriptor {
-            address_mode_u: wgpu::AddressMode::ClampToEdge,
-            address_mode_v: wgpu::AddressMode::ClampToEdge,
-            address_mode_w: wgpu::AddressMode::ClampToEdge,
-            mag_filter: wgpu::FilterMode::Linear,
-            min_filter: wgpu::FilterMode::Nearest,
-            mipmap_filter: wgpu::FilterMode::Nearest,
-            ..Default::default()
+        let palette_texture = renderer.device.create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size: Self::PALETTE_SIZE,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
         });
+        let palette_view = palette_texture.create_view(&wgpu::TextureViewDescriptor::default());
         let bind_group = renderer
             .device
             .create_bind_group(&wgpu::BindGroupDescriptor {
-                layout: &bind_group_layout,
+                layout: bind_group_layout,
                 entries: &[
                     wgpu::BindGroupEntry {
                         binding: 0,
@@ -54,37 +236,290 @@ impl MaterialComponent {
                     },
                     wgpu::BindGroupEntry {
                         binding: 1,
-                        resource: wgpu::BindingResource::Sampler(&sampler),
+                        resource: wgpu::BindingResource::TextureView(&palette_view),
                     },
                 ],
                 label: None,
             });
 
-        Self {
+        let material_component = Self {
             bind_group,
 
+            mode: MaterialMode::Indexed,
             size,
             texture,
             view,
-            sampler,
+            sampler: None,
+            palette_texture: Some(palette_texture),
+            fullbright_texture: None,
+        };
+        material_component.update_palette(&renderer.queue, &resource::palette_rgba());
+        material_component
+    }
+
+    pub fn mode(&self) -> MaterialMode {
+        self.mode
+    }
+
+    // Expects 256 RGBA colors, only used by indexed materials.
+    pub fn update_palette(&self, queue: &wgpu::Queue, palette: &[u8]) {
+        if let Some(palette_texture) = &self.palette_texture {
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    texture: palette_texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d::ZERO,
+                    aspect: wgpu::TextureAspect::All,
+                },
+                palette,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(4 * Self::PALETTE_SIZE.width),
+                    rows_per_image: Some(Self::PALETTE_SIZE.height),
+                },
+                Self::PALETTE_SIZE,
+            );
         }
     }
 
+    // Takes RGBA pixels, or palette indices for indexed materials. The lower
+    // mip levels, if any, are generated from the image.
     pub fn update_texture_image(&self, queue: &wgpu::Queue, image: &[u8]) {
+        self.write_mip_chain(queue, &self.texture, self.bytes_per_pixel(), image);
+    }
+
+    pub fn update_texture_mip(&self, queue: &wgpu::Queue, mip_level: u32, image: &[u8]) {
+        self.write_mip(
+            queue,
+            &self.texture,
+            self.bytes_per_pixel(),
+            mip_level,
+            image,
+        );
+    }
+
+    // One byte per pixel, see resource::fullbright_mask. Ignored by materials
+    // created without a mask; indexed ones tell fullbrights by their index.
+    pub fn update_fullbright_mask(&self, queue: &wgpu::Queue, mask: &[u8]) {
+        if let Some(fullbright_texture) = &self.fullbright_texture {
+            self.write_mip_chain(queue, fullbright_texture, 1, mask);
+        }
+    }
+
+    pub fn update_fullbright_mip(&self, queue: &wgpu::Queue, mip_level: u32, mask: &[u8]) {
+        if let Some(fullbright_texture) = &self.fullbright_texture {
+            self.write_mip(queue, fullbright_texture, 1, mip_level, mask);
+        }
+    }
+
+    fn bytes_per_pixel(&self) -> u32 {
+        match self.mode {
+            MaterialMode::Rgba => 4,
+            MaterialMode::Indexed => 1,
+        }
+    }
+
+    fn write_mip_chain(
+        &self,
+        queue: &wgpu::Queue,
+        texture: &wgpu::Texture,
+        bytes_per_pixel: u32,
+        image: &[u8],
+    ) {
+        self.write_mip(queue, texture, bytes_per_pixel, 0, image);
+
+        let mut mip = image.to_vec();
+        for mip_level in 1..texture.mip_level_count() {
+            mip = downsample(
+                &mip,
+                (self.size.width >> (mip_level - 1)).max(1),
+                (self.size.height >> (mip_level - 1)).max(1),
+                bytes_per_pixel as usize,
+            );
+            self.write_mip(queue, texture, bytes_per_pixel, mip_level, &mip);
+        }
+    }
+
+    fn write_mip(
+        &self,
+        queue: &wgpu::Queue,
+        texture: &wgpu::Texture,
+        bytes_per_pixel: u32,
+        mip_level: u32,
+        image: &[u8],
+    ) {
+        let size = wgpu::Extent3d {
+            width: (self.size.width >> mip_level).max(1),
+            height: (self.size.height >> mip_level).max(1),
+            depth_or_array_layers: 1,
+        };
         queue.write_texture(
             wgpu::ImageCopyTexture {
-                texture: &self.texture,
-                mip_level: 0,
+                texture,
+                mip_level,
                 origin: wgpu::Origin3d::ZERO,
                 aspect: wgpu::TextureAspect::All,
             },
             image,
             wgpu::ImageDataLayout {
                 offset: 0,
-                bytes_per_row: Some(4 * self.size.width),
-                rows_per_image: Some(self.size.height),
+                bytes_per_row: Some(bytes_per_pixel * size.width),
+                rows_per_image: Some(size.height),
             },
-            self.size.clone(),
+            size,
+        );
+    }
+}
+
+// Full chain down to 1x1, so the LOD clamps have levels to choose from.
+pub fn mip_level_count(width: u32, height: u32) -> u32 {
+    32 - width.max(height).max(1).leading_zeros()
+}
+
+pub fn sampler_descriptor(lod_bias: f32, mip_level_count: u32) -> wgpu::SamplerDescriptor<'static> {
+    let (lod_min_clamp, lod_max_clamp) = pipeline::lod_clamps(lod_bias, mip_level_count);
+    wgpu::SamplerDescriptor {
+        address_mode_u: wgpu::AddressMode::ClampToEdge,
+        address_mode_v: wgpu::AddressMode::ClampToEdge,
+        address_mode_w: wgpu::AddressMode::ClampToEdge,
+        mag_filter: wgpu::FilterMode::Linear,
+        min_filter: wgpu::FilterMode::Nearest,
+        mipmap_filter: wgpu::FilterMode::Nearest,
+        lod_min_clamp,
+        lod_max_clamp,
+        ..Default::default()
+    }
+}
+
+// Halves the image with a 2x2 box filter, odd edges reuse the last texel.
+fn downsample(image: &[u8], width: u32, height: u32, bytes_per_pixel: usize) -> Vec<u8> {
+    let (width, height) = (width as usize, height as usize);
+    let (mip_width, mip_height) = ((width / 2).max(1), (height / 2).max(1));
+    let mut mip = Vec::with_capacity(mip_width * mip_height * bytes_per_pixel);
+    for y in 0..mip_height {
+        for x in 0..mip_width {
+            let rows = [2 * y, (2 * y + 1).min(height - 1)];
+            let columns = [2 * x, (2 * x + 1).min(width - 1)];
+            for channel in 0..bytes_per_pixel {
+                let sum: u32 = rows
+                    .iter()
+                    .flat_map(|row| columns.iter().map(move |column| (row, column)))
+                    .map(|(row, column)| {
+                        image[(row * width + column) * bytes_per_pixel + channel] as u32
+                    })
+                    .sum();
+                mip.push(((sum + 2) / 4) as u8);
+            }
+        }
+    }
+    mip
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::renderer::tests::headless_renderer;
+
+    // The first level of an RGBA material, whose rows must be a multiple of
+    // wgpu::COPY_BYTES_PER_ROW_ALIGNMENT long.
+    pub(crate) fn texture_rgba(renderer: &Renderer, material: &MaterialComponent) -> Vec<u8> {
+        let bytes_per_row = material.size.width * 4;
+        assert_eq!(bytes_per_row % wgpu::COPY_BYTES_PER_ROW_ALIGNMENT, 0);
+        let readback_buffer = renderer.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: (bytes_per_row * material.size.height) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let mut encoder = renderer
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        encoder.copy_texture_to_buffer(
+            material.texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &readback_buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(bytes_per_row),
+                    rows_per_image: None,
+                },
+            },
+            material.size,
+        );
+        renderer.queue.submit(std::iter::once(encoder.finish()));
+
+        let slice = readback_buffer.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |result| result.unwrap());
+        renderer.device.poll(wgpu::Maintain::Wait);
+        let rgba = slice.get_mapped_range().to_vec();
+        readback_buffer.unmap();
+
+        rgba
+    }
+
+    #[test]
+    fn sampler_descriptor_uses_lod_clamps() {
+        let sharp = sampler_descriptor(-1.0, 4);
+        assert_eq!((sharp.lod_min_clamp, sharp.lod_max_clamp), (0.0, 2.0));
+
+        let smooth = sampler_descriptor(1.5, 4);
+        assert_eq!((smooth.lod_min_clamp, smooth.lod_max_clamp), (1.5, 3.0));
+
+        let unbiased = sampler_descriptor(0.0, mip_level_count(64, 32));
+        assert_eq!((unbiased.lod_min_clamp, unbiased.lod_max_clamp), (0.0, 6.0));
+    }
+
+    #[test]
+    fn mip_level_count_reaches_one_texel() {
+        assert_eq!(mip_level_count(1, 1), 1);
+        assert_eq!(mip_level_count(64, 32), 7);
+        assert_eq!(mip_level_count(296, 194), 9);
+    }
+
+    #[test]
+    fn downsample_averages_texels() {
+        let image = [0, 4, 8, 12, 16, 20];
+        assert_eq!(downsample(&image, 3, 2, 1), vec![8]);
+        assert_eq!(downsample(&[0, 10, 20, 30], 2, 1, 2), vec![10, 20]);
+    }
+
+    #[test]
+    fn indexed_materials_create_the_index_and_palette_textures() {
+        resource::GLOBAL_PALETTE
+            .write()
+            .unwrap()
+            .get_or_insert_with(resource::fallback_palette);
+        let Some(renderer) = headless_renderer() else {
+            return;
+        };
+
+        let indexed = MaterialComponent::new_indexed(
+            &renderer,
+            &renderer
+                .entity_render_pipeline
+                .indexed_texture_bind_group_layout,
+            16,
+            8,
+        );
+        assert_eq!(indexed.mode(), MaterialMode::Indexed);
+        assert_eq!(indexed.texture.format(), wgpu::TextureFormat::R8Uint);
+        assert_eq!((indexed.texture.width(), indexed.texture.height()), (16, 8));
+        let palette_texture = indexed.palette_texture.as_ref().unwrap();
+        assert_eq!(
+            palette_texture.format(),
+            wgpu::TextureFormat::Rgba8UnormSrgb
+        );
+        assert_eq!(
+            (palette_texture.width(), palette_texture.height()),
+            (256, 1)
+        );
+
+        let rgba = MaterialComponent::new(
+            &renderer,
+            &renderer.entity_render_pipeline.texture_bind_group_layout,
+            16,
+            8,
         );
+        assert!(rgba.palette_texture.is_none());
     }
 }
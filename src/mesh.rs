@@ -1,27 +1,398 @@
-use crate::renderer::Renderer;
+use std::{
+    cell::Cell,
+    error::Error,
+    fs::File,
+    io::{BufWriter, ErrorKind, Write},
+    ops::Range,
+    path::Path,
+};
+
+use cgmath::{Matrix4, Vector3};
+use wgpu::util::DeviceExt;
+
+use crate::{
+    animation::KeyframeAnimationComponent, bvh::Aabb, material::MaterialComponent,
+    renderer::Renderer,
+};
 
 pub struct MeshComponent {
-    pub vertex_buffer: wgpu::Buffer,
     pub vertex_count: usize,
+
+    bounds: Option<Aabb>,
+    vertex_bounds: Cell<Option<Aabb>>,
+    index_buffer: Option<wgpu::Buffer>,
+    index_count: usize,
+    vertex_buffers: Box<[wgpu::Buffer]>,
+    current_vertex_buffer: BufferRotation,
+    packed_frames: Option<PackedFrames>,
+    packed_frame_buffer: wgpu::Buffer,
+    frame_blend_buffer: wgpu::Buffer,
+}
+
+// Every frame of an alias model uploaded once, so animating only updates the
+// frames to blend instead of the whole vertex buffer.
+struct PackedFrames {
+    frame_count: usize,
+    scale: [f32; 3],
+    origin: [f32; 3],
+    current_frames: Cell<(usize, usize)>,
+}
+
+// Which of several buffers is current. Each update writes the next one, so
+// the buffer a frame in flight reads is not overwritten.
+struct BufferRotation {
+    count: usize,
+    current: Cell<usize>,
+}
+
+impl BufferRotation {
+    fn new(count: usize) -> Self {
+        Self {
+            count,
+            current: Cell::new(0),
+        }
+    }
+
+    fn current(&self) -> usize {
+        self.current.get()
+    }
+
+    // Makes the next buffer current and returns it.
+    fn advance(&self) -> usize {
+        let next = (self.current.get() + 1) % self.count;
+        self.current.set(next);
+        next
+    }
 }
 
 impl MeshComponent {
+    const VERTEX_BUFFER_COUNT: usize = 2;
+
     pub fn new(renderer: &Renderer, vertex_count: usize) -> Self {
-        let vertex_buffer = renderer.device.create_buffer(&wgpu::BufferDescriptor {
+        // Meshes without packed frames still bind zeroed ones, which the
+        // shaders ignore while the frame blend is disabled.
+        let packed_frame_buffer = renderer.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: (std::mem::size_of::<[u8; 4]>() * vertex_count) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::VERTEX,
+            mapped_at_creation: false,
+        });
+
+        Self::with_frame_buffer(renderer, vertex_count, None, packed_frame_buffer)
+    }
+
+    // Positions are rebuilt on the GPU as packed * scale + origin, like the
+    // frames of a MDL file. All frames must have the same vertex count.
+    pub fn with_packed_frames(
+        renderer: &Renderer,
+        frames: &[Box<[[u8; 4]]>],
+        scale: [f32; 3],
+        origin: [f32; 3],
+    ) -> Result<Self, Box<dyn Error>> {
+        let vertex_count = frames.first().map_or(0, |frame| frame.len());
+        if frames.iter().any(|frame| frame.len() != vertex_count) {
+            return Err(Box::new(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "packed frames have different vertex counts",
+            )));
+        }
+
+        let packed_frame_buffer =
+            renderer
+                .device
+                .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: None,
+                    contents: bytemuck::cast_slice(&frames.concat()),
+                    usage: wgpu::BufferUsages::VERTEX,
+                });
+        let packed_frames = PackedFrames {
+            frame_count: frames.len(),
+            scale,
+            origin,
+            current_frames: Cell::new((0, 0)),
+        };
+
+        Ok(Self::with_frame_buffer(
+            renderer,
+            vertex_count,
+            Some(packed_frames),
+            packed_frame_buffer,
+        ))
+    }
+
+    fn with_frame_buffer(
+        renderer: &Renderer,
+        vertex_count: usize,
+        packed_frames: Option<PackedFrames>,
+        packed_frame_buffer: wgpu::Buffer,
+    ) -> Self {
+        let vertex_buffers = (0..Self::VERTEX_BUFFER_COUNT)
+            .map(|_| {
+                renderer.device.create_buffer(&wgpu::BufferDescriptor {
+                    label: None,
+                    size: (std::mem::size_of::<Vertex>() * vertex_count) as wgpu::BufferAddress,
+                    usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                })
+            })
+            .collect();
+        let frame_blend_buffer = renderer.device.create_buffer(&wgpu::BufferDescriptor {
             label: None,
-            size: (std::mem::size_of::<Vertex>() * vertex_count) as wgpu::BufferAddress,
+            size: std::mem::size_of::<FrameBlend>() as wgpu::BufferAddress,
             usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
             mapped_at_creation: false,
         });
 
         Self {
-            vertex_buffer,
             vertex_count,
+
+            bounds: None,
+            vertex_bounds: Cell::new(None),
+            index_buffer: None,
+            index_count: 0,
+            vertex_buffers,
+            current_vertex_buffer: BufferRotation::new(Self::VERTEX_BUFFER_COUNT),
+            packed_frames,
+            packed_frame_buffer,
+            frame_blend_buffer,
+        }
+    }
+
+    // Bounds are in model space, using Quake coordinates like the vertices.
+    // Bounds set by set_bounds take precedence over the vertex bounds, since
+    // they can cover every frame of an animation.
+    pub fn bounds(&self) -> Option<Aabb> {
+        self.bounds.or(self.vertex_bounds.get())
+    }
+
+    // Bounds of the vertices last uploaded with update_vertex_buffer. Frames
+    // blended on the GPU with set_frame_blend do not update them.
+    pub fn vertex_bounds(&self) -> Option<Aabb> {
+        self.vertex_bounds.get()
+    }
+
+    pub fn set_bounds(&mut self, bounds: Aabb) {
+        self.bounds = Some(bounds);
+    }
+
+    pub fn vertex_buffer(&self) -> &wgpu::Buffer {
+        &self.vertex_buffers[self.current_vertex_buffer.current()]
+    }
+
+    pub fn index_count(&self) -> usize {
+        self.index_count
+    }
+
+    // Meshes with indices are drawn indexed, reusing vertices shared between
+    // triangles. The buffer is only reallocated when the indices grow.
+    pub fn update_index_buffer(&mut self, renderer: &Renderer, indices: &[u32]) {
+        let size = std::mem::size_of_val(indices) as wgpu::BufferAddress;
+        match self.index_buffer {
+            Some(ref index_buffer) if index_buffer.size() >= size => {
+                renderer
+                    .queue
+                    .write_buffer(index_buffer, 0, bytemuck::cast_slice(indices));
+            }
+            _ => {
+                self.index_buffer = Some(renderer.device.create_buffer_init(
+                    &wgpu::util::BufferInitDescriptor {
+                        label: None,
+                        contents: bytemuck::cast_slice(indices),
+                        usage: wgpu::BufferUsages::INDEX | wgpu::BufferUsages::COPY_DST,
+                    },
+                ));
+            }
         }
+        self.index_count = indices.len();
     }
 
-    pub fn update_vertex_buffer(&self, queue: &wgpu::Queue, vertices: &Vec<Vertex>) {
-        queue.write_buffer(&self.vertex_buffer, 0, bytemuck::cast_slice(vertices));
+    pub fn packed_frame_count(&self) -> usize {
+        self.packed_frames
+            .as_ref()
+            .map_or(0, |packed_frames| packed_frames.frame_count)
+    }
+
+    // Positions then come from the vertex buffer again, e.g. when the
+    // animation needs blending the GPU path cannot do.
+    pub fn update_vertex_buffer(&self, queue: &wgpu::Queue, vertices: &[Vertex]) {
+        let next_vertex_buffer = self.current_vertex_buffer.advance();
+        queue.write_buffer(
+            &self.vertex_buffers[next_vertex_buffer],
+            0,
+            bytemuck::cast_slice(vertices),
+        );
+        self.vertex_bounds.set(Aabb::from_points(
+            vertices.iter().map(|vertex| Vector3::from(vertex.position)),
+        ));
+
+        if self.packed_frames.is_some() {
+            queue.write_buffer(
+                &self.frame_blend_buffer,
+                0,
+                bytemuck::bytes_of(&FrameBlend::default()),
+            );
+        }
+    }
+
+    // Draws the packed frames interpolated from current to next by t. Normals
+    // and texture coordinates still come from the vertex buffer, which must
+    // have been filled once.
+    pub fn set_frame_blend(
+        &self,
+        queue: &wgpu::Queue,
+        current: usize,
+        next: usize,
+        t: f32,
+    ) -> Result<(), Box<dyn Error>> {
+        let packed_frames = self.packed_frames.as_ref().ok_or_else(|| {
+            std::io::Error::new(ErrorKind::Unsupported, "mesh has no packed frames")
+        })?;
+        if current >= packed_frames.frame_count || next >= packed_frames.frame_count {
+            return Err(Box::new(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "frames out of range: {} and {}, mesh has {}",
+                    current, next, packed_frames.frame_count
+                ),
+            )));
+        }
+
+        let [sx, sy, sz] = packed_frames.scale;
+        let [ox, oy, oz] = packed_frames.origin;
+        let frame_blend = FrameBlend {
+            scale: [sx, sy, sz, t],
+            origin: [ox, oy, oz, 1.0],
+        };
+        queue.write_buffer(
+            &self.frame_blend_buffer,
+            0,
+            bytemuck::bytes_of(&frame_blend),
+        );
+        packed_frames.current_frames.set((current, next));
+
+        Ok(())
+    }
+
+    // Binds the buffers described by vertex_buffer_layouts.
+    pub fn set_vertex_buffers<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>) {
+        let (current, next) = self
+            .packed_frames
+            .as_ref()
+            .map_or((0, 0), |packed_frames| packed_frames.current_frames.get());
+        let frame_size = (std::mem::size_of::<[u8; 4]>() * self.vertex_count) as u64;
+
+        render_pass.set_vertex_buffer(0, self.vertex_buffer().slice(..));
+        render_pass.set_vertex_buffer(
+            1,
+            self.packed_frame_buffer
+                .slice(current as u64 * frame_size..(current as u64 + 1) * frame_size),
+        );
+        render_pass.set_vertex_buffer(
+            2,
+            self.packed_frame_buffer
+                .slice(next as u64 * frame_size..(next as u64 + 1) * frame_size),
+        );
+        render_pass.set_vertex_buffer(3, self.frame_blend_buffer.slice(..));
+    }
+
+    // Binds the vertex buffers and the index buffer, if any, and draws the
+    // given instances.
+    pub fn draw<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>, instances: Range<u32>) {
+        self.set_vertex_buffers(render_pass);
+        match self.index_buffer {
+            Some(ref index_buffer) => {
+                render_pass.set_index_buffer(index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                render_pass.draw_indexed(0..self.index_count as u32, 0, instances);
+            }
+            None => render_pass.draw(0..self.vertex_count as u32, instances),
+        }
+    }
+
+    pub fn vertex_buffer_layouts<'a>() -> [wgpu::VertexBufferLayout<'a>; 4] {
+        [
+            Vertex::desc(),
+            wgpu::VertexBufferLayout {
+                array_stride: std::mem::size_of::<[u8; 4]>() as wgpu::BufferAddress,
+                step_mode: wgpu::VertexStepMode::Vertex,
+                attributes: &Self::CURRENT_FRAME_ATTRS,
+            },
+            wgpu::VertexBufferLayout {
+                array_stride: std::mem::size_of::<[u8; 4]>() as wgpu::BufferAddress,
+                step_mode: wgpu::VertexStepMode::Vertex,
+                attributes: &Self::NEXT_FRAME_ATTRS,
+            },
+            wgpu::VertexBufferLayout {
+                array_stride: std::mem::size_of::<FrameBlend>() as wgpu::BufferAddress,
+                step_mode: wgpu::VertexStepMode::Instance,
+                attributes: &Self::FRAME_BLEND_ATTRS,
+            },
+        ]
+    }
+
+    const CURRENT_FRAME_ATTRS: [wgpu::VertexAttribute; 1] = wgpu::vertex_attr_array![3 => Uint8x4];
+    const NEXT_FRAME_ATTRS: [wgpu::VertexAttribute; 1] = wgpu::vertex_attr_array![4 => Uint8x4];
+    const FRAME_BLEND_ATTRS: [wgpu::VertexAttribute; 2] =
+        wgpu::vertex_attr_array![5 => Float32x4, 6 => Float32x4];
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, bytemuck::Pod, bytemuck::Zeroable)]
+struct FrameBlend {
+    // w is the interpolation factor between the current and next frame.
+    scale: [f32; 4],
+    // w is set when positions come from the packed frames.
+    origin: [f32; 4],
+}
+
+// Additional models drawn with the transform of the entity holding them, such
+// as a weapon carried by a player model.
+pub struct SubMesh {
+    pub mesh: MeshComponent,
+    pub material: MaterialComponent,
+    pub animation: KeyframeAnimationComponent,
+}
+
+pub struct SubMeshComponent {
+    pub sub_meshes: Vec<SubMesh>,
+}
+
+impl SubMeshComponent {
+    pub fn new() -> Self {
+        Self {
+            sub_meshes: Vec::new(),
+        }
+    }
+}
+
+impl Default for SubMeshComponent {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Copies of the entity mesh drawn in a single instanced draw call, such as
+// the ammo boxes of a level, see Scene::add_instanced_entity. Each transform
+// is applied in the entity space, so the copies move with the entity and
+// share its material, tint and animation. The copies cast no shadows.
+pub struct InstanceComponent {
+    pub transforms: Vec<Matrix4<f32>>,
+}
+
+impl InstanceComponent {
+    pub fn new() -> Self {
+        Self {
+            transforms: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, transform: Matrix4<f32>) {
+        self.transforms.push(transform);
+    }
+}
+
+impl Default for InstanceComponent {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -45,3 +416,137 @@ impl Vertex {
         }
     }
 }
+
+// Writes an unindexed triangle list as a Wavefront OBJ, in Quake coordinates
+// (z up). Triangles are front facing when clockwise on screen, so their
+// winding is reversed to match the counter-clockwise OBJ convention.
+pub fn export_obj<P>(path: P, vertices: &[Vertex]) -> Result<(), Box<dyn Error>>
+where
+    P: AsRef<Path>,
+{
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_obj(&mut writer, vertices)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+pub fn write_obj<W>(writer: &mut W, vertices: &[Vertex]) -> Result<(), Box<dyn Error>>
+where
+    W: Write,
+{
+    for vertex in vertices {
+        let [x, y, z] = vertex.position;
+        writeln!(writer, "v {} {} {}", x, y, z)?;
+    }
+    for vertex in vertices {
+        let [x, y, z] = vertex.normal;
+        writeln!(writer, "vn {} {} {}", x, y, z)?;
+    }
+    for vertex in vertices {
+        // OBJ texture coordinates start at the bottom of the image.
+        let [s, t] = vertex.texcoord;
+        writeln!(writer, "vt {} {}", s, 1.0 - t)?;
+    }
+    // OBJ indices start at 1.
+    for triangle in 0..vertices.len() / 3 {
+        let (a, b, c) = (triangle * 3 + 1, triangle * 3 + 2, triangle * 3 + 3);
+        writeln!(writer, "f {a}/{a}/{a} {c}/{c}/{c} {b}/{b}/{b}")?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::tests::headless_renderer;
+
+    fn vertex(position: [f32; 3]) -> Vertex {
+        Vertex {
+            position,
+            normal: [0.0, 0.0, 1.0],
+            texcoord: [0.0; 2],
+        }
+    }
+
+    #[test]
+    fn updates_rotate_the_current_vertex_buffer() {
+        let rotation = BufferRotation::new(2);
+        assert_eq!(rotation.current(), 0);
+        assert_eq!(rotation.advance(), 1);
+        assert_eq!(rotation.current(), 1);
+        assert_eq!(rotation.advance(), 0);
+        let rotation = BufferRotation::new(3);
+        let order: Vec<usize> = (0..4).map(|_| rotation.advance()).collect();
+        assert_eq!(order, [1, 2, 0, 1]);
+
+        let Some(renderer) = headless_renderer() else {
+            return;
+        };
+        let mesh_component = MeshComponent::new(&renderer, 3);
+        let vertices = [vertex([0.0; 3]); 3];
+        let is_current = |i: usize| {
+            std::ptr::eq(
+                mesh_component.vertex_buffer(),
+                &mesh_component.vertex_buffers[i],
+            )
+        };
+        assert!(is_current(0));
+        mesh_component.update_vertex_buffer(&renderer.queue, &vertices);
+        assert!(is_current(1));
+        mesh_component.update_vertex_buffer(&renderer.queue, &vertices);
+        assert!(is_current(0));
+    }
+
+    #[test]
+    fn bounds_cover_the_uploaded_vertices() {
+        let Some(renderer) = headless_renderer() else {
+            return;
+        };
+        let mut mesh_component = MeshComponent::new(&renderer, 3);
+        assert_eq!(mesh_component.bounds(), None);
+
+        let vertices = [
+            vertex([1.0, -2.0, 3.0]),
+            vertex([-4.0, 5.0, 0.5]),
+            vertex([2.0, 0.0, -6.0]),
+        ];
+        mesh_component.update_vertex_buffer(&renderer.queue, &vertices);
+        let expected = Aabb::new(Vector3::new(-4.0, -2.0, -6.0), Vector3::new(2.0, 5.0, 3.0));
+        assert_eq!(mesh_component.vertex_bounds(), Some(expected));
+        assert_eq!(mesh_component.bounds(), Some(expected));
+
+        // Bounds of the whole animation take precedence.
+        let animation_bounds =
+            Aabb::new(Vector3::new(-8.0, -8.0, -8.0), Vector3::new(8.0, 8.0, 8.0));
+        mesh_component.set_bounds(animation_bounds);
+        assert_eq!(mesh_component.bounds(), Some(animation_bounds));
+        assert_eq!(mesh_component.vertex_bounds(), Some(expected));
+    }
+
+    #[test]
+    fn obj_has_a_line_per_vertex_and_triangle() {
+        let vertices: Vec<Vertex> = (0..6)
+            .map(|i| Vertex {
+                position: [i as f32, 0.0, 0.0],
+                normal: [0.0, 0.0, 1.0],
+                texcoord: [0.0, 0.25],
+            })
+            .collect();
+        let path = std::env::temp_dir().join(format!("quake_rs_{}_mesh.obj", std::process::id()));
+        export_obj(&path, &vertices).unwrap();
+        let obj = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        let count = |prefix: &str| obj.lines().filter(|line| line.starts_with(prefix)).count();
+        assert_eq!(count("v "), 6);
+        assert_eq!(count("vn "), 6);
+        assert_eq!(count("vt "), 6);
+        assert_eq!(count("f "), 2);
+        assert!(obj.lines().any(|line| line == "v 5 0 0"));
+        assert!(obj.lines().any(|line| line == "vt 0 0.75"));
+        // The winding is reversed.
+        assert!(obj.lines().any(|line| line == "f 4/4/4 6/6/6 5/5/5"));
+    }
+}
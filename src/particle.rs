@@ -0,0 +1,173 @@
+use std::time::Duration;
+
+use cgmath::{InnerSpace, Vector3, Zero};
+
+use crate::{random::GLOBAL_RANDOM, resource::GLOBAL_PALETTE, units};
+
+#[derive(Clone, Debug)]
+pub struct Particle {
+    pub position: Vector3<f32>,
+    pub velocity: Vector3<f32>,
+    pub color: u8,
+    pub lifetime: Duration,
+}
+
+pub struct ParticleSystem {
+    particles: Vec<Particle>,
+    gravity: f32,
+}
+
+impl ParticleSystem {
+    // Matches the size of the particle pool of the original engine.
+    pub const MAX_PARTICLES: usize = 2048;
+
+    pub fn new() -> Self {
+        Self {
+            particles: Vec::with_capacity(Self::MAX_PARTICLES),
+            gravity: units::from_meters(units::gravity()),
+        }
+    }
+
+    pub fn particles(&self) -> &[Particle] {
+        &self.particles
+    }
+
+    pub fn spawn(&mut self, particle: Particle) {
+        if self.particles.len() < Self::MAX_PARTICLES {
+            self.particles.push(particle);
+        }
+    }
+
+    // Origin and spread are in Quake units; particles are thrown in random
+    // directions with speeds up to spread units per second.
+    pub fn spawn_burst(&mut self, origin: [f32; 3], count: usize, spread: f32) {
+        let mut random = GLOBAL_RANDOM.lock().unwrap();
+        for _ in 0..count {
+            let mut direction = Vector3::new(random.crandom(), random.crandom(), random.crandom());
+            if direction.is_zero() {
+                direction = Vector3::unit_z();
+            }
+
+            self.spawn(Particle {
+                position: Vector3::from(origin),
+                velocity: direction.normalize() * spread * random.next_f32(),
+                color: 0x6f + (random.next_u32() % 8) as u8,
+                lifetime: Duration::from_secs_f32(random.range(0.3, 0.8)),
+            });
+        }
+    }
+
+    pub fn update(&mut self, delta_time: &Duration) {
+        let dt = delta_time.as_secs_f32();
+        self.particles.retain_mut(|particle| {
+            if particle.lifetime <= *delta_time {
+                return false;
+            }
+
+            particle.lifetime -= *delta_time;
+            particle.velocity.z -= self.gravity * dt;
+            particle.position += particle.velocity * dt;
+            true
+        });
+    }
+
+    pub fn vertices(&self) -> Vec<Vertex1XYZ1UV1RGBA> {
+        const CORNERS: [[f32; 2]; 6] = [
+            [-1.0, -1.0],
+            [-1.0, 1.0],
+            [1.0, 1.0],
+            [-1.0, -1.0],
+            [1.0, 1.0],
+            [1.0, -1.0],
+        ];
+
+        let palette = GLOBAL_PALETTE.read().unwrap();
+        let mut vertices = Vec::with_capacity(self.particles.len() * CORNERS.len());
+        for particle in self.particles.iter() {
+            let rgb = palette
+                .as_ref()
+                .map(|palette| palette[particle.color as usize])
+                .unwrap_or([0xff; 3]);
+            let color = [
+                rgb[0] as f32 / 255.0,
+                rgb[1] as f32 / 255.0,
+                rgb[2] as f32 / 255.0,
+                1.0,
+            ];
+            for corner in CORNERS {
+                vertices.push(Vertex1XYZ1UV1RGBA {
+                    position: particle.position.into(),
+                    texcoord: corner,
+                    color,
+                });
+            }
+        }
+        vertices
+    }
+}
+
+impl Default for ParticleSystem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Vertex1XYZ1UV1RGBA {
+    pub position: [f32; 3],
+    pub texcoord: [f32; 2],
+    pub color: [f32; 4],
+}
+
+impl Vertex1XYZ1UV1RGBA {
+    const VERTEX_ATTRS: [wgpu::VertexAttribute; 3] =
+        wgpu::vertex_attr_array![0 => Float32x3, 1 => Float32x2, 2 => Float32x4];
+
+    pub fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<Self>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &Self::VERTEX_ATTRS,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::Clock;
+
+    #[test]
+    fn particles_expire_after_their_lifetime() {
+        let mut particles = ParticleSystem::new();
+        particles.spawn_burst([0.0, 0.0, 64.0], 100, 50.0);
+        assert_eq!(particles.particles().len(), 100);
+
+        // Bursts live between 0.3 and 0.8 seconds.
+        let mut clock = Clock::manual();
+        clock.advance(Duration::from_millis(200));
+        particles.update(&clock.delta());
+        assert_eq!(particles.particles().len(), 100);
+
+        clock.advance(Duration::from_millis(700));
+        particles.update(&clock.delta());
+        assert!(particles.particles().is_empty());
+    }
+
+    #[test]
+    fn pool_is_capped() {
+        let mut particles = ParticleSystem::new();
+        particles.spawn_burst([0.0; 3], ParticleSystem::MAX_PARTICLES - 10, 50.0);
+        particles.spawn_burst([0.0; 3], 100, 50.0);
+        assert_eq!(particles.particles().len(), ParticleSystem::MAX_PARTICLES);
+
+        particles.spawn(Particle {
+            position: Vector3::zero(),
+            velocity: Vector3::zero(),
+            color: 0,
+            lifetime: Duration::from_secs(1),
+        });
+        assert_eq!(particles.particles().len(), ParticleSystem::MAX_PARTICLES);
+    }
+}
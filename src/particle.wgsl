@@ -0,0 +1,35 @@
+@group(0) @binding(0) var<uniform> view_proj : mat4x4f;
+
+struct VertexInput {
+    @location(0) position : vec3<f32>,
+    @location(1) texcoord : vec2<f32>,
+    @location(2) color    : vec4<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip  : vec4<f32>,
+    @location(0)       color : vec4<f32>,
+}
+
+fn from_quake_coords(coords: vec3<f32>) -> vec3<f32> {
+  return vec3<f32>(-coords.y, coords.z, -coords.x);
+}
+
+/* Particles are expanded into screen aligned quads in clip space, so they
+ * shrink with distance like any other geometry.  */
+const PARTICLE_SIZE : f32 = 1.5;
+
+@vertex fn vs_main(in: VertexInput) -> VertexOutput {
+    var out : VertexOutput;
+
+    out.clip = view_proj * vec4<f32>(from_quake_coords(in.position), 1.0);
+    out.clip = vec4<f32>(out.clip.xy + in.texcoord * PARTICLE_SIZE, out.clip.zw);
+    out.color = in.color;
+
+    return out;
+}
+
+
+@fragment fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    return in.color;
+}
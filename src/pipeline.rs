@@ -1,58 +1,145 @@
-use cgmath::{Matrix4, SquareMatrix};
+use std::{cell::Cell, ops::Range};
+
+use cgmath::{InnerSpace, Matrix4, SquareMatrix, Vector3};
 use wgpu::util::DeviceExt;
 
 use crate::{
+    camera::Camera,
     entity::Entity,
-    material::MaterialComponent,
-    mesh::{MeshComponent, Vertex},
-    transform::TransformComponent,
+    fence::FenceComponent,
+    liquid::LiquidComponent,
+    material::{MaterialComponent, MaterialMode},
+    mesh::{InstanceComponent, MeshComponent, SubMeshComponent},
+    particle::{ParticleSystem, Vertex1XYZ1UV1RGBA},
+    render_state::RenderStateComponent,
+    shadow::ShadowComponent,
+    transform::{self, TransformComponent},
 };
 
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct ModelUniform {
+    model_matrix: [[f32; 4]; 4],
+    tint: [f32; 4],
+    // x is set for liquid surfaces, y for alpha tested ones.
+    flags: [u32; 4],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct FrameUniform {
+    time: f32,
+    liquid_warp_speed: f32,
+    liquid_warp: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct InstanceRaw {
+    model_matrix: [[f32; 4]; 4],
+}
+
+impl InstanceRaw {
+    const INSTANCE_ATTRS: [wgpu::VertexAttribute; 4] = wgpu::vertex_attr_array![
+        7 => Float32x4, 8 => Float32x4, 9 => Float32x4, 10 => Float32x4
+    ];
+
+    fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<Self>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &Self::INSTANCE_ATTRS,
+        }
+    }
+}
+
 pub struct AliasPipeline {
     pub albedo_view: wgpu::TextureView,
     pub normal_view: wgpu::TextureView,
     pub depth_view: wgpu::TextureView,
     pub texture_bind_group_layout: wgpu::BindGroupLayout,
+    pub indexed_texture_bind_group_layout: wgpu::BindGroupLayout,
 
     albedo_texture: wgpu::Texture,
     normal_texture: wgpu::Texture,
     depth_texture: wgpu::Texture,
+    viewmodel_depth_texture: wgpu::Texture,
+    viewmodel_depth_view: wgpu::TextureView,
 
-    model_matrix_buffer: wgpu::Buffer,
+    model_uniform_buffer: wgpu::Buffer,
+    model_uniform_stride: wgpu::BufferAddress,
     model_bind_group: wgpu::BindGroup,
+    frame_uniform_buffer: wgpu::Buffer,
+    instance_buffer: wgpu::Buffer,
+    clear_color: wgpu::Color,
+    depth_bias: wgpu::DepthBiasState,
     render_pipeline: wgpu::RenderPipeline,
+    indexed_render_pipeline: wgpu::RenderPipeline,
 }
 
 impl AliasPipeline {
+    // Per-entity uniforms live in a single buffer indexed with dynamic offsets,
+    // so entities past this count in a frame are not drawn. The viewmodel gets
+    // its own slot after them.
+    pub const MAX_ENTITIES: usize = 1024;
+    const VIEWMODEL_SLOT: usize = Self::MAX_ENTITIES;
+    // Instance transforms of every entity share a single buffer too, so
+    // instances past this count in a frame are not drawn. The first slot holds
+    // the identity, used by entities drawn once.
+    pub const MAX_INSTANCES: usize = 4096;
+    // The albedo and normal targets need different clear values: the albedo
+    // one shows wherever no geometry is drawn, while the normal one should
+    // decode to no normal at all. Normals are stored biased, so that is gray.
+    pub const DEFAULT_CLEAR_COLOR: wgpu::Color = wgpu::Color::BLACK;
+    const NORMAL_CLEAR_COLOR: wgpu::Color = wgpu::Color {
+        r: 0.5,
+        g: 0.5,
+        b: 0.5,
+        a: 0.0,
+    };
+
+    // A depth bias pushes the entity surfaces away from coplanar geometry to
+    // avoid z-fighting, the default applies none.
     pub fn new<'a>(
         device: &wgpu::Device,
         config: &wgpu::SurfaceConfiguration,
         bind_group_layouts: &'a [&'a wgpu::BindGroupLayout],
+        depth_bias: wgpu::DepthBiasState,
     ) -> Self {
-        let target_size = wgpu::Extent3d {
-            width: config.width,
-            height: config.height,
-            depth_or_array_layers: 1,
-        };
-
-        let albedo_texture = Self::create_attachment_texture(device, config.format, target_size);
-        let albedo_view = albedo_texture.create_view(&Default::default());
-
-        let normal_texture = Self::create_attachment_texture(device, config.format, target_size);
-        let normal_view = normal_texture.create_view(&Default::default());
-
-        let depth_texture =
-            Self::create_attachment_texture(device, wgpu::TextureFormat::Depth32Float, target_size);
-        let depth_view = depth_texture.create_view(&Default::default());
+        let [(albedo_texture, albedo_view), (normal_texture, normal_view), (depth_texture, depth_view), (viewmodel_depth_texture, viewmodel_depth_view)] =
+            Self::create_attachments(device, config);
 
-        let model_matrix: [[f32; 4]; 4] = Matrix4::identity().into();
-        let model_matrix_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        let model_uniform_size = std::mem::size_of::<ModelUniform>() as wgpu::BufferAddress;
+        let model_uniform_alignment =
+            device.limits().min_uniform_buffer_offset_alignment as wgpu::BufferAddress;
+        let model_uniform_stride =
+            model_uniform_size.div_ceil(model_uniform_alignment) * model_uniform_alignment;
+        let model_uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
             label: None,
-            contents: bytemuck::cast_slice(&[model_matrix]),
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            size: model_uniform_stride * (Self::VIEWMODEL_SLOT + 1) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM
+                | wgpu::BufferUsages::COPY_DST
+                | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let frame_uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: std::mem::size_of::<FrameUniform>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM
+                | wgpu::BufferUsages::COPY_DST
+                | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let instance_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: (std::mem::size_of::<InstanceRaw>() * (Self::MAX_INSTANCES + 1))
+                as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
         });
         let (model_bind_group, model_bind_group_layout) =
-            Self::create_model_bind_group(device, &model_matrix_buffer);
+            Self::create_model_bind_group(device, &model_uniform_buffer, &frame_uniform_buffer);
 
         let texture_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
@@ -73,15 +160,68 @@ impl AliasPipeline {
                         ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
                         count: None,
                     },
+                    // Fullbright mask, see MaterialComponent::new_fullbright.
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        },
+                        count: None,
+                    },
+                ],
+                label: None,
+            });
+        let indexed_texture_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            sample_type: wgpu::TextureSampleType::Uint,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        },
+                        count: None,
+                    },
                 ],
                 label: None,
             });
+
         let mut chained_bind_group_layouts = bind_group_layouts.to_vec();
         chained_bind_group_layouts.push(&model_bind_group_layout);
         chained_bind_group_layouts.push(&texture_bind_group_layout);
+        let render_pipeline = Self::create_render_pipeline(
+            device,
+            config.format,
+            &chained_bind_group_layouts,
+            wgpu::include_wgsl!("alias.wgsl"),
+            depth_bias,
+        );
 
-        let render_pipeline =
-            Self::create_render_pipeline(device, config.format, &chained_bind_group_layouts);
+        let mut chained_bind_group_layouts = bind_group_layouts.to_vec();
+        chained_bind_group_layouts.push(&model_bind_group_layout);
+        chained_bind_group_layouts.push(&indexed_texture_bind_group_layout);
+        let indexed_render_pipeline = Self::create_render_pipeline(
+            device,
+            config.format,
+            &chained_bind_group_layouts,
+            wgpu::include_wgsl!("alias_indexed.wgsl"),
+            depth_bias,
+        );
 
         Self {
             albedo_texture,
@@ -90,13 +230,22 @@ impl AliasPipeline {
             normal_view,
             depth_texture,
             depth_view,
+            viewmodel_depth_texture,
+            viewmodel_depth_view,
 
             texture_bind_group_layout,
+            indexed_texture_bind_group_layout,
 
-            model_matrix_buffer,
+            model_uniform_buffer,
+            model_uniform_stride,
             model_bind_group,
+            frame_uniform_buffer,
+            instance_buffer,
+            clear_color: Self::DEFAULT_CLEAR_COLOR,
 
+            depth_bias,
             render_pipeline,
+            indexed_render_pipeline,
         }
     }
 
@@ -105,10 +254,39 @@ impl AliasPipeline {
         queue: &wgpu::Queue,
         encoder: &mut wgpu::CommandEncoder,
         bind_groups: &'a [&'a wgpu::BindGroup],
-        entities: &Vec<Entity>,
-    ) {
-        let albedo_attachment = Self::create_render_pass_color_attachment(&self.albedo_view);
-        let normal_attachment = Self::create_render_pass_color_attachment(&self.normal_view);
+        entities: &[&Entity],
+    ) -> usize {
+        let entities = if entities.len() > Self::MAX_ENTITIES {
+            warn!(
+                "Too many entities to draw: {}, only {} will be rendered",
+                entities.len(),
+                Self::MAX_ENTITIES
+            );
+            &entities[..Self::MAX_ENTITIES]
+        } else {
+            entities
+        };
+
+        let mut model_uniforms = vec![0u8; entities.len() * self.model_uniform_stride as usize];
+        for (entity, model_uniform) in entities
+            .iter()
+            .zip(model_uniforms.chunks_mut(self.model_uniform_stride as usize))
+        {
+            let uniform = Self::model_uniform(entity);
+            model_uniform[..std::mem::size_of::<ModelUniform>()]
+                .copy_from_slice(bytemuck::bytes_of(&uniform));
+        }
+        if !model_uniforms.is_empty() {
+            queue.write_buffer(&self.model_uniform_buffer, 0, &model_uniforms);
+        }
+
+        let (instances, instance_ranges) = Self::instances(entities);
+        queue.write_buffer(&self.instance_buffer, 0, bytemuck::cast_slice(&instances));
+
+        let albedo_attachment =
+            Self::create_render_pass_color_attachment(&self.albedo_view, self.clear_color);
+        let normal_attachment =
+            Self::create_render_pass_color_attachment(&self.normal_view, Self::NORMAL_CLEAR_COLOR);
         let color_attachments = [Some(albedo_attachment), Some(normal_attachment)];
         let render_pass_desc = Self::create_render_pass_desc(&color_attachments, &self.depth_view);
         let mut render_pass = encoder.begin_render_pass(&render_pass_desc);
@@ -118,41 +296,228 @@ impl AliasPipeline {
             render_pass.set_bind_group(i as u32, bind_group, &[]);
         }
 
+        let mut draws = 0;
+        for (i, (entity, instance_range)) in entities.iter().zip(instance_ranges).enumerate() {
+            draws += self.draw_entity(
+                &mut render_pass,
+                bind_groups.len() as u32,
+                i,
+                entity,
+                instance_range,
+            );
+        }
+
+        draws
+    }
+
+    // Packs the instance transforms after the identity slot, returning the
+    // range each entity draws. Entities without instances draw the identity.
+    fn instances(entities: &[&Entity]) -> (Vec<InstanceRaw>, Vec<Range<u32>>) {
+        let mut instances = vec![InstanceRaw {
+            model_matrix: Matrix4::identity().into(),
+        }];
+        let mut instance_ranges = Vec::with_capacity(entities.len());
         for entity in entities {
-            let mut bind_group_index = bind_groups.len() as u32 - 1;
+            let transforms = entity
+                .get_component::<InstanceComponent>()
+                .map_or(&[][..], |instance_component| {
+                    &instance_component.transforms[..]
+                });
+            if transforms.is_empty() {
+                instance_ranges.push(0..1);
+                continue;
+            }
 
-            let mut model_matrix: [[f32; 4]; 4] = Matrix4::identity().into();
-            if let Some(transform_component) = entity.get_component::<TransformComponent>() {
-                model_matrix = transform_component.transform_matrix().into();
+            let available = Self::MAX_INSTANCES + 1 - instances.len();
+            if transforms.len() > available {
+                warn!(
+                    "Too many instances to draw: {}, only {} will be rendered",
+                    transforms.len(),
+                    available
+                );
             }
-            queue.write_buffer(
-                &self.model_matrix_buffer,
-                0,
-                bytemuck::cast_slice(&[model_matrix]),
+            let start = instances.len() as u32;
+            instances.extend(
+                transforms
+                    .iter()
+                    .take(available)
+                    .map(|transform| InstanceRaw {
+                        model_matrix: (*transform).into(),
+                    }),
+            );
+            instance_ranges.push(start..instances.len() as u32);
+        }
+
+        (instances, instance_ranges)
+    }
+
+    // Draws on top of whatever the entity pass left in the color targets,
+    // with bind_groups carrying the viewmodel projection instead of the
+    // camera one.
+    pub fn viewmodel_render_pass<'a>(
+        &self,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        bind_groups: &'a [&'a wgpu::BindGroup],
+        entity: &Entity,
+    ) -> usize {
+        let uniform = Self::model_uniform(entity);
+        queue.write_buffer(
+            &self.model_uniform_buffer,
+            Self::VIEWMODEL_SLOT as wgpu::BufferAddress * self.model_uniform_stride,
+            bytemuck::bytes_of(&uniform),
+        );
+
+        let load_attachment = |view| wgpu::RenderPassColorAttachment {
+            view,
+            resolve_target: None,
+            ops: wgpu::Operations {
+                load: wgpu::LoadOp::Load,
+                store: true,
+            },
+        };
+        let color_attachments = [
+            Some(load_attachment(&self.albedo_view)),
+            Some(load_attachment(&self.normal_view)),
+        ];
+        let render_pass_desc =
+            Self::create_render_pass_desc(&color_attachments, &self.viewmodel_depth_view);
+        let mut render_pass = encoder.begin_render_pass(&render_pass_desc);
+
+        render_pass.set_pipeline(&self.render_pipeline);
+        for (i, bind_group) in bind_groups.iter().enumerate() {
+            render_pass.set_bind_group(i as u32, bind_group, &[]);
+        }
+        self.draw_entity(
+            &mut render_pass,
+            bind_groups.len() as u32,
+            Self::VIEWMODEL_SLOT,
+            entity,
+            0..1,
+        )
+    }
+
+    // Returns the number of draws issued, one per mesh of the entity.
+    fn draw_entity<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        model_bind_group_index: u32,
+        model_uniform_slot: usize,
+        entity: &'a Entity,
+        instances: Range<u32>,
+    ) -> usize {
+        let model_uniform_offset = model_uniform_slot as wgpu::DynamicOffset
+            * self.model_uniform_stride as wgpu::DynamicOffset;
+        render_pass.set_bind_group(
+            model_bind_group_index,
+            &self.model_bind_group,
+            &[model_uniform_offset],
+        );
+
+        let material_bind_group_index = model_bind_group_index + 1;
+        if let Some(material_component) = entity.get_component::<MaterialComponent>() {
+            render_pass.set_pipeline(self.material_render_pipeline(material_component));
+            render_pass.set_bind_group(
+                material_bind_group_index,
+                &material_component.bind_group,
+                &[],
             );
-            bind_group_index += 1;
-            render_pass.set_bind_group(bind_group_index, &self.model_bind_group, &[]);
+        }
 
-            if let Some(material_component) = entity.get_component::<MaterialComponent>() {
-                bind_group_index += 1;
-                render_pass.set_bind_group(bind_group_index, &material_component.bind_group, &[]);
-            }
+        let mut draws = 0;
+        if let Some(mesh_component) = entity.get_component::<MeshComponent>() {
+            render_pass.set_vertex_buffer(4, self.instance_buffer.slice(..));
+            mesh_component.draw(render_pass, instances.clone());
+            draws += 1;
+        }
 
-            if let Some(mesh_component) = entity.get_component::<MeshComponent>() {
-                render_pass.set_vertex_buffer(0, mesh_component.vertex_buffer.slice(..));
-                render_pass.draw(0..mesh_component.vertex_count as u32, 0..1);
+        if let Some(sub_mesh_component) = entity.get_component::<SubMeshComponent>() {
+            for sub_mesh in sub_mesh_component.sub_meshes.iter() {
+                render_pass.set_pipeline(self.material_render_pipeline(&sub_mesh.material));
+                render_pass.set_bind_group(
+                    material_bind_group_index,
+                    &sub_mesh.material.bind_group,
+                    &[],
+                );
+                render_pass.set_vertex_buffer(4, self.instance_buffer.slice(..));
+                sub_mesh.mesh.draw(render_pass, instances.clone());
+                draws += 1;
             }
         }
+
+        draws
+    }
+
+    pub fn depth_bias(&self) -> wgpu::DepthBiasState {
+        self.depth_bias
+    }
+
+    fn material_render_pipeline(
+        &self,
+        material_component: &MaterialComponent,
+    ) -> &wgpu::RenderPipeline {
+        match material_component.mode() {
+            MaterialMode::Rgba => &self.render_pipeline,
+            MaterialMode::Indexed => &self.indexed_render_pipeline,
+        }
+    }
+
+    fn model_uniform(entity: &Entity) -> ModelUniform {
+        let mut model_matrix: [[f32; 4]; 4] = Matrix4::identity().into();
+        if let Some(transform_component) = entity.get_component::<TransformComponent>() {
+            model_matrix = transform_component.transform_matrix().into();
+        }
+
+        let mut tint = [1f32; 4];
+        if let Some(render_state_component) = entity.get_component::<RenderStateComponent>() {
+            tint = render_state_component.tint_uniform();
+        }
+
+        let liquid = entity.get_component::<LiquidComponent>().is_some() as u32;
+        let fence = entity.get_component::<FenceComponent>().is_some() as u32;
+
+        ModelUniform {
+            model_matrix,
+            tint,
+            flags: [liquid, fence, 0, 0],
+        }
+    }
+
+    // Uploaded once per frame, time is in seconds.
+    pub fn update_frame_uniform(
+        &self,
+        queue: &wgpu::Queue,
+        time: f32,
+        liquid_warp: bool,
+        liquid_warp_speed: f32,
+    ) {
+        let uniform = FrameUniform {
+            time,
+            liquid_warp_speed,
+            liquid_warp: liquid_warp as u32,
+            padding: 0,
+        };
+        queue.write_buffer(&self.frame_uniform_buffer, 0, bytemuck::bytes_of(&uniform));
+    }
+
+    pub fn clear_color(&self) -> wgpu::Color {
+        self.clear_color
+    }
+
+    // Color of the albedo target where no entity is drawn.
+    pub fn set_clear_color(&mut self, clear_color: wgpu::Color) {
+        self.clear_color = clear_color;
     }
 
     fn create_render_pass_color_attachment<'a>(
         view: &'a wgpu::TextureView,
+        clear_color: wgpu::Color,
     ) -> wgpu::RenderPassColorAttachment {
         wgpu::RenderPassColorAttachment {
             view,
             resolve_target: None,
             ops: wgpu::Operations {
-                load: wgpu::LoadOp::Clear(wgpu::Color::BLUE),
+                load: wgpu::LoadOp::Clear(clear_color),
                 store: true,
             },
         }
@@ -176,6 +541,52 @@ impl AliasPipeline {
         }
     }
 
+    // Rebuilds the render targets at the surface size, the views handed out
+    // before are left dangling and must be fetched again.
+    pub fn resize(&mut self, device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) {
+        [
+            (self.albedo_texture, self.albedo_view),
+            (self.normal_texture, self.normal_view),
+            (self.depth_texture, self.depth_view),
+            (self.viewmodel_depth_texture, self.viewmodel_depth_view),
+        ] = Self::create_attachments(device, config);
+    }
+
+    // Albedo, normal, depth and viewmodel depth targets.
+    fn create_attachments(
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+    ) -> [(wgpu::Texture, wgpu::TextureView); 4] {
+        let target_size = wgpu::Extent3d {
+            width: config.width,
+            height: config.height,
+            depth_or_array_layers: 1,
+        };
+
+        let albedo_texture = Self::create_attachment_texture(device, config.format, target_size);
+        let albedo_view = albedo_texture.create_view(&Default::default());
+
+        let normal_texture = Self::create_attachment_texture(device, config.format, target_size);
+        let normal_view = normal_texture.create_view(&Default::default());
+
+        let depth_texture =
+            Self::create_attachment_texture(device, wgpu::TextureFormat::Depth32Float, target_size);
+        let depth_view = depth_texture.create_view(&Default::default());
+
+        // The viewmodel is drawn over a depth buffer of its own so the scene
+        // depth is left intact for the passes that sample it.
+        let viewmodel_depth_texture =
+            Self::create_attachment_texture(device, wgpu::TextureFormat::Depth32Float, target_size);
+        let viewmodel_depth_view = viewmodel_depth_texture.create_view(&Default::default());
+
+        [
+            (albedo_texture, albedo_view),
+            (normal_texture, normal_view),
+            (depth_texture, depth_view),
+            (viewmodel_depth_texture, viewmodel_depth_view),
+        ]
+    }
+
     fn create_attachment_texture(
         device: &wgpu::Device,
         format: wgpu::TextureFormat,
@@ -196,27 +607,52 @@ impl AliasPipeline {
     fn create_model_bind_group(
         device: &wgpu::Device,
         buffer: &wgpu::Buffer,
+        frame_buffer: &wgpu::Buffer,
     ) -> (wgpu::BindGroup, wgpu::BindGroupLayout) {
         let model_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                entries: &[wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::VERTEX,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: true,
+                            min_binding_size: wgpu::BufferSize::new(
+                                std::mem::size_of::<ModelUniform>() as u64,
+                            ),
+                        },
+                        count: None,
                     },
-                    count: None,
-                }],
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                ],
                 label: None,
             });
         let model_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             layout: &model_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: buffer.as_entire_binding(),
-            }],
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                        buffer,
+                        offset: 0,
+                        size: wgpu::BufferSize::new(std::mem::size_of::<ModelUniform>() as u64),
+                    }),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: frame_buffer.as_entire_binding(),
+                },
+            ],
             label: None,
         });
 
@@ -227,14 +663,25 @@ impl AliasPipeline {
         device: &wgpu::Device,
         format: wgpu::TextureFormat,
         bind_group_layouts: &'a [&'a wgpu::BindGroupLayout],
+        shader: wgpu::ShaderModuleDescriptor,
+        depth_bias: wgpu::DepthBiasState,
     ) -> wgpu::RenderPipeline {
-        let shader = device.create_shader_module(wgpu::include_wgsl!("alias.wgsl"));
+        let shader = device.create_shader_module(shader);
         let render_pipeline_layout =
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: None,
                 bind_group_layouts,
                 push_constant_ranges: &[],
             });
+        let [vertex, current_frame, next_frame, frame_blend] =
+            MeshComponent::vertex_buffer_layouts();
+        let vertex_buffer_layouts = [
+            vertex,
+            current_frame,
+            next_frame,
+            frame_blend,
+            InstanceRaw::desc(),
+        ];
 
         device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
             label: None,
@@ -242,7 +689,7 @@ impl AliasPipeline {
             vertex: wgpu::VertexState {
                 module: &shader,
                 entry_point: "vs_main",
-                buffers: &[Vertex::desc()],
+                buffers: &vertex_buffer_layouts,
             },
             fragment: Some(wgpu::FragmentState {
                 module: &shader,
@@ -274,7 +721,7 @@ impl AliasPipeline {
                 depth_write_enabled: true,
                 depth_compare: wgpu::CompareFunction::LessEqual,
                 stencil: wgpu::StencilState::default(),
-                bias: wgpu::DepthBiasState::default(),
+                bias: depth_bias,
             }),
             multisample: wgpu::MultisampleState {
                 count: 1,
@@ -306,13 +753,99 @@ impl Vertex1XY1UV {
     }
 }
 
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum FilterQuality {
+    Nearest,
+    #[default]
+    Trilinear,
+}
+
+impl FilterQuality {
+    fn filter_mode(&self) -> wgpu::FilterMode {
+        match self {
+            FilterQuality::Nearest => wgpu::FilterMode::Nearest,
+            FilterQuality::Trilinear => wgpu::FilterMode::Linear,
+        }
+    }
+}
+
+// wgpu samplers have no LOD bias, so it is approximated with the LOD clamps:
+// a positive bias skips the finest mips and a negative one the coarsest.
+pub fn lod_clamps(lod_bias: f32, mip_level_count: u32) -> (f32, f32) {
+    let max_lod = mip_level_count.saturating_sub(1) as f32;
+    (
+        lod_bias.clamp(0.0, max_lod),
+        (max_lod + lod_bias.min(0.0)).max(0.0),
+    )
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct LightUniform {
+    inverse_view_projection_matrix: [[f32; 4]; 4],
+    eye: [f32; 4],
+    // Direction the light travels, in engine coordinates.
+    direction: [f32; 4],
+    // a is the strength of the specular highlight.
+    color: [f32; 4],
+}
+
+impl Default for LightUniform {
+    fn default() -> Self {
+        Self {
+            inverse_view_projection_matrix: Matrix4::identity().into(),
+            eye: [0.0; 4],
+            direction: [0.0, -1.0, 0.0, 0.0],
+            color: [0.0; 4],
+        }
+    }
+}
+
+// A point light, position is in Quake coordinates. Its contribution fades
+// linearly to nothing at radius, like the dynamic lights of the original
+// engine.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Light {
+    pub position: Vector3<f32>,
+    pub color: [f32; 3],
+    pub radius: f32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, bytemuck::Pod, bytemuck::Zeroable)]
+struct PointLightRaw {
+    // w is the radius.
+    position: [f32; 4],
+    color: [f32; 4],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct PointLightsUniform {
+    // x is the number of lights in use.
+    count: [u32; 4],
+    lights: [PointLightRaw; TargetPipeline::MAX_LIGHTS],
+}
+
 pub struct TargetPipeline {
     target_vertex_buffer: wgpu::Buffer,
+    screen_tint_buffer: wgpu::Buffer,
+    ambient_buffer: wgpu::Buffer,
+    ambient: Cell<[f32; 3]>,
+    light: Cell<LightUniform>,
+    light_buffer: wgpu::Buffer,
+    point_lights_buffer: wgpu::Buffer,
+    target_sampler: wgpu::Sampler,
+    target_bind_group_layout: wgpu::BindGroupLayout,
     target_bind_group: wgpu::BindGroup,
     render_pipeline: wgpu::RenderPipeline,
 }
 
 impl TargetPipeline {
+    // Point lights live in a fixed size uniform array, lights past this count
+    // are not drawn. Must match MAX_LIGHTS in target.wgsl.
+    pub const MAX_LIGHTS: usize = 32;
+
     const TARGET_VERTICES: [Vertex1XY1UV; 6] = [
         Vertex1XY1UV {
             position: [0.0, 0.0],
@@ -346,28 +879,174 @@ impl TargetPipeline {
         albedo_view: &'a wgpu::TextureView,
         normal_view: &'a wgpu::TextureView,
         depth_view: &'a wgpu::TextureView,
+        filter_quality: FilterQuality,
     ) -> Self {
         let target_vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: None,
             contents: bytemuck::cast_slice(&Self::TARGET_VERTICES),
             usage: wgpu::BufferUsages::VERTEX,
         });
-        let (target_bind_group, target_bind_group_layout) =
-            Self::create_target_bind_group(device, albedo_view, normal_view, depth_view);
+        let screen_tint_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::cast_slice(&[0f32; 4]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let ambient_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::cast_slice(&[1f32; 4]),
+            usage: wgpu::BufferUsages::UNIFORM
+                | wgpu::BufferUsages::COPY_DST
+                | wgpu::BufferUsages::COPY_SRC,
+        });
+        let light = LightUniform::default();
+        let light_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::bytes_of(&light),
+            usage: wgpu::BufferUsages::UNIFORM
+                | wgpu::BufferUsages::COPY_DST
+                | wgpu::BufferUsages::COPY_SRC,
+        });
+        let point_lights_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: std::mem::size_of::<PointLightsUniform>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM
+                | wgpu::BufferUsages::COPY_DST
+                | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        // The targets have a single level sampled at screen size, so the LOD
+        // bias only applies to the materials.
+        let target_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: filter_quality.filter_mode(),
+            min_filter: filter_quality.filter_mode(),
+            mipmap_filter: filter_quality.filter_mode(),
+            ..Default::default()
+        });
+        let target_bind_group_layout = Self::create_target_bind_group_layout(device);
+        let target_bind_group = Self::create_target_bind_group(
+            device,
+            &target_bind_group_layout,
+            albedo_view,
+            normal_view,
+            depth_view,
+            [
+                &screen_tint_buffer,
+                &ambient_buffer,
+                &light_buffer,
+                &point_lights_buffer,
+            ],
+            &target_sampler,
+        );
         let render_pipeline =
             Self::create_render_pipeline(device, config.format, &[&target_bind_group_layout]);
 
         Self {
             target_vertex_buffer,
+            screen_tint_buffer,
+            ambient_buffer,
+            ambient: Cell::new([1.0; 3]),
+            light: Cell::new(light),
+            light_buffer,
+            point_lights_buffer,
+            target_sampler,
+            target_bind_group_layout,
             target_bind_group,
             render_pipeline,
         }
     }
 
-    pub fn render_pass<'a>(&self, encoder: &mut wgpu::CommandEncoder, view: &'a wgpu::TextureView) {
-        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-            label: None,
-            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+    pub fn set_screen_tint(&self, queue: &wgpu::Queue, tint: [f32; 4]) {
+        queue.write_buffer(&self.screen_tint_buffer, 0, bytemuck::cast_slice(&tint));
+    }
+
+    pub fn set_ambient(&self, queue: &wgpu::Queue, ambient: [f32; 3]) {
+        self.ambient.set(ambient);
+        queue.write_buffer(
+            &self.ambient_buffer,
+            0,
+            bytemuck::cast_slice(&[ambient[0], ambient[1], ambient[2], 1.0]),
+        );
+    }
+
+    // A single directional light shading the GBuffer, added to the ambient
+    // floor. Direction is where the light travels, in Quake coordinates, and
+    // a black color turns it off. Specular scales a highlight that follows
+    // the view, Quake has none.
+    pub fn set_directional_light(
+        &self,
+        queue: &wgpu::Queue,
+        direction: Vector3<f32>,
+        color: [f32; 3],
+        specular: f32,
+    ) {
+        let direction = transform::from_quake_coords(direction).normalize();
+        let mut light = self.light.get();
+        light.direction = [direction.x, direction.y, direction.z, 0.0];
+        light.color = [color[0], color[1], color[2], specular];
+        self.light.set(light);
+        queue.write_buffer(&self.light_buffer, 0, bytemuck::bytes_of(&light));
+    }
+
+    // Takes the ambient and directional light of another pipeline, e.g. the
+    // one replaced when the renderer is recreated.
+    pub fn copy_lighting(&self, queue: &wgpu::Queue, other: &TargetPipeline) {
+        self.set_ambient(queue, other.ambient.get());
+        let mut light = self.light.get();
+        light.direction = other.light.get().direction;
+        light.color = other.light.get().color;
+        self.light.set(light);
+        queue.write_buffer(&self.light_buffer, 0, bytemuck::bytes_of(&light));
+    }
+
+    // Replaces the point lights, meant to be called every frame. No lights
+    // leaves only the ambient and directional lighting.
+    pub fn set_lights(&self, queue: &wgpu::Queue, lights: &[Light]) {
+        let lights = if lights.len() > Self::MAX_LIGHTS {
+            warn!(
+                "Too many lights: {}, only {} will be rendered",
+                lights.len(),
+                Self::MAX_LIGHTS
+            );
+            &lights[..Self::MAX_LIGHTS]
+        } else {
+            lights
+        };
+
+        let mut uniform = PointLightsUniform {
+            count: [lights.len() as u32, 0, 0, 0],
+            lights: [PointLightRaw::default(); Self::MAX_LIGHTS],
+        };
+        for (light, raw) in lights.iter().zip(uniform.lights.iter_mut()) {
+            let position = transform::from_quake_coords(light.position);
+            *raw = PointLightRaw {
+                position: [position.x, position.y, position.z, light.radius],
+                color: [light.color[0], light.color[1], light.color[2], 1.0],
+            };
+        }
+        queue.write_buffer(&self.point_lights_buffer, 0, bytemuck::bytes_of(&uniform));
+    }
+
+    // World positions are rebuilt from the depth buffer with the inverse of
+    // the camera view projection, uploaded once per frame.
+    pub fn update_camera(&self, queue: &wgpu::Queue, camera: &Camera) {
+        let mut light = self.light.get();
+        light.inverse_view_projection_matrix = camera
+            .view_projection_matrix()
+            .invert()
+            .unwrap_or(Matrix4::identity())
+            .into();
+        light.eye = [camera.eye.x, camera.eye.y, camera.eye.z, 1.0];
+        self.light.set(light);
+        queue.write_buffer(&self.light_buffer, 0, bytemuck::bytes_of(&light));
+    }
+
+    pub fn render_pass<'a>(&self, encoder: &mut wgpu::CommandEncoder, view: &'a wgpu::TextureView) {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: None,
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
                 view,
                 resolve_target: None,
                 ops: wgpu::Operations {
@@ -383,66 +1062,45 @@ impl TargetPipeline {
         render_pass.draw(0..6, 0..1);
     }
 
-    fn create_target_bind_group<'a>(
+    // The GBuffer views change when AliasPipeline::resize rebuilds them.
+    pub fn resize<'a>(
+        &mut self,
         device: &wgpu::Device,
         albedo_view: &'a wgpu::TextureView,
         normal_view: &'a wgpu::TextureView,
         depth_view: &'a wgpu::TextureView,
-    ) -> (wgpu::BindGroup, wgpu::BindGroupLayout) {
-        let target_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                entries: &[
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 0,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Texture {
-                            multisampled: false,
-                            view_dimension: wgpu::TextureViewDimension::D2,
-                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                        },
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 1,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Texture {
-                            multisampled: false,
-                            view_dimension: wgpu::TextureViewDimension::D2,
-                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                        },
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 2,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Texture {
-                            multisampled: false,
-                            view_dimension: wgpu::TextureViewDimension::D2,
-                            sample_type: wgpu::TextureSampleType::Depth,
-                        },
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 3,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                        count: None,
-                    },
-                ],
-                label: None,
-            });
+    ) {
+        self.target_bind_group = Self::create_target_bind_group(
+            device,
+            &self.target_bind_group_layout,
+            albedo_view,
+            normal_view,
+            depth_view,
+            [
+                &self.screen_tint_buffer,
+                &self.ambient_buffer,
+                &self.light_buffer,
+                &self.point_lights_buffer,
+            ],
+            &self.target_sampler,
+        );
+    }
 
-        let target_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
-            address_mode_u: wgpu::AddressMode::ClampToEdge,
-            address_mode_v: wgpu::AddressMode::ClampToEdge,
-            address_mode_w: wgpu::AddressMode::ClampToEdge,
-            mag_filter: wgpu::FilterMode::Linear,
-            min_filter: wgpu::FilterMode::Nearest,
-            mipmap_filter: wgpu::FilterMode::Nearest,
-            ..Default::default()
-        });
-        let target_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &target_bind_group_layout,
+    fn create_target_bind_group<'a>(
+        device: &wgpu::Device,
+        target_bind_group_layout: &wgpu::BindGroupLayout,
+        albedo_view: &'a wgpu::TextureView,
+        normal_view: &'a wgpu::TextureView,
+        depth_view: &'a wgpu::TextureView,
+        // Screen tint, ambient, directional light and point lights, bound
+        // after the sampler.
+        uniform_buffers: [&wgpu::Buffer; 4],
+        target_sampler: &wgpu::Sampler,
+    ) -> wgpu::BindGroup {
+        let [screen_tint_buffer, ambient_buffer, light_buffer, point_lights_buffer] =
+            uniform_buffers;
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: target_bind_group_layout,
             entries: &[
                 wgpu::BindGroupEntry {
                     binding: 0,
@@ -458,13 +1116,111 @@ impl TargetPipeline {
                 },
                 wgpu::BindGroupEntry {
                     binding: 3,
-                    resource: wgpu::BindingResource::Sampler(&target_sampler),
+                    resource: wgpu::BindingResource::Sampler(target_sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: screen_tint_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: ambient_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: light_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 7,
+                    resource: point_lights_buffer.as_entire_binding(),
                 },
             ],
             label: None,
-        });
+        })
+    }
 
-        (target_bind_group, target_bind_group_layout)
+    fn create_target_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Depth,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 6,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 7,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+            label: None,
+        })
     }
 
     fn create_render_pipeline<'a>(
@@ -516,3 +1272,972 @@ impl TargetPipeline {
         })
     }
 }
+
+pub struct ScreenPipeline {
+    pub texture_bind_group_layout: wgpu::BindGroupLayout,
+
+    screen_vertex_buffer: wgpu::Buffer,
+    render_pipeline: wgpu::RenderPipeline,
+}
+
+impl ScreenPipeline {
+    pub fn new(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) -> Self {
+        let screen_vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::cast_slice(&TargetPipeline::TARGET_VERTICES),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+
+        let texture_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                ],
+                label: None,
+            });
+        let render_pipeline =
+            Self::create_render_pipeline(device, config.format, &[&texture_bind_group_layout]);
+
+        Self {
+            texture_bind_group_layout,
+
+            screen_vertex_buffer,
+            render_pipeline,
+        }
+    }
+
+    pub fn render_pass<'a>(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &'a wgpu::TextureView,
+        bind_group: &'a wgpu::BindGroup,
+    ) {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: None,
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: None,
+        });
+        render_pass.set_pipeline(&self.render_pipeline);
+        render_pass.set_bind_group(0, bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.screen_vertex_buffer.slice(..));
+        render_pass.draw(0..6, 0..1);
+    }
+
+    fn create_render_pipeline<'a>(
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+        bind_group_layouts: &'a [&'a wgpu::BindGroupLayout],
+    ) -> wgpu::RenderPipeline {
+        let screen_shader = device.create_shader_module(wgpu::include_wgsl!("screen.wgsl"));
+        let screen_render_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: None,
+                bind_group_layouts,
+                push_constant_ranges: &[],
+            });
+
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: None,
+            layout: Some(&screen_render_pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &screen_shader,
+                entry_point: "vs_main",
+                buffers: &[Vertex1XY1UV::desc()],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &screen_shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Cw,
+                cull_mode: Some(wgpu::Face::Back),
+                polygon_mode: wgpu::PolygonMode::Fill,
+                unclipped_depth: false,
+                conservative: false,
+            },
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState {
+                count: 1,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            multiview: None,
+        })
+    }
+}
+
+pub struct SkyPipeline {
+    inverse_rotation_projection_matrix_buffer: wgpu::Buffer,
+    sky_bind_group_layout: wgpu::BindGroupLayout,
+    cubemap: Option<(wgpu::Texture, wgpu::BindGroup)>,
+    render_pipeline: wgpu::RenderPipeline,
+}
+
+impl SkyPipeline {
+    const CUBEMAP_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+    pub fn new(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) -> Self {
+        let inverse_rotation_projection_matrix: [[f32; 4]; 4] = Matrix4::identity().into();
+        let inverse_rotation_projection_matrix_buffer =
+            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: None,
+                contents: bytemuck::cast_slice(&[inverse_rotation_projection_matrix]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            });
+
+        let sky_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::Cube,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                ],
+                label: None,
+            });
+        let render_pipeline =
+            Self::create_render_pipeline(device, config.format, &[&sky_bind_group_layout]);
+
+        Self {
+            inverse_rotation_projection_matrix_buffer,
+            sky_bind_group_layout,
+            cubemap: None,
+            render_pipeline,
+        }
+    }
+
+    // Faces are expected in wgpu cube layer order, +X, -X, +Y, -Y, +Z, -Z,
+    // all sharing the same square size.
+    // One layer per face, viewed as a cube.
+    pub fn cubemap_descriptor(size: u32) -> wgpu::TextureDescriptor<'static> {
+        wgpu::TextureDescriptor {
+            label: None,
+            size: wgpu::Extent3d {
+                width: size,
+                height: size,
+                depth_or_array_layers: 6,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Self::CUBEMAP_FORMAT,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        }
+    }
+
+    pub fn set_cubemap(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        size: u32,
+        faces: &[Box<[u8]>; 6],
+    ) {
+        let texture = device.create_texture(&Self::cubemap_descriptor(size));
+        for (layer, face) in faces.iter().enumerate() {
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    texture: &texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d {
+                        x: 0,
+                        y: 0,
+                        z: layer as u32,
+                    },
+                    aspect: wgpu::TextureAspect::All,
+                },
+                face,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(4 * size),
+                    rows_per_image: Some(size),
+                },
+                wgpu::Extent3d {
+                    width: size,
+                    height: size,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::Cube),
+            ..Default::default()
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+        let sky_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.sky_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: self
+                        .inverse_rotation_projection_matrix_buffer
+                        .as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&sampler),
+                },
+            ],
+            label: None,
+        });
+
+        self.cubemap = Some((texture, sky_bind_group));
+    }
+
+    pub fn clear_cubemap(&mut self) {
+        self.cubemap = None;
+    }
+
+    pub fn render_pass<'a>(
+        &self,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        albedo_view: &'a wgpu::TextureView,
+        depth_view: &'a wgpu::TextureView,
+        rotation_projection_matrix: Matrix4<f32>,
+    ) {
+        let sky_bind_group = match &self.cubemap {
+            Some((_, sky_bind_group)) => sky_bind_group,
+            None => return,
+        };
+
+        let inverse_rotation_projection_matrix: [[f32; 4]; 4] = rotation_projection_matrix
+            .invert()
+            .unwrap_or(Matrix4::identity())
+            .into();
+        queue.write_buffer(
+            &self.inverse_rotation_projection_matrix_buffer,
+            0,
+            bytemuck::cast_slice(&[inverse_rotation_projection_matrix]),
+        );
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: None,
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: albedo_view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: depth_view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+        render_pass.set_pipeline(&self.render_pipeline);
+        render_pass.set_bind_group(0, sky_bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+
+    fn create_render_pipeline<'a>(
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+        bind_group_layouts: &'a [&'a wgpu::BindGroupLayout],
+    ) -> wgpu::RenderPipeline {
+        let sky_shader = device.create_shader_module(wgpu::include_wgsl!("sky.wgsl"));
+        let sky_render_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: None,
+                bind_group_layouts,
+                push_constant_ranges: &[],
+            });
+
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: None,
+            layout: Some(&sky_render_pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &sky_shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &sky_shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: Some(wgpu::BlendState::REPLACE),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Cw,
+                cull_mode: None,
+                polygon_mode: wgpu::PolygonMode::Fill,
+                unclipped_depth: false,
+                conservative: false,
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: wgpu::TextureFormat::Depth32Float,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::LessEqual,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState {
+                count: 1,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            multiview: None,
+        })
+    }
+}
+
+pub struct ParticlePipeline {
+    particle_vertex_buffer: wgpu::Buffer,
+    render_pipeline: wgpu::RenderPipeline,
+}
+
+impl ParticlePipeline {
+    const MAX_VERTICES: usize = ParticleSystem::MAX_PARTICLES * 6;
+
+    pub fn new<'a>(
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+        bind_group_layouts: &'a [&'a wgpu::BindGroupLayout],
+    ) -> Self {
+        let particle_vertex_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: (std::mem::size_of::<Vertex1XYZ1UV1RGBA>() * Self::MAX_VERTICES)
+                as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let render_pipeline =
+            Self::create_render_pipeline(device, config.format, bind_group_layouts);
+
+        Self {
+            particle_vertex_buffer,
+            render_pipeline,
+        }
+    }
+
+    pub fn render_pass<'a>(
+        &self,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        albedo_view: &'a wgpu::TextureView,
+        depth_view: &'a wgpu::TextureView,
+        bind_groups: &'a [&'a wgpu::BindGroup],
+        particles: &ParticleSystem,
+    ) {
+        let vertices = particles.vertices();
+        if vertices.is_empty() {
+            return;
+        }
+        queue.write_buffer(
+            &self.particle_vertex_buffer,
+            0,
+            bytemuck::cast_slice(&vertices),
+        );
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: None,
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: albedo_view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: depth_view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+        render_pass.set_pipeline(&self.render_pipeline);
+        for (i, bind_group) in bind_groups.iter().enumerate() {
+            render_pass.set_bind_group(i as u32, bind_group, &[]);
+        }
+        render_pass.set_vertex_buffer(0, self.particle_vertex_buffer.slice(..));
+        render_pass.draw(0..vertices.len() as u32, 0..1);
+    }
+
+    fn create_render_pipeline<'a>(
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+        bind_group_layouts: &'a [&'a wgpu::BindGroupLayout],
+    ) -> wgpu::RenderPipeline {
+        let particle_shader = device.create_shader_module(wgpu::include_wgsl!("particle.wgsl"));
+        let particle_render_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: None,
+                bind_group_layouts,
+                push_constant_ranges: &[],
+            });
+
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: None,
+            layout: Some(&particle_render_pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &particle_shader,
+                entry_point: "vs_main",
+                buffers: &[Vertex1XYZ1UV1RGBA::desc()],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &particle_shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: Some(wgpu::BlendState {
+                        color: wgpu::BlendComponent {
+                            src_factor: wgpu::BlendFactor::One,
+                            dst_factor: wgpu::BlendFactor::One,
+                            operation: wgpu::BlendOperation::Add,
+                        },
+                        alpha: wgpu::BlendComponent::OVER,
+                    }),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Cw,
+                cull_mode: None,
+                polygon_mode: wgpu::PolygonMode::Fill,
+                unclipped_depth: false,
+                conservative: false,
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: wgpu::TextureFormat::Depth32Float,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::LessEqual,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState {
+                count: 1,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            multiview: None,
+        })
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct ShadowUniform {
+    model_matrix: [[f32; 4]; 4],
+    floor_distance: [f32; 4],
+}
+
+pub struct ShadowPipeline {
+    shadow_uniform_buffer: wgpu::Buffer,
+    shadow_uniform_stride: wgpu::BufferAddress,
+    shadow_bind_group: wgpu::BindGroup,
+    depth_bias: wgpu::DepthBiasState,
+    render_pipeline: wgpu::RenderPipeline,
+}
+
+impl ShadowPipeline {
+    // Pulls the shadow towards the camera so it wins the depth test against
+    // the floor it lies on.
+    pub const DEPTH_BIAS: wgpu::DepthBiasState = wgpu::DepthBiasState {
+        constant: -2,
+        slope_scale: -1.0,
+        clamp: 0.0,
+    };
+
+    pub fn new<'a>(
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+        bind_group_layouts: &'a [&'a wgpu::BindGroupLayout],
+        depth_bias: wgpu::DepthBiasState,
+    ) -> Self {
+        let shadow_uniform_size = std::mem::size_of::<ShadowUniform>() as wgpu::BufferAddress;
+        let shadow_uniform_alignment =
+            device.limits().min_uniform_buffer_offset_alignment as wgpu::BufferAddress;
+        let shadow_uniform_stride =
+            shadow_uniform_size.div_ceil(shadow_uniform_alignment) * shadow_uniform_alignment;
+        let shadow_uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: shadow_uniform_stride * AliasPipeline::MAX_ENTITIES as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let shadow_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: true,
+                        min_binding_size: wgpu::BufferSize::new(shadow_uniform_size),
+                    },
+                    count: None,
+                }],
+                label: None,
+            });
+        let shadow_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &shadow_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: &shadow_uniform_buffer,
+                    offset: 0,
+                    size: wgpu::BufferSize::new(shadow_uniform_size),
+                }),
+            }],
+            label: None,
+        });
+
+        let mut chained_bind_group_layouts = bind_group_layouts.to_vec();
+        chained_bind_group_layouts.push(&shadow_bind_group_layout);
+        let render_pipeline = Self::create_render_pipeline(
+            device,
+            config.format,
+            &chained_bind_group_layouts,
+            depth_bias,
+        );
+
+        Self {
+            shadow_uniform_buffer,
+            shadow_uniform_stride,
+            shadow_bind_group,
+            depth_bias,
+            render_pipeline,
+        }
+    }
+
+    pub fn depth_bias(&self) -> wgpu::DepthBiasState {
+        self.depth_bias
+    }
+
+    pub fn render_pass<'a>(
+        &self,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        albedo_view: &'a wgpu::TextureView,
+        depth_view: &'a wgpu::TextureView,
+        bind_groups: &'a [&'a wgpu::BindGroup],
+        entities: &[&Entity],
+    ) -> usize {
+        let shadows: Vec<(&MeshComponent, ShadowUniform)> = entities
+            .iter()
+            .take(AliasPipeline::MAX_ENTITIES)
+            .filter_map(|entity| {
+                let floor_distance = entity.get_component::<ShadowComponent>()?.floor_distance?;
+                let mesh_component = entity.get_component::<MeshComponent>()?;
+                let model_matrix = match entity.get_component::<TransformComponent>() {
+                    Some(transform_component) => transform_component.transform_matrix(),
+                    None => Matrix4::identity(),
+                };
+
+                Some((
+                    mesh_component,
+                    ShadowUniform {
+                        model_matrix: model_matrix.into(),
+                        floor_distance: [floor_distance, 0.0, 0.0, 0.0],
+                    },
+                ))
+            })
+            .collect();
+        if shadows.is_empty() {
+            return 0;
+        }
+
+        let mut shadow_uniforms = vec![0u8; shadows.len() * self.shadow_uniform_stride as usize];
+        for ((_, uniform), shadow_uniform) in shadows
+            .iter()
+            .zip(shadow_uniforms.chunks_mut(self.shadow_uniform_stride as usize))
+        {
+            shadow_uniform[..std::mem::size_of::<ShadowUniform>()]
+                .copy_from_slice(bytemuck::bytes_of(uniform));
+        }
+        queue.write_buffer(&self.shadow_uniform_buffer, 0, &shadow_uniforms);
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: None,
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: albedo_view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: depth_view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+        render_pass.set_pipeline(&self.render_pipeline);
+        for (i, bind_group) in bind_groups.iter().enumerate() {
+            render_pass.set_bind_group(i as u32, bind_group, &[]);
+        }
+
+        for (i, (mesh_component, _)) in shadows.iter().enumerate() {
+            let shadow_uniform_offset =
+                i as wgpu::DynamicOffset * self.shadow_uniform_stride as wgpu::DynamicOffset;
+            render_pass.set_bind_group(
+                bind_groups.len() as u32,
+                &self.shadow_bind_group,
+                &[shadow_uniform_offset],
+            );
+            mesh_component.draw(&mut render_pass, 0..1);
+        }
+
+        shadows.len()
+    }
+
+    fn create_render_pipeline<'a>(
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+        bind_group_layouts: &'a [&'a wgpu::BindGroupLayout],
+        depth_bias: wgpu::DepthBiasState,
+    ) -> wgpu::RenderPipeline {
+        let shadow_shader = device.create_shader_module(wgpu::include_wgsl!("shadow.wgsl"));
+        let shadow_render_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: None,
+                bind_group_layouts,
+                push_constant_ranges: &[],
+            });
+
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: None,
+            layout: Some(&shadow_render_pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shadow_shader,
+                entry_point: "vs_main",
+                buffers: &MeshComponent::vertex_buffer_layouts(),
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shadow_shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::COLOR,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Cw,
+                cull_mode: None,
+                polygon_mode: wgpu::PolygonMode::Fill,
+                unclipped_depth: false,
+                conservative: false,
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: wgpu::TextureFormat::Depth32Float,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::LessEqual,
+                stencil: wgpu::StencilState::default(),
+                bias: depth_bias,
+            }),
+            multisample: wgpu::MultisampleState {
+                count: 1,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            multiview: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        animation::KeyframeAnimationComponent,
+        mesh::SubMesh,
+        renderer::{
+            tests::{entity_pass_draws, headless_renderer, read_buffer, render_frame},
+            Renderer,
+        },
+    };
+
+    fn view_projection_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+            label: None,
+        })
+    }
+
+    fn sub_mesh(renderer: &Renderer) -> SubMesh {
+        SubMesh {
+            mesh: MeshComponent::new(renderer, 3),
+            material: MaterialComponent::new(
+                renderer,
+                &renderer.entity_render_pipeline.texture_bind_group_layout,
+                4,
+                4,
+            ),
+            animation: KeyframeAnimationComponent::new(),
+        }
+    }
+
+    #[test]
+    fn filter_quality_maps_to_the_filter_mode() {
+        assert_eq!(
+            FilterQuality::Nearest.filter_mode(),
+            wgpu::FilterMode::Nearest
+        );
+        assert_eq!(
+            FilterQuality::Trilinear.filter_mode(),
+            wgpu::FilterMode::Linear
+        );
+        assert_eq!(FilterQuality::default(), FilterQuality::Trilinear);
+    }
+
+    #[test]
+    fn every_mesh_of_an_entity_is_drawn() {
+        let Some(renderer) = headless_renderer() else {
+            return;
+        };
+        let mut sub_mesh_component = SubMeshComponent::new();
+        sub_mesh_component.sub_meshes.push(sub_mesh(&renderer));
+        sub_mesh_component.sub_meshes.push(sub_mesh(&renderer));
+        let mut entity = Entity::new();
+        entity.add_component(sub_mesh_component);
+        assert_eq!(entity_pass_draws(&renderer, &[&entity]), 2);
+
+        entity.add_component(MeshComponent::new(&renderer, 3));
+        entity.add_component(MaterialComponent::new(
+            &renderer,
+            &renderer.entity_render_pipeline.texture_bind_group_layout,
+            4,
+            4,
+        ));
+        assert_eq!(entity_pass_draws(&renderer, &[&entity]), 3);
+        assert_eq!(entity_pass_draws(&renderer, &[&entity, &entity]), 6);
+    }
+
+    #[test]
+    fn pipelines_keep_the_requested_depth_bias() {
+        let Some(renderer) = headless_renderer() else {
+            return;
+        };
+        assert_eq!(
+            renderer.entity_render_pipeline.depth_bias(),
+            wgpu::DepthBiasState::default()
+        );
+
+        let bind_group_layout = view_projection_bind_group_layout(&renderer.device);
+        let depth_bias = wgpu::DepthBiasState {
+            constant: 4,
+            slope_scale: 1.5,
+            clamp: 0.0,
+        };
+        let alias_pipeline = AliasPipeline::new(
+            &renderer.device,
+            &renderer.config,
+            &[&bind_group_layout],
+            depth_bias,
+        );
+        assert_eq!(alias_pipeline.depth_bias(), depth_bias);
+        let shadow_pipeline = ShadowPipeline::new(
+            &renderer.device,
+            &renderer.config,
+            &[&bind_group_layout],
+            ShadowPipeline::DEPTH_BIAS,
+        );
+        assert_eq!(shadow_pipeline.depth_bias(), ShadowPipeline::DEPTH_BIAS);
+        // Negative values pull the shadow towards the camera.
+        assert!(shadow_pipeline.depth_bias().constant < 0);
+        assert!(shadow_pipeline.depth_bias().slope_scale < 0.0);
+    }
+
+    fn target_pipeline(renderer: &Renderer) -> TargetPipeline {
+        TargetPipeline::new(
+            &renderer.device,
+            &renderer.config,
+            &renderer.entity_render_pipeline.albedo_view,
+            &renderer.entity_render_pipeline.normal_view,
+            &renderer.entity_render_pipeline.depth_view,
+            FilterQuality::default(),
+        )
+    }
+
+    #[test]
+    fn ambient_is_uploaded_and_updated() {
+        let Some(renderer) = headless_renderer() else {
+            return;
+        };
+        let target_pipeline = target_pipeline(&renderer);
+        let ambient = |target_pipeline: &TargetPipeline| -> [f32; 4] {
+            bytemuck::pod_read_unaligned(&read_buffer(&renderer, &target_pipeline.ambient_buffer))
+        };
+        assert_eq!(ambient(&target_pipeline), [1.0; 4]);
+
+        target_pipeline.set_ambient(&renderer.queue, [0.25, 0.5, 0.75]);
+        assert_eq!(ambient(&target_pipeline), [0.25, 0.5, 0.75, 1.0]);
+        target_pipeline.set_ambient(&renderer.queue, [0.1, 0.1, 0.1]);
+        assert_eq!(ambient(&target_pipeline), [0.1, 0.1, 0.1, 1.0]);
+    }
+
+    #[test]
+    fn frame_uniform_advances_every_frame() {
+        let Some(mut renderer) = headless_renderer() else {
+            return;
+        };
+        renderer.set_liquid_warp(true, 2.0);
+        let frame_uniform = |renderer: &Renderer| -> FrameUniform {
+            bytemuck::pod_read_unaligned(&read_buffer(
+                renderer,
+                &renderer.entity_render_pipeline.frame_uniform_buffer,
+            ))
+        };
+
+        render_frame(&renderer, &[]);
+        let first = frame_uniform(&renderer);
+        assert_eq!(first.liquid_warp, 1);
+        assert_eq!(first.liquid_warp_speed, 2.0);
+
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        render_frame(&renderer, &[]);
+        let second = frame_uniform(&renderer);
+        assert!(second.time >= first.time + 0.01);
+    }
+
+    #[test]
+    fn no_lights_leave_only_the_albedo() {
+        let Some(renderer) = headless_renderer() else {
+            return;
+        };
+        let target_pipeline = target_pipeline(&renderer);
+        target_pipeline.set_lights(
+            &renderer.queue,
+            &[Light {
+                position: Vector3::new(0.0, 0.0, 64.0),
+                color: [1.0, 0.5, 0.0],
+                radius: 200.0,
+            }],
+        );
+        target_pipeline.set_lights(&renderer.queue, &[]);
+
+        // The shader multiplies the albedo by the ambient plus every light,
+        // so a unit ambient and nothing else lit keeps the albedo as is.
+        let ambient: [f32; 4] =
+            bytemuck::pod_read_unaligned(&read_buffer(&renderer, &target_pipeline.ambient_buffer));
+        assert_eq!(ambient, [1.0; 4]);
+        let light: LightUniform =
+            bytemuck::pod_read_unaligned(&read_buffer(&renderer, &target_pipeline.light_buffer));
+        assert_eq!(light.color, [0.0; 4]);
+        let point_lights: PointLightsUniform = bytemuck::pod_read_unaligned(&read_buffer(
+            &renderer,
+            &target_pipeline.point_lights_buffer,
+        ));
+        assert_eq!(point_lights.count[0], 0);
+    }
+
+    #[test]
+    fn tint_is_uploaded_with_the_model_matrix() {
+        let Some(renderer) = headless_renderer() else {
+            return;
+        };
+        let untinted = Entity::new();
+        let mut render_state_component = RenderStateComponent::new();
+        render_state_component.set_tint([1.0, 0.0, 0.0], 2.0);
+        let mut tinted = Entity::new();
+        tinted.add_component(render_state_component);
+        entity_pass_draws(&renderer, &[&untinted, &tinted]);
+
+        let pipeline = &renderer.entity_render_pipeline;
+        let model_uniforms = read_buffer(&renderer, &pipeline.model_uniform_buffer);
+        let model_uniform = |slot: usize| -> ModelUniform {
+            let offset = slot * pipeline.model_uniform_stride as usize;
+            bytemuck::pod_read_unaligned(
+                &model_uniforms[offset..offset + std::mem::size_of::<ModelUniform>()],
+            )
+        };
+        assert_eq!(model_uniform(0).tint, [1.0; 4]);
+        assert_eq!(model_uniform(1).tint, [1.0, 0.0, 0.0, 2.0]);
+    }
+}
@@ -0,0 +1,207 @@
+use std::{
+    cell::{Cell, RefCell},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RenderPass {
+    Entity = 0,
+    Target = 1,
+}
+
+impl RenderPass {
+    pub const COUNT: usize = 2;
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RenderPass::Entity => "entity",
+            RenderPass::Target => "target",
+        }
+    }
+}
+
+pub struct GpuProfiler {
+    query_set: wgpu::QuerySet,
+    resolve_buffer: wgpu::Buffer,
+    readback_buffer: wgpu::Buffer,
+    readback_ready: Arc<AtomicBool>,
+    readback_pending: Cell<bool>,
+    timestamp_period: f32,
+    pass_times: RefCell<[Duration; RenderPass::COUNT]>,
+}
+
+impl GpuProfiler {
+    const QUERY_COUNT: u32 = RenderPass::COUNT as u32 * 2;
+    const BUFFER_SIZE: wgpu::BufferAddress =
+        Self::QUERY_COUNT as wgpu::BufferAddress * wgpu::QUERY_SIZE as wgpu::BufferAddress;
+
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Option<Self> {
+        if !device.features().contains(wgpu::Features::TIMESTAMP_QUERY) {
+            debug!("Timestamp queries not supported, GPU pass times disabled");
+            return None;
+        }
+
+        let query_set = device.create_query_set(&wgpu::QuerySetDescriptor {
+            label: None,
+            ty: wgpu::QueryType::Timestamp,
+            count: Self::QUERY_COUNT,
+        });
+        let resolve_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: Self::BUFFER_SIZE,
+            usage: wgpu::BufferUsages::QUERY_RESOLVE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let readback_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: Self::BUFFER_SIZE,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        Some(Self {
+            query_set,
+            resolve_buffer,
+            readback_buffer,
+            readback_ready: Arc::new(AtomicBool::new(false)),
+            readback_pending: Cell::new(false),
+            timestamp_period: queue.get_timestamp_period(),
+            pass_times: RefCell::new([Duration::ZERO; RenderPass::COUNT]),
+        })
+    }
+
+    pub fn pass_times(&self) -> Vec<(&'static str, Duration)> {
+        let pass_times = self.pass_times.borrow();
+        [RenderPass::Entity, RenderPass::Target]
+            .iter()
+            .map(|pass| (pass.name(), pass_times[*pass as usize]))
+            .collect()
+    }
+
+    // Queries can only be recorded while the readback buffer is not mapped,
+    // so frames submitted while a readback is in flight are not measured.
+    pub fn is_recording(&self) -> bool {
+        !self.readback_pending.get()
+    }
+
+    pub fn begin_pass(&self, encoder: &mut wgpu::CommandEncoder, pass: RenderPass) {
+        if self.is_recording() {
+            encoder.write_timestamp(&self.query_set, pass as u32 * 2);
+        }
+    }
+
+    pub fn end_pass(&self, encoder: &mut wgpu::CommandEncoder, pass: RenderPass) {
+        if self.is_recording() {
+            encoder.write_timestamp(&self.query_set, pass as u32 * 2 + 1);
+        }
+    }
+
+    pub fn resolve(&self, encoder: &mut wgpu::CommandEncoder) {
+        if self.is_recording() {
+            encoder.resolve_query_set(
+                &self.query_set,
+                0..Self::QUERY_COUNT,
+                &self.resolve_buffer,
+                0,
+            );
+            encoder.copy_buffer_to_buffer(
+                &self.resolve_buffer,
+                0,
+                &self.readback_buffer,
+                0,
+                Self::BUFFER_SIZE,
+            );
+        }
+    }
+
+    pub fn request_readback(&self) {
+        if self.readback_pending.replace(true) {
+            return;
+        }
+
+        let readback_ready = self.readback_ready.clone();
+        self.readback_buffer
+            .slice(..)
+            .map_async(wgpu::MapMode::Read, move |result| {
+                if result.is_ok() {
+                    readback_ready.store(true, Ordering::Release);
+                }
+            });
+    }
+
+    pub fn collect(&self, device: &wgpu::Device) {
+        if !self.readback_pending.get() {
+            return;
+        }
+
+        device.poll(wgpu::Maintain::Poll);
+        if !self.readback_ready.swap(false, Ordering::Acquire) {
+            return;
+        }
+
+        {
+            let timestamps = self.readback_buffer.slice(..).get_mapped_range();
+            let timestamps: &[u64] = bytemuck::cast_slice(&timestamps);
+            let mut pass_times = self.pass_times.borrow_mut();
+            for (i, pass_time) in pass_times.iter_mut().enumerate() {
+                *pass_time = pass_duration(
+                    timestamps[i * 2],
+                    timestamps[i * 2 + 1],
+                    self.timestamp_period,
+                );
+            }
+        }
+        self.readback_buffer.unmap();
+        self.readback_pending.set(false);
+    }
+}
+
+// Timestamps count ticks of timestamp_period nanoseconds, an end before its
+// begin reads as zero.
+fn pass_duration(begin: u64, end: u64, timestamp_period: f32) -> Duration {
+    let ticks = end.saturating_sub(begin);
+    Duration::from_nanos((ticks as f64 * timestamp_period as f64) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::tests::{headless_renderer, render_frame};
+
+    #[test]
+    fn timestamps_convert_to_pass_times() {
+        assert_eq!(
+            pass_duration(1_000, 3_000_000, 1.0),
+            Duration::from_nanos(2_999_000)
+        );
+        assert_eq!(pass_duration(0, 125_000, 40.0), Duration::from_millis(5));
+        assert_eq!(pass_duration(500, 500, 1.0), Duration::ZERO);
+        assert_eq!(pass_duration(600, 500, 1.0), Duration::ZERO);
+    }
+
+    #[test]
+    fn pass_times_are_reported_after_a_frame() {
+        let Some(renderer) = headless_renderer() else {
+            return;
+        };
+        if renderer.gpu_pass_times().is_none() {
+            eprintln!("skipping the GPU test: timestamp queries not supported");
+            return;
+        }
+
+        // The times of a frame are read back while rendering the next ones.
+        for _ in 0..3 {
+            render_frame(&renderer, &[]);
+        }
+        let pass_times = renderer.gpu_pass_times().unwrap();
+        assert_eq!(
+            pass_times.iter().map(|(name, _)| *name).collect::<Vec<_>>(),
+            ["entity", "target"]
+        );
+        assert!(pass_times.iter().all(|(_, time)| !time.is_zero()));
+    }
+}
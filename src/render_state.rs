@@ -0,0 +1,28 @@
+pub struct RenderStateComponent {
+    pub tint: [f32; 3],
+    pub intensity: f32,
+}
+
+impl RenderStateComponent {
+    pub fn new() -> Self {
+        Self {
+            tint: [1.0, 1.0, 1.0],
+            intensity: 1.0,
+        }
+    }
+
+    pub fn set_tint(&mut self, tint: [f32; 3], intensity: f32) {
+        self.tint = tint;
+        self.intensity = intensity;
+    }
+
+    pub fn tint_uniform(&self) -> [f32; 4] {
+        [self.tint[0], self.tint[1], self.tint[2], self.intensity]
+    }
+}
+
+impl Default for RenderStateComponent {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,81 +1,158 @@
-use std::error::Error;
+use std::{
+    error::Error,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use async_std::task;
-use cgmath::{Matrix4, SquareMatrix};
+use cgmath::{Matrix4, SquareMatrix, Vector3};
 use wgpu::util::DeviceExt;
 use winit::window::Window;
 
 use crate::{
     camera::Camera,
     entity::Entity,
-    pipeline::{AliasPipeline, TargetPipeline},
+    material::MaterialComponent,
+    particle::ParticleSystem,
+    pipeline::{
+        AliasPipeline, FilterQuality, Light, ParticlePipeline, ScreenPipeline, ShadowPipeline,
+        SkyPipeline, TargetPipeline,
+    },
+    profiler::{GpuProfiler, RenderPass},
+    resource,
+    viewmodel::ViewModelComponent,
 };
 
 pub struct Renderer {
     pub device: wgpu::Device,
     pub queue: wgpu::Queue,
     pub config: wgpu::SurfaceConfiguration,
+    // Picked up by the materials created afterwards, so set it before loading
+    // the scene. Negative keeps textures sharper, positive smooths them.
+    pub lod_bias: f32,
 
-    surface: wgpu::Surface,
+    // None when headless.
+    surface: Option<wgpu::Surface>,
 
     view_projection_matrix_buffer: wgpu::Buffer,
     view_projection_bind_group: wgpu::BindGroup,
+    viewmodel_projection_matrix_buffer: wgpu::Buffer,
+    viewmodel_projection_bind_group: wgpu::BindGroup,
     pub entity_render_pipeline: AliasPipeline,
+    shadow_render_pipeline: ShadowPipeline,
+    sky_render_pipeline: SkyPipeline,
+    particle_render_pipeline: ParticlePipeline,
     target_render_pipeline: TargetPipeline,
+    screen_render_pipeline: ScreenPipeline,
+    screen_image: Option<MaterialComponent>,
+    viewmodel: Option<Entity>,
+    shadows: bool,
+    liquid_warp: bool,
+    liquid_warp_speed: f32,
+    start_time: Instant,
+    gpu_profiler: Option<GpuProfiler>,
+    device_lost: Arc<AtomicBool>,
 }
 
 impl Renderer {
     pub fn new(window: &Window) -> Result<Self, Box<dyn Error>> {
         let size = window.inner_size();
+        let instance = Self::create_instance();
+        let surface = unsafe { instance.create_surface(&window) }?;
+
+        Self::with_surface(&instance, Some(surface), size.width, size.height)
+    }
+
+    // Renders to an offscreen texture instead of a window, frames are drawn
+    // but never presented.
+    pub fn headless(width: u32, height: u32) -> Result<Self, Box<dyn Error>> {
+        Self::with_surface(&Self::create_instance(), None, width, height)
+    }
 
-        // Create an instance and adapter
-        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+    fn create_instance() -> wgpu::Instance {
+        wgpu::Instance::new(wgpu::InstanceDescriptor {
             backends: wgpu::Backends::all(),
             dx12_shader_compiler: Default::default(),
-        });
-        let surface = unsafe { instance.create_surface(&window) }?;
+        })
+    }
+
+    fn with_surface(
+        instance: &wgpu::Instance,
+        surface: Option<wgpu::Surface>,
+        width: u32,
+        height: u32,
+    ) -> Result<Self, Box<dyn Error>> {
+        // Create an adapter
         let adapter = task::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
             power_preference: wgpu::PowerPreference::HighPerformance,
             force_fallback_adapter: false,
-            compatible_surface: Some(&surface),
+            compatible_surface: surface.as_ref(),
         }))
-        .unwrap();
+        .ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotFound, "no suitable GPU adapter")
+        })?;
 
         // Create the device and queue
         let (device, queue) = task::block_on(adapter.request_device(
             &wgpu::DeviceDescriptor {
                 label: None,
-                features: wgpu::Features::empty(),
+                features: adapter.features() & wgpu::Features::TIMESTAMP_QUERY,
                 limits: wgpu::Limits::default(),
             },
             None,
         ))?;
 
-        let surface_caps = surface.get_capabilities(&adapter);
-        let surface_format = surface_caps
-            .formats
-            .iter()
-            .copied()
-            .filter(|f| f.is_srgb())
-            .next()
-            .unwrap_or(surface_caps.formats[0]);
+        let surface_format = match &surface {
+            Some(surface) => {
+                let surface_caps = surface.get_capabilities(&adapter);
+                surface_caps
+                    .formats
+                    .iter()
+                    .copied()
+                    .filter(|f| f.is_srgb())
+                    .next()
+                    .unwrap_or(surface_caps.formats[0])
+            }
+            None => wgpu::TextureFormat::Rgba8UnormSrgb,
+        };
         let config = wgpu::SurfaceConfiguration {
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
             format: surface_format,
-            width: size.width,
-            height: size.height,
+            width,
+            height,
             present_mode: wgpu::PresentMode::Fifo,
             alpha_mode: wgpu::CompositeAlphaMode::Auto,
             view_formats: vec![],
         };
-        surface.configure(&device, &config);
+        if let Some(surface) = &surface {
+            surface.configure(&device, &config);
+        }
+
+        // wgpu reports a lost device as an out of memory error, the renderer
+        // is flagged so the caller can rebuild it instead of crashing.
+        let device_lost = Arc::new(AtomicBool::new(false));
+        {
+            let device_lost = device_lost.clone();
+            device.on_uncaptured_error(Box::new(move |err| match err {
+                wgpu::Error::OutOfMemory { .. } => {
+                    error!("GPU device lost: {}", err);
+                    device_lost.store(true, Ordering::SeqCst);
+                }
+                wgpu::Error::Validation { .. } => error!("GPU validation error: {}", err),
+            }));
+        }
 
         let view_projection_matrix: [[f32; 4]; 4] = Matrix4::identity().into();
         let view_projection_matrix_buffer =
             device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
                 label: None,
                 contents: bytemuck::cast_slice(&[view_projection_matrix]),
-                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+                usage: wgpu::BufferUsages::UNIFORM
+                    | wgpu::BufferUsages::COPY_DST
+                    | wgpu::BufferUsages::COPY_SRC,
             });
         let view_projection_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
@@ -100,31 +177,269 @@ impl Renderer {
             label: None,
         });
 
-        let entity_render_pipeline =
-            AliasPipeline::new(&device, &config, &[&view_projection_bind_group_layout]);
+        let viewmodel_projection_matrix_buffer =
+            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: None,
+                contents: bytemuck::cast_slice(&[view_projection_matrix]),
+                usage: wgpu::BufferUsages::UNIFORM
+                    | wgpu::BufferUsages::COPY_DST
+                    | wgpu::BufferUsages::COPY_SRC,
+            });
+        let viewmodel_projection_bind_group =
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                layout: &view_projection_bind_group_layout,
+                entries: &[wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: viewmodel_projection_matrix_buffer.as_entire_binding(),
+                }],
+                label: None,
+            });
+
+        let entity_render_pipeline = AliasPipeline::new(
+            &device,
+            &config,
+            &[&view_projection_bind_group_layout],
+            wgpu::DepthBiasState::default(),
+        );
+        let shadow_render_pipeline = ShadowPipeline::new(
+            &device,
+            &config,
+            &[&view_projection_bind_group_layout],
+            ShadowPipeline::DEPTH_BIAS,
+        );
+        let sky_render_pipeline = SkyPipeline::new(&device, &config);
+        let particle_render_pipeline =
+            ParticlePipeline::new(&device, &config, &[&view_projection_bind_group_layout]);
         let target_render_pipeline = TargetPipeline::new(
             &device,
             &config,
             &entity_render_pipeline.albedo_view,
             &entity_render_pipeline.normal_view,
             &entity_render_pipeline.depth_view,
+            FilterQuality::default(),
         );
+        let screen_render_pipeline = ScreenPipeline::new(&device, &config);
+        let gpu_profiler = GpuProfiler::new(&device, &queue);
 
         Ok(Self {
             device,
             queue,
             config,
+            lod_bias: 0.0,
             surface,
 
             view_projection_matrix_buffer,
             view_projection_bind_group,
+            viewmodel_projection_matrix_buffer,
+            viewmodel_projection_bind_group,
 
             entity_render_pipeline,
+            shadow_render_pipeline,
+            sky_render_pipeline,
+            particle_render_pipeline,
             target_render_pipeline,
+            screen_render_pipeline,
+            screen_image: None,
+            viewmodel: None,
+            shadows: true,
+            liquid_warp: true,
+            liquid_warp_speed: 1.0,
+            start_time: Instant::now(),
+            gpu_profiler,
+            device_lost,
         })
     }
 
-    pub fn render(&self, camera: &Camera, entities: &Vec<Entity>) -> Result<(), Box<dyn Error>> {
+    pub fn is_device_lost(&self) -> bool {
+        self.device_lost.load(Ordering::SeqCst)
+    }
+
+    // Builds a new device, surface and pipelines, keeping the renderer
+    // settings. Everything created on the old device is gone: the screen
+    // image, skybox and viewmodel are dropped, and the caller has to rebuild
+    // the scene resources.
+    pub fn recreate(&mut self, window: &Window) -> Result<(), Box<dyn Error>> {
+        warn!("Recreating the renderer");
+
+        self.take_over(Self::new(window)?);
+
+        Ok(())
+    }
+
+    // Replaces the device and everything created on it by those of renderer,
+    // carrying the settings over.
+    fn take_over(&mut self, mut renderer: Self) {
+        renderer.lod_bias = self.lod_bias;
+        renderer.shadows = self.shadows;
+        renderer.liquid_warp = self.liquid_warp;
+        renderer.liquid_warp_speed = self.liquid_warp_speed;
+        renderer.start_time = self.start_time;
+        renderer
+            .entity_render_pipeline
+            .set_clear_color(self.entity_render_pipeline.clear_color());
+        renderer
+            .target_render_pipeline
+            .copy_lighting(&renderer.queue, &self.target_render_pipeline);
+        *self = renderer;
+    }
+
+    // Reconfigures the surface and rebuilds the render targets sized to it,
+    // keeping the device and everything created on it. A zero size, as when
+    // the window is minimized, is ignored.
+    pub fn resize(&mut self, width: u32, height: u32) {
+        if width == 0 || height == 0 {
+            return;
+        }
+
+        self.config.width = width;
+        self.config.height = height;
+        if let Some(surface) = &self.surface {
+            surface.configure(&self.device, &self.config);
+        }
+        self.entity_render_pipeline
+            .resize(&self.device, &self.config);
+        self.target_render_pipeline.resize(
+            &self.device,
+            &self.entity_render_pipeline.albedo_view,
+            &self.entity_render_pipeline.normal_view,
+            &self.entity_render_pipeline.depth_view,
+        );
+    }
+
+    pub fn set_screen_image<S>(&mut self, name: S) -> Result<(), Box<dyn Error>>
+    where
+        S: AsRef<str>,
+    {
+        let (width, height, indices) = resource::load_lmp_image(name)?;
+        let screen_image = MaterialComponent::new(
+            self,
+            &self.screen_render_pipeline.texture_bind_group_layout,
+            width,
+            height,
+        );
+        screen_image.update_texture_image(&self.queue, &resource::palette_index_to_rgba(&indices));
+        self.screen_image = Some(screen_image);
+
+        Ok(())
+    }
+
+    // Loads the six gfx/env/<name><suffix>.tga faces, with the suffixes
+    // ordered to match the cube layers once converted to engine coordinates.
+    pub fn set_skybox<S>(&mut self, name: S) -> Result<(), Box<dyn Error>>
+    where
+        S: AsRef<str>,
+    {
+        let mut sizes = [(0, 0); 6];
+        let mut faces: [Box<[u8]>; 6] = Default::default();
+        for ((face, size), suffix) in faces
+            .iter_mut()
+            .zip(sizes.iter_mut())
+            .zip(["rt", "lf", "up", "dn", "bk", "ft"])
+        {
+            let (width, height, rgba) =
+                resource::load_tga_image(format!("gfx/env/{}{}.tga", name.as_ref(), suffix))?;
+            *size = (width, height);
+            *face = rgba;
+        }
+        let size = Self::skybox_size(&sizes)?;
+
+        self.sky_render_pipeline
+            .set_cubemap(&self.device, &self.queue, size, &faces);
+
+        Ok(())
+    }
+
+    // The faces must be square, not empty and all of the same size, which is
+    // returned.
+    fn skybox_size(sizes: &[(u32, u32); 6]) -> Result<u32, Box<dyn Error>> {
+        let (width, height) = sizes[0];
+        for (face_width, face_height) in sizes.iter().copied() {
+            if face_width == 0 || face_width != face_height || face_width != width {
+                return Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "invalid skybox face size: {}x{}, expected {}x{}",
+                        face_width, face_height, width, height
+                    ),
+                )));
+            }
+        }
+
+        Ok(width)
+    }
+
+    pub fn clear_skybox(&mut self) {
+        self.sky_render_pipeline.clear_cubemap();
+    }
+
+    pub fn clear_screen_image(&mut self) {
+        self.screen_image = None;
+    }
+
+    // The viewmodel transform is relative to the eye; its field of view comes
+    // from the ViewModelComponent, or the default one if it has none.
+    pub fn set_viewmodel(&mut self, entity: Option<Entity>) {
+        self.viewmodel = entity;
+    }
+
+    pub fn viewmodel_mut(&mut self) -> Option<&mut Entity> {
+        self.viewmodel.as_mut()
+    }
+
+    // Speed scales the scrolling of entities tagged with LiquidComponent, 1.0
+    // matches the original engine.
+    pub fn set_liquid_warp(&mut self, enabled: bool, speed: f32) {
+        self.liquid_warp = enabled;
+        self.liquid_warp_speed = speed;
+    }
+
+    pub fn set_shadows(&mut self, shadows: bool) {
+        self.shadows = shadows;
+    }
+
+    // See AliasPipeline::set_clear_color, black by default.
+    pub fn set_clear_color(&mut self, clear_color: wgpu::Color) {
+        self.entity_render_pipeline.set_clear_color(clear_color);
+    }
+
+    pub fn set_screen_tint(&self, tint: [f32; 4]) {
+        self.target_render_pipeline
+            .set_screen_tint(&self.queue, tint);
+    }
+
+    pub fn set_ambient(&self, ambient: [f32; 3]) {
+        self.target_render_pipeline
+            .set_ambient(&self.queue, ambient);
+    }
+
+    // See TargetPipeline::set_directional_light.
+    pub fn set_directional_light(&self, direction: Vector3<f32>, color: [f32; 3], specular: f32) {
+        self.target_render_pipeline
+            .set_directional_light(&self.queue, direction, color, specular);
+    }
+
+    // See TargetPipeline::set_lights.
+    pub fn set_lights(&self, lights: &[Light]) {
+        self.target_render_pipeline
+            .set_lights(&self.queue, lights);
+    }
+
+    pub fn gpu_pass_times(&self) -> Option<Vec<(&'static str, Duration)>> {
+        self.gpu_profiler
+            .as_ref()
+            .map(|profiler| profiler.pass_times())
+    }
+
+    pub fn render(
+        &self,
+        camera: &Camera,
+        entities: &[&Entity],
+        particles: &ParticleSystem,
+    ) -> Result<(), Box<dyn Error>> {
+        if let Some(profiler) = &self.gpu_profiler {
+            profiler.collect(&self.device);
+        }
+
         let view_projection_matrix: [[f32; 4]; 4] = camera.view_projection_matrix().into();
         self.queue.write_buffer(
             &self.view_projection_matrix_buffer,
@@ -132,23 +447,336 @@ impl Renderer {
             bytemuck::cast_slice(&[view_projection_matrix]),
         );
 
+        self.target_render_pipeline
+            .update_camera(&self.queue, camera);
+
+        self.entity_render_pipeline.update_frame_uniform(
+            &self.queue,
+            self.start_time.elapsed().as_secs_f32(),
+            self.liquid_warp,
+            self.liquid_warp_speed,
+        );
+
         let mut encoder = self
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        self.begin_pass(&mut encoder, RenderPass::Entity);
         self.entity_render_pipeline.render_pass(
             &self.queue,
             &mut encoder,
             &[&self.view_projection_bind_group],
             entities,
         );
+        self.shadow_pass(&mut encoder, entities);
+        self.sky_render_pipeline.render_pass(
+            &self.queue,
+            &mut encoder,
+            &self.entity_render_pipeline.albedo_view,
+            &self.entity_render_pipeline.depth_view,
+            camera.rotation_projection_matrix(),
+        );
+        self.particle_render_pipeline.render_pass(
+            &self.queue,
+            &mut encoder,
+            &self.entity_render_pipeline.albedo_view,
+            &self.entity_render_pipeline.depth_view,
+            &[&self.view_projection_bind_group],
+            particles,
+        );
+        if let Some(viewmodel) = &self.viewmodel {
+            let field_of_view = viewmodel
+                .get_component::<ViewModelComponent>()
+                .map_or(ViewModelComponent::default().field_of_view, |component| {
+                    component.field_of_view
+                });
+            let viewmodel_projection_matrix: [[f32; 4]; 4] =
+                camera.projection_matrix_with_fov(field_of_view).into();
+            self.queue.write_buffer(
+                &self.viewmodel_projection_matrix_buffer,
+                0,
+                bytemuck::cast_slice(&[viewmodel_projection_matrix]),
+            );
+            self.entity_render_pipeline.viewmodel_render_pass(
+                &self.queue,
+                &mut encoder,
+                &[&self.viewmodel_projection_bind_group],
+                viewmodel,
+            );
+        }
+        self.end_pass(&mut encoder, RenderPass::Entity);
 
-        let output = self.surface.get_current_texture().unwrap();
-        let output_view = output.texture.create_view(&Default::default());
+        let output = match &self.surface {
+            Some(surface) => match surface.get_current_texture() {
+                Ok(output) => Some(output),
+                // The frame is skipped, the next one renders to the
+                // reconfigured surface.
+                Err(wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated) => {
+                    surface.configure(&self.device, &self.config);
+                    return Ok(());
+                }
+                Err(wgpu::SurfaceError::Timeout) => return Ok(()),
+                Err(wgpu::SurfaceError::OutOfMemory) => {
+                    self.device_lost.store(true, Ordering::SeqCst);
+                    return Err(Box::new(wgpu::SurfaceError::OutOfMemory));
+                }
+            },
+            None => None,
+        };
+        let offscreen_texture;
+        let output_texture = match &output {
+            Some(output) => &output.texture,
+            None => {
+                offscreen_texture = self.create_offscreen_texture();
+                &offscreen_texture
+            }
+        };
+        let output_view = output_texture.create_view(&Default::default());
+        self.begin_pass(&mut encoder, RenderPass::Target);
         self.target_render_pipeline
             .render_pass(&mut encoder, &output_view);
+        self.end_pass(&mut encoder, RenderPass::Target);
+        if let Some(screen_image) = &self.screen_image {
+            self.screen_render_pipeline.render_pass(
+                &mut encoder,
+                &output_view,
+                &screen_image.bind_group,
+            );
+        }
+        if let Some(profiler) = &self.gpu_profiler {
+            profiler.resolve(&mut encoder);
+        }
         self.queue.submit(std::iter::once(encoder.finish()));
-        output.present();
+        if let Some(profiler) = &self.gpu_profiler {
+            profiler.request_readback();
+        }
+        if let Some(output) = output {
+            output.present();
+        }
 
         Ok(())
     }
+
+    // Stands in for the surface texture when headless.
+    fn create_offscreen_texture(&self) -> wgpu::Texture {
+        self.device.create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size: wgpu::Extent3d {
+                width: self.config.width,
+                height: self.config.height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: self.config.format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            view_formats: &[],
+        })
+    }
+
+    // Returns the number of shadows drawn, none while they are disabled.
+    fn shadow_pass(&self, encoder: &mut wgpu::CommandEncoder, entities: &[&Entity]) -> usize {
+        if !self.shadows {
+            return 0;
+        }
+
+        self.shadow_render_pipeline.render_pass(
+            &self.queue,
+            encoder,
+            &self.entity_render_pipeline.albedo_view,
+            &self.entity_render_pipeline.depth_view,
+            &[&self.view_projection_bind_group],
+            entities,
+        )
+    }
+
+    fn begin_pass(&self, encoder: &mut wgpu::CommandEncoder, pass: RenderPass) {
+        if let Some(profiler) = &self.gpu_profiler {
+            profiler.begin_pass(encoder, pass);
+        }
+    }
+
+    fn end_pass(&self, encoder: &mut wgpu::CommandEncoder, pass: RenderPass) {
+        if let Some(profiler) = &self.gpu_profiler {
+            profiler.end_pass(encoder, pass);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use cgmath::Deg;
+    use lazy_static::lazy_static;
+
+    use crate::{mesh::MeshComponent, pipeline::SkyPipeline, shadow::ShadowComponent};
+
+    lazy_static! {
+        // Shared by the tests, as dropping an instance tears down the EGL
+        // display of every other one on the GL backend.
+        static ref INSTANCE: wgpu::Instance = Renderer::create_instance();
+    }
+
+    // None where no adapter is available, the GPU tests then pass without
+    // running.
+    pub(crate) fn headless_renderer() -> Option<Renderer> {
+        match Renderer::with_surface(&INSTANCE, None, 64, 64) {
+            Ok(renderer) => Some(renderer),
+            Err(err) => {
+                eprintln!("skipping the GPU test: {}", err);
+                None
+            }
+        }
+    }
+
+    // Validation errors are only logged, as in the engine: the GL backend
+    // cannot build every pipeline, yet frames must still go through.
+    pub(crate) fn render_frame(renderer: &Renderer, entities: &[&Entity]) {
+        let camera = Camera::new(renderer.config.width as i32, renderer.config.height as i32);
+        renderer
+            .render(&camera, entities, &ParticleSystem::new())
+            .unwrap();
+        renderer.device.poll(wgpu::Maintain::Wait);
+    }
+
+    // The buffer must have been created with COPY_SRC.
+    pub(crate) fn read_buffer(renderer: &Renderer, buffer: &wgpu::Buffer) -> Vec<u8> {
+        let readback_buffer = renderer.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: buffer.size(),
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let mut encoder = renderer
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        encoder.copy_buffer_to_buffer(buffer, 0, &readback_buffer, 0, buffer.size());
+        renderer.queue.submit(std::iter::once(encoder.finish()));
+
+        let slice = readback_buffer.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |result| result.unwrap());
+        renderer.device.poll(wgpu::Maintain::Wait);
+        let bytes = slice.get_mapped_range().to_vec();
+        readback_buffer.unmap();
+
+        bytes
+    }
+
+    // Runs only the entity pass, returning the number of draws it issued.
+    pub(crate) fn entity_pass_draws(renderer: &Renderer, entities: &[&Entity]) -> usize {
+        let mut encoder = renderer
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        let draws = renderer.entity_render_pipeline.render_pass(
+            &renderer.queue,
+            &mut encoder,
+            &[&renderer.view_projection_bind_group],
+            entities,
+        );
+        renderer.queue.submit(std::iter::once(encoder.finish()));
+        renderer.device.poll(wgpu::Maintain::Wait);
+
+        draws
+    }
+
+    #[test]
+    fn skybox_faces_must_match() {
+        assert_eq!(Renderer::skybox_size(&[(64, 64); 6]).unwrap(), 64);
+
+        assert!(Renderer::skybox_size(&[(0, 0); 6]).is_err());
+        let mut sizes = [(64, 64); 6];
+        sizes[3] = (32, 32);
+        assert!(Renderer::skybox_size(&sizes).is_err());
+        sizes[3] = (64, 32);
+        assert!(Renderer::skybox_size(&sizes).is_err());
+        sizes[3] = (0, 0);
+        assert!(Renderer::skybox_size(&sizes).is_err());
+    }
+
+    #[test]
+    fn skybox_cubemap_has_six_layers() {
+        let size = Renderer::skybox_size(&[(128, 128); 6]).unwrap();
+        let descriptor = SkyPipeline::cubemap_descriptor(size);
+        assert_eq!(
+            descriptor.size,
+            wgpu::Extent3d {
+                width: 128,
+                height: 128,
+                depth_or_array_layers: 6,
+            }
+        );
+        assert_eq!(descriptor.dimension, wgpu::TextureDimension::D2);
+    }
+
+    #[test]
+    fn shadow_pass_is_skipped_when_disabled() {
+        let Some(mut renderer) = headless_renderer() else {
+            return;
+        };
+        let mut entity = Entity::new();
+        entity.add_component(MeshComponent::new(&renderer, 3));
+        entity.add_component(ShadowComponent {
+            floor_distance: Some(24.0),
+        });
+
+        let shadows = |renderer: &Renderer| {
+            let mut encoder = renderer
+                .device
+                .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+            let shadows = renderer.shadow_pass(&mut encoder, &[&entity]);
+            renderer.queue.submit(std::iter::once(encoder.finish()));
+            shadows
+        };
+        renderer.set_shadows(true);
+        assert_eq!(shadows(&renderer), 1);
+        renderer.set_shadows(false);
+        assert_eq!(shadows(&renderer), 0);
+    }
+
+    #[test]
+    fn viewmodel_has_its_own_projection() {
+        let Some(mut renderer) = headless_renderer() else {
+            return;
+        };
+        let mut viewmodel = Entity::new();
+        viewmodel.add_component(ViewModelComponent {
+            field_of_view: Deg(60.0).into(),
+        });
+        renderer.set_viewmodel(Some(viewmodel));
+        let mut camera = Camera::new(renderer.config.width as i32, renderer.config.height as i32);
+        camera.set_quake_view([64.0, 32.0, 24.0], 45.0);
+        renderer
+            .render(&camera, &[], &ParticleSystem::new())
+            .unwrap();
+
+        let matrix = |buffer: &wgpu::Buffer| -> Matrix4<f32> {
+            bytemuck::pod_read_unaligned::<[[f32; 4]; 4]>(&read_buffer(&renderer, buffer)).into()
+        };
+        let world = matrix(&renderer.view_projection_matrix_buffer);
+        let viewmodel = matrix(&renderer.viewmodel_projection_matrix_buffer);
+        assert_eq!(world, camera.view_projection_matrix());
+        assert_eq!(viewmodel, camera.projection_matrix_with_fov(Deg(60.0)));
+        assert_ne!(world, viewmodel);
+    }
+
+    #[test]
+    fn recreate_keeps_the_settings_and_renders_again() {
+        let Some(mut renderer) = headless_renderer() else {
+            return;
+        };
+        renderer.lod_bias = 0.5;
+        renderer.set_shadows(false);
+        renderer.set_clear_color(wgpu::Color::RED);
+        render_frame(&renderer, &[]);
+
+        renderer.take_over(headless_renderer().unwrap());
+        assert_eq!(renderer.lod_bias, 0.5);
+        assert!(!renderer.shadows);
+        assert_eq!(
+            renderer.entity_render_pipeline.clear_color(),
+            wgpu::Color::RED
+        );
+        assert!(!renderer.is_device_lost());
+        render_frame(&renderer, &[]);
+    }
 }
@@ -4,49 +4,484 @@ use std::{
     io::{Error, ErrorKind},
     io::{Read, Seek, SeekFrom},
     path::Path,
-    sync::Mutex,
+    sync::RwLock,
 };
 
 use lazy_static::lazy_static;
-use once_cell::sync::OnceCell;
+
+use crate::lump::LumpReader;
 
 lazy_static! {
-    pub static ref GLOBAL_RESOURCES: OnceCell<Mutex<Pak>> = OnceCell::new();
-    pub static ref GLOBAL_PALETTE: OnceCell<Box<[[u8; 3]; 256]>> = OnceCell::new();
+    pub static ref GLOBAL_RESOURCES: RwLock<Option<Pak>> = RwLock::new(None);
+    pub static ref GLOBAL_PALETTE: RwLock<Option<Box<[[u8; 3]; 256]>>> = RwLock::new(None);
 }
 
 #[macro_export]
 macro_rules! load_resource {
     ($name: expr) => {
-        GLOBAL_RESOURCES.get().unwrap().lock().unwrap().read($name)
+        GLOBAL_RESOURCES
+            .read()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .read($name)
     };
 }
 
-pub fn init<P>(path: P)
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum PaletteSource {
+    // gfx/palette.lmp from the PAK, or the fallback when the PAK has none.
+    #[default]
+    Pak,
+    Fallback,
+}
+
+pub fn init<P>(path: P) -> Result<(), Error>
 where
     P: AsRef<Path>,
 {
-    GLOBAL_RESOURCES.get_or_init(|| Mutex::new(Pak::open(path).unwrap()));
-    GLOBAL_PALETTE.get_or_init(|| {
-        let palette = GLOBAL_RESOURCES
-            .get()
-            .unwrap()
-            .lock()
-            .unwrap()
-            .read("gfx/palette.lmp")
-            .unwrap();
-        let mut rgb = [[0u8; 3]; 256];
-        for index in 0..256 {
-            for channel in 0..3 {
-                rgb[index][channel] = palette[index * 3 + channel];
+    init_with(path, PaletteSource::default())
+}
+
+pub fn init_with<P>(path: P, palette_source: PaletteSource) -> Result<(), Error>
+where
+    P: AsRef<Path>,
+{
+    if GLOBAL_RESOURCES.read().unwrap().is_none() {
+        reset_with(path, palette_source)?;
+    }
+
+    Ok(())
+}
+
+pub fn reset<P>(path: P) -> Result<(), Error>
+where
+    P: AsRef<Path>,
+{
+    reset_with(path, PaletteSource::default())
+}
+
+pub fn reset_with<P>(path: P, palette_source: PaletteSource) -> Result<(), Error>
+where
+    P: AsRef<Path>,
+{
+    let pak = Pak::open(path)?;
+    let rgb = match palette_source {
+        PaletteSource::Pak => match pak.read("gfx/palette.lmp") {
+            Ok(palette) => parse_palette(&palette)?,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                warn!("gfx/palette.lmp not found, using the fallback palette");
+                fallback_palette()
             }
+            Err(err) => return Err(err),
+        },
+        PaletteSource::Fallback => fallback_palette(),
+    };
+
+    // Both locks are held while swapping so readers never see a PAK paired
+    // with the palette of another.
+    let mut resources = GLOBAL_RESOURCES.write().unwrap();
+    let mut palette = GLOBAL_PALETTE.write().unwrap();
+    *resources = Some(pak);
+    *palette = Some(rgb);
+
+    Ok(())
+}
+
+// A palette.lmp is exactly 256 RGB triplets.
+pub fn parse_palette(data: &[u8]) -> Result<Box<[[u8; 3]; 256]>, Error> {
+    if data.len() != 256 * 3 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid palette size: {} bytes, expected 768", data.len()),
+        ));
+    }
+
+    let mut rgb = Box::new([[0u8; 3]; 256]);
+    for (color, channels) in rgb.iter_mut().zip(data.chunks_exact(3)) {
+        color.copy_from_slice(channels);
+    }
+    Ok(rgb)
+}
+
+// The palette.lmp shipped with Quake, so models and textures keep their
+// colors when the PAK has none.
+const QUAKE_PALETTE: [[u8; 3]; 256] = [
+    [0, 0, 0],
+    [15, 15, 15],
+    [31, 31, 31],
+    [47, 47, 47],
+    [63, 63, 63],
+    [75, 75, 75],
+    [91, 91, 91],
+    [107, 107, 107],
+    [123, 123, 123],
+    [139, 139, 139],
+    [155, 155, 155],
+    [171, 171, 171],
+    [187, 187, 187],
+    [203, 203, 203],
+    [219, 219, 219],
+    [235, 235, 235],
+    [15, 11, 7],
+    [23, 15, 11],
+    [31, 23, 11],
+    [39, 27, 15],
+    [47, 35, 19],
+    [55, 43, 23],
+    [63, 47, 23],
+    [75, 55, 27],
+    [83, 59, 27],
+    [91, 67, 31],
+    [99, 75, 31],
+    [107, 83, 31],
+    [115, 87, 31],
+    [123, 95, 35],
+    [131, 103, 35],
+    [143, 111, 35],
+    [11, 11, 15],
+    [19, 19, 27],
+    [27, 27, 39],
+    [39, 39, 51],
+    [47, 47, 63],
+    [55, 55, 75],
+    [63, 63, 87],
+    [71, 71, 103],
+    [79, 79, 115],
+    [91, 91, 127],
+    [99, 99, 139],
+    [107, 107, 151],
+    [115, 115, 163],
+    [123, 123, 175],
+    [131, 131, 187],
+    [139, 139, 203],
+    [0, 0, 0],
+    [7, 7, 0],
+    [11, 11, 0],
+    [19, 19, 0],
+    [27, 27, 0],
+    [35, 35, 0],
+    [43, 43, 7],
+    [47, 47, 7],
+    [55, 55, 7],
+    [63, 63, 7],
+    [71, 71, 7],
+    [75, 75, 11],
+    [83, 83, 11],
+    [91, 91, 11],
+    [99, 99, 11],
+    [107, 107, 15],
+    [7, 0, 0],
+    [15, 0, 0],
+    [23, 0, 0],
+    [31, 0, 0],
+    [39, 0, 0],
+    [47, 0, 0],
+    [55, 0, 0],
+    [63, 0, 0],
+    [71, 0, 0],
+    [79, 0, 0],
+    [87, 0, 0],
+    [95, 0, 0],
+    [103, 0, 0],
+    [111, 0, 0],
+    [119, 0, 0],
+    [127, 0, 0],
+    [19, 19, 0],
+    [27, 27, 0],
+    [35, 35, 0],
+    [47, 43, 0],
+    [55, 47, 0],
+    [67, 55, 0],
+    [75, 59, 7],
+    [87, 67, 7],
+    [95, 71, 7],
+    [107, 75, 11],
+    [119, 83, 15],
+    [131, 87, 19],
+    [139, 91, 19],
+    [151, 95, 27],
+    [163, 99, 31],
+    [175, 103, 35],
+    [35, 19, 7],
+    [47, 23, 11],
+    [59, 31, 15],
+    [75, 35, 19],
+    [87, 43, 23],
+    [99, 47, 31],
+    [115, 55, 35],
+    [127, 59, 43],
+    [143, 67, 51],
+    [159, 79, 51],
+    [175, 99, 47],
+    [191, 119, 47],
+    [207, 143, 43],
+    [223, 171, 39],
+    [239, 203, 31],
+    [255, 243, 27],
+    [11, 7, 0],
+    [27, 19, 0],
+    [43, 35, 15],
+    [55, 43, 19],
+    [71, 51, 27],
+    [83, 55, 35],
+    [99, 63, 43],
+    [111, 71, 51],
+    [127, 83, 63],
+    [139, 95, 71],
+    [155, 107, 83],
+    [167, 123, 95],
+    [183, 135, 107],
+    [195, 147, 123],
+    [211, 163, 139],
+    [227, 179, 151],
+    [171, 139, 163],
+    [159, 127, 151],
+    [147, 115, 135],
+    [139, 103, 123],
+    [127, 91, 111],
+    [119, 83, 99],
+    [107, 75, 87],
+    [95, 63, 75],
+    [87, 55, 67],
+    [75, 47, 55],
+    [67, 39, 47],
+    [55, 31, 35],
+    [43, 23, 27],
+    [35, 19, 19],
+    [23, 11, 11],
+    [15, 7, 7],
+    [187, 115, 159],
+    [175, 107, 143],
+    [163, 95, 131],
+    [151, 87, 119],
+    [139, 79, 107],
+    [127, 75, 95],
+    [115, 67, 83],
+    [107, 59, 75],
+    [95, 51, 63],
+    [83, 43, 55],
+    [71, 35, 43],
+    [59, 31, 35],
+    [47, 23, 27],
+    [35, 19, 19],
+    [23, 11, 11],
+    [15, 7, 7],
+    [219, 195, 187],
+    [203, 179, 167],
+    [191, 163, 155],
+    [175, 151, 139],
+    [163, 135, 123],
+    [151, 123, 111],
+    [135, 111, 95],
+    [123, 99, 83],
+    [107, 87, 71],
+    [95, 75, 59],
+    [83, 63, 51],
+    [67, 51, 39],
+    [55, 43, 31],
+    [39, 31, 23],
+    [27, 19, 15],
+    [15, 11, 7],
+    [111, 131, 123],
+    [103, 123, 111],
+    [95, 115, 103],
+    [87, 107, 95],
+    [79, 99, 87],
+    [71, 91, 79],
+    [63, 83, 71],
+    [55, 75, 63],
+    [47, 67, 55],
+    [43, 59, 47],
+    [35, 51, 39],
+    [31, 43, 31],
+    [23, 35, 23],
+    [15, 27, 19],
+    [11, 19, 11],
+    [7, 11, 7],
+    [255, 243, 27],
+    [239, 223, 23],
+    [219, 203, 19],
+    [203, 183, 15],
+    [187, 167, 15],
+    [171, 151, 11],
+    [155, 131, 7],
+    [139, 115, 7],
+    [123, 99, 7],
+    [107, 83, 0],
+    [91, 71, 0],
+    [75, 55, 0],
+    [59, 43, 0],
+    [43, 31, 0],
+    [27, 15, 0],
+    [11, 7, 0],
+    [0, 0, 255],
+    [11, 11, 239],
+    [19, 19, 223],
+    [27, 27, 207],
+    [35, 35, 191],
+    [43, 43, 175],
+    [47, 47, 159],
+    [47, 47, 143],
+    [47, 47, 127],
+    [47, 47, 111],
+    [47, 47, 95],
+    [43, 43, 79],
+    [35, 35, 63],
+    [27, 27, 47],
+    [19, 19, 31],
+    [11, 11, 15],
+    [43, 0, 0],
+    [59, 0, 0],
+    [75, 7, 0],
+    [95, 7, 0],
+    [111, 15, 0],
+    [127, 23, 7],
+    [147, 31, 7],
+    [163, 39, 11],
+    [183, 51, 15],
+    [195, 75, 27],
+    [207, 99, 43],
+    [219, 127, 59],
+    [227, 151, 79],
+    [231, 171, 95],
+    [239, 191, 119],
+    [247, 211, 139],
+    [167, 123, 59],
+    [183, 155, 55],
+    [199, 195, 55],
+    [231, 227, 87],
+    [127, 191, 255],
+    [171, 231, 255],
+    [215, 255, 255],
+    [103, 0, 0],
+    [139, 0, 0],
+    [179, 0, 0],
+    [215, 0, 0],
+    [255, 0, 0],
+    [255, 243, 147],
+    [255, 247, 199],
+    [255, 255, 255],
+    [159, 91, 83],
+];
+
+pub fn fallback_palette() -> Box<[[u8; 3]; 256]> {
+    Box::new(QUAKE_PALETTE)
+}
+
+pub fn load_lmp_image<S>(name: S) -> Result<(u32, u32, Box<[u8]>), Error>
+where
+    S: AsRef<str>,
+{
+    debug!("Loading LMP file {}", name.as_ref());
+
+    parse_lmp_image(&load_resource!(name.as_ref())?)
+}
+
+// A little endian width and height followed by the palette indices, row by
+// row.
+pub fn parse_lmp_image(bytes: &[u8]) -> Result<(u32, u32, Box<[u8]>), Error> {
+    if bytes.len() < 8 {
+        return Err(Error::new(ErrorKind::InvalidData, "truncated header"));
+    }
+
+    let width = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
+    let height = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+    let size = width as usize * height as usize;
+    if bytes.len() - 8 < size {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid image size: {}x{}", width, height),
+        ));
+    }
+
+    Ok((width, height, bytes[8..8 + size].into()))
+}
+
+pub fn load_tga_image<S>(name: S) -> Result<(u32, u32, Box<[u8]>), Error>
+where
+    S: AsRef<str>,
+{
+    debug!("Loading TGA file {}", name.as_ref());
+
+    let bytes = load_resource!(name.as_ref())?;
+    if bytes.len() < 18 {
+        return Err(Error::new(ErrorKind::InvalidData, "truncated header"));
+    }
+
+    let id_length = bytes[0] as usize;
+    let image_type = bytes[2];
+    let width = u16::from_le_bytes(bytes[12..14].try_into().unwrap()) as u32;
+    let height = u16::from_le_bytes(bytes[14..16].try_into().unwrap()) as u32;
+    let bytes_per_pixel = bytes[16] as usize / 8;
+    let top_to_bottom = bytes[17] & 0x20 != 0;
+    if bytes[1] != 0 || !(bytes_per_pixel == 3 || bytes_per_pixel == 4) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "only true-color images are supported",
+        ));
+    }
+
+    if bytes.len() < 18 + id_length {
+        return Err(Error::new(ErrorKind::InvalidData, "truncated header"));
+    }
+
+    let num_pixels = width as usize * height as usize;
+    let mut data = bytes[18 + id_length..].iter().copied();
+    let mut pixels = Vec::with_capacity(num_pixels);
+    let read_pixel = |data: &mut dyn Iterator<Item = u8>| -> Result<[u8; 4], Error> {
+        let mut bgra = [0xffu8; 4];
+        for channel in bgra.iter_mut().take(bytes_per_pixel) {
+            *channel = data
+                .next()
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "truncated image data"))?;
+        }
+        Ok([bgra[2], bgra[1], bgra[0], bgra[3]])
+    };
+    match image_type {
+        2 => {
+            for _ in 0..num_pixels {
+                pixels.push(read_pixel(&mut data)?);
+            }
+        }
+        10 => {
+            while pixels.len() < num_pixels {
+                let packet = data
+                    .next()
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "truncated image data"))?;
+                let count = (packet & 0x7f) as usize + 1;
+                if packet & 0x80 != 0 {
+                    let pixel = read_pixel(&mut data)?;
+                    pixels.extend(std::iter::repeat_n(pixel, count));
+                } else {
+                    for _ in 0..count {
+                        pixels.push(read_pixel(&mut data)?);
+                    }
+                }
+            }
+            pixels.truncate(num_pixels);
+        }
+        ty => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid image type: {}", ty),
+            ))
         }
-        Box::new(rgb)
-    });
+    }
+
+    if !top_to_bottom {
+        pixels.reverse();
+        for row in pixels.chunks_mut(width as usize) {
+            row.reverse();
+        }
+    }
+
+    Ok((width, height, pixels.concat().into_boxed_slice()))
 }
 
-pub fn palette_index_to_rgba(indices: &Box<[u8]>) -> Box<[u8]> {
-    let palette = GLOBAL_PALETTE.get().unwrap();
+pub fn palette_index_to_rgba(indices: &[u8]) -> Box<[u8]> {
+    let palette = GLOBAL_PALETTE.read().unwrap();
+    let palette = palette.as_ref().unwrap();
     let mut rgba = Vec::with_capacity(indices.len() * 4);
     for color_index in indices.iter() {
         match *color_index {
@@ -66,6 +501,22 @@ pub fn palette_index_to_rgba(indices: &Box<[u8]>) -> Box<[u8]> {
     rgba.into_boxed_slice()
 }
 
+// Palette indices from this one on are drawn at full brightness regardless of
+// lighting.
+pub const FULLBRIGHT_START: u8 = 224;
+
+// 0xff where the pixel is fullbright and 0 elsewhere.
+pub fn fullbright_mask(indices: &[u8]) -> Box<[u8]> {
+    indices
+        .iter()
+        .map(|index| if *index >= FULLBRIGHT_START { 0xff } else { 0 })
+        .collect()
+}
+
+pub fn palette_rgba() -> Box<[u8]> {
+    palette_index_to_rgba(&(0..=255).collect::<Vec<u8>>())
+}
+
 #[derive(Debug)]
 pub struct Pak {
     file: File,
@@ -77,45 +528,64 @@ impl Pak {
     where
         P: AsRef<Path>,
     {
-        debug!("Opening PAK file {}", path.as_ref().to_string_lossy());
+        let pak_name = path.as_ref().to_string_lossy();
+        debug!("Opening PAK file {}", pak_name);
 
         let mut file = File::open(path.as_ref())?;
 
-        let mut header = [0u8; 12];
+        let mut header = vec![0u8; 12];
         file.read_exact(&mut header)?;
+        let mut header = LumpReader::new(header);
 
-        if &header[0..4] != b"PACK" {
+        let ident = header.read_bytes(4)?;
+        if &*ident != b"PACK" {
             return Err(Error::new(ErrorKind::InvalidData, "invalid signature"));
         }
 
-        let mut offset = i32::from_le_bytes(header[4..8].try_into().unwrap());
-        let num_files = i32::from_le_bytes(header[8..12].try_into().unwrap()) / 64;
+        let file_len = file.metadata()?.len();
+        let mut offset = header.read_i32()?;
+        let directory_size = header.read_i32()?;
+        if !Self::is_within(offset, directory_size, file_len) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "directory out of bounds: offset {}, size {}",
+                    offset, directory_size
+                ),
+            ));
+        }
+        let num_files = directory_size / 64;
 
-        debug!("PACK Header:");
+        debug!("{}: PACK Header:", pak_name);
         debug!(
-            "  Ident:            {}",
-            String::from_utf8(header[0..4].to_vec()).unwrap()
+            "{}:   Ident:            {}",
+            pak_name,
+            String::from_utf8_lossy(&ident)
         );
-        debug!("  Directory Offset: {}", offset);
-        debug!("  Number of files:  {}", num_files);
+        debug!("{}:   Directory Offset: {}", pak_name, offset);
+        debug!("{}:   Number of files:  {}", pak_name, num_files);
 
-        debug!("PACK Content:");
+        debug!("{}: PACK Content:", pak_name);
         let mut directory = HashMap::with_capacity(num_files as usize);
         for _ in 0..num_files {
             file.seek(SeekFrom::Start(offset as u64))?;
 
-            let mut entry_buf = [0u8; 64];
+            let mut entry_buf = vec![0u8; 64];
             file.read_exact(&mut entry_buf)?;
+            let mut entry = LumpReader::new(entry_buf);
 
-            let name = {
-                let len = match entry_buf.iter().position(|&c| c == 0) {
-                    Some(value) => value,
-                    None => 0,
-                };
-                String::from_utf8_lossy(&entry_buf[..len])
-            };
-            let file_offset = i32::from_le_bytes(entry_buf[56..60].try_into().unwrap());
-            let file_size = i32::from_le_bytes(entry_buf[60..64].try_into().unwrap());
+            let name = entry.read_string_fixed(56)?;
+            let file_offset = entry.read_i32()?;
+            let file_size = entry.read_i32()?;
+            if !Self::is_within(file_offset, file_size, file_len) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "entry out of bounds: {}, offset {}, size {}",
+                        name, file_offset, file_size
+                    ),
+                ));
+            }
 
             debug!(
                 "  {:<64} {:<16} {:<16}",
@@ -131,15 +601,62 @@ impl Pak {
         Ok(Self { file, directory })
     }
 
-    pub fn read<S>(&mut self, name: S) -> Result<Vec<u8>, Error>
+    #[cfg(unix)]
+    fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> Result<(), Error> {
+        use std::os::unix::fs::FileExt;
+
+        file.read_exact_at(buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> Result<(), Error> {
+        use std::os::windows::fs::FileExt;
+
+        while !buf.is_empty() {
+            match file.seek_read(buf, offset) {
+                Ok(0) => return Err(Error::from(ErrorKind::UnexpectedEof)),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    fn is_within(offset: i32, size: i32, file_len: u64) -> bool {
+        offset >= 0 && size >= 0 && offset as u64 + size as u64 <= file_len
+    }
+
+    // Names matching a pattern where '*' stands for any run of characters and
+    // '?' for exactly one, sorted alphabetically.
+    pub fn glob<S>(&self, pattern: S) -> Vec<String>
+    where
+        S: AsRef<str>,
+    {
+        let pattern = pattern.as_ref().as_bytes();
+        let mut names: Vec<String> = self
+            .directory
+            .keys()
+            .filter(|name| glob_match(pattern, name.as_bytes()))
+            .cloned()
+            .collect();
+        names.sort();
+        names
+    }
+
+    // Positioned reads leave the file cursor alone, so entries can be read
+    // from several threads at once.
+    pub fn read<S>(&self, name: S) -> Result<Vec<u8>, Error>
     where
         S: AsRef<str>,
     {
         match self.directory.get(name.as_ref()) {
             Some((offset, size)) => {
                 let mut bytes = vec![0; *size as usize];
-                self.file.seek(SeekFrom::Start(*offset as u64))?;
-                self.file.read_exact(&mut bytes)?;
+                Self::read_exact_at(&self.file, &mut bytes, *offset as u64)?;
 
                 Ok(bytes)
             }
@@ -150,3 +667,217 @@ impl Pak {
         }
     }
 }
+
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob_match(&pattern[1..], name) || (!name.is_empty() && glob_match(pattern, &name[1..]))
+        }
+        (Some(b'?'), Some(_)) => glob_match(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => glob_match(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The files one after the other, followed by the directory.
+    fn pak_bytes(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let data_len: usize = files.iter().map(|(_, data)| data.len()).sum();
+        let mut bytes = b"PACK".to_vec();
+        bytes.extend_from_slice(&(12 + data_len as i32).to_le_bytes());
+        bytes.extend_from_slice(&(64 * files.len() as i32).to_le_bytes());
+        for (_, data) in files {
+            bytes.extend_from_slice(data);
+        }
+
+        let mut offset = 12;
+        for (name, data) in files {
+            let mut entry = [0u8; 56];
+            entry[..name.len()].copy_from_slice(name.as_bytes());
+            bytes.extend_from_slice(&entry);
+            bytes.extend_from_slice(&(offset as i32).to_le_bytes());
+            bytes.extend_from_slice(&(data.len() as i32).to_le_bytes());
+            offset += data.len();
+        }
+        bytes
+    }
+
+    // Named after the process so concurrent test runs do not collide.
+    fn write_pak(name: &str, bytes: &[u8]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("quake_rs_{}_{}", std::process::id(), name));
+        std::fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    #[test]
+    fn short_palette_is_an_error() {
+        let err = parse_palette(&[0; 100]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(parse_palette(&[0; 768]).is_ok());
+    }
+
+    #[test]
+    fn fallback_palette_is_the_quake_palette() {
+        let palette = fallback_palette();
+        assert_eq!(palette[0], [0, 0, 0]);
+        assert_eq!(palette[15], [235, 235, 235]);
+        assert_eq!(palette[252], [255, 243, 147]);
+        assert_eq!(palette[254], [255, 255, 255]);
+        assert_eq!(palette[255], [159, 91, 83]);
+    }
+
+    // Tests replacing the globals take turns so they observe their own PAK.
+    static GLOBALS: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    #[test]
+    fn fallback_palette_can_be_selected() {
+        let _globals = GLOBALS.lock().unwrap();
+        let path = write_pak(
+            "short_palette.pak",
+            &pak_bytes(&[("gfx/palette.lmp", &[0x7f; 100])]),
+        );
+
+        let err = reset_with(&path, PaletteSource::Pak).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        reset_with(&path, PaletteSource::Fallback).unwrap();
+        assert_eq!(
+            GLOBAL_PALETTE.read().unwrap().as_deref(),
+            Some(&*fallback_palette())
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn fullbright_mask_marks_the_last_rows() {
+        let indices: Vec<u8> = (0..=255).collect();
+        let mask = fullbright_mask(&indices);
+        assert_eq!(mask.len(), 256);
+        for (index, value) in mask.iter().enumerate() {
+            let expected = if index >= 224 { 0xff } else { 0 };
+            assert_eq!(*value, expected, "index {}", index);
+        }
+    }
+
+    #[test]
+    fn lmp_header_gives_the_dimensions() {
+        let mut bytes = 3u32.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&2u32.to_le_bytes());
+        bytes.extend_from_slice(&[1, 2, 3, 4, 5, 6]);
+
+        let (width, height, indices) = parse_lmp_image(&bytes).unwrap();
+        assert_eq!((width, height), (3, 2));
+        assert_eq!(&*indices, &[1, 2, 3, 4, 5, 6]);
+
+        // Trailing bytes are ignored, missing ones are an error.
+        bytes.push(7);
+        assert_eq!(parse_lmp_image(&bytes).unwrap().2.len(), 6);
+        assert!(parse_lmp_image(&bytes[..12]).is_err());
+        assert!(parse_lmp_image(&bytes[..6]).is_err());
+    }
+
+    #[test]
+    fn entries_past_the_end_are_rejected() {
+        let bytes = pak_bytes(&[("gfx/a.lmp", b"abcd"), ("gfx/b.lmp", b"efgh")]);
+        let path = write_pak("valid.pak", &bytes);
+        let pak = Pak::open(&path).unwrap();
+        assert_eq!(pak.read("gfx/b.lmp").unwrap(), b"efgh");
+        std::fs::remove_file(path).unwrap();
+
+        // The size, then the offset, of the last entry.
+        let size_at = bytes.len() - 4;
+        let offset_at = bytes.len() - 8;
+        for (at, value) in [
+            (size_at, 1000),
+            (size_at, -1),
+            (offset_at, bytes.len() as i32),
+            (offset_at, -4),
+            (8, 1000),
+        ] {
+            let mut corrupt = bytes.clone();
+            corrupt[at..at + 4].copy_from_slice(&value.to_le_bytes());
+            let path = write_pak("corrupt.pak", &corrupt);
+            let err = Pak::open(&path).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{}", err);
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn different_entries_read_concurrently() {
+        let files: Vec<(String, Vec<u8>)> = (0..16u8)
+            .map(|i| (format!("progs/model{}.mdl", i), vec![i; 64 + i as usize]))
+            .collect();
+        let entries: Vec<(&str, &[u8])> = files
+            .iter()
+            .map(|(name, data)| (name.as_str(), data.as_slice()))
+            .collect();
+        let path = write_pak("concurrent.pak", &pak_bytes(&entries));
+        let pak = Pak::open(&path).unwrap();
+
+        std::thread::scope(|scope| {
+            for (name, data) in files.iter() {
+                let pak = &pak;
+                scope.spawn(move || {
+                    for _ in 0..32 {
+                        assert_eq!(&pak.read(name).unwrap(), data);
+                    }
+                });
+            }
+        });
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reads_only_borrow_the_pak() {
+        let path = write_pak(
+            "shared.pak",
+            &pak_bytes(&[("gfx/a.lmp", b"abcd"), ("gfx/b.lmp", b"efgh")]),
+        );
+        let pak = Pak::open(&path).unwrap();
+
+        // Both borrows are alive across both reads, which &mut self forbids.
+        let (first, second) = (&pak, &pak);
+        let a = first.read("gfx/a.lmp").unwrap();
+        let b = second.read("gfx/b.lmp").unwrap();
+        assert_eq!((a.as_slice(), b.as_slice()), (&b"abcd"[..], &b"efgh"[..]));
+        assert_eq!(first.read("gfx/b.lmp").unwrap(), b);
+
+        // Readers only ever take the shared lock of the global.
+        fn assert_sync<T: Sync>() {}
+        assert_sync::<Pak>();
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reset_serves_the_new_pak() {
+        let _globals = GLOBALS.lock().unwrap();
+        let first = write_pak("first.pak", &pak_bytes(&[("gfx/a.lmp", b"first")]));
+        let second = write_pak(
+            "second.pak",
+            &pak_bytes(&[("gfx/a.lmp", b"second"), ("gfx/b.lmp", b"only")]),
+        );
+
+        reset(&first).unwrap();
+        assert_eq!(load_resource!("gfx/a.lmp").unwrap(), b"first");
+        assert!(load_resource!("gfx/b.lmp").is_err());
+
+        reset(&second).unwrap();
+        assert_eq!(load_resource!("gfx/a.lmp").unwrap(), b"second");
+        assert_eq!(load_resource!("gfx/b.lmp").unwrap(), b"only");
+
+        // init leaves an installed PAK alone.
+        init(&first).unwrap();
+        assert_eq!(load_resource!("gfx/a.lmp").unwrap(), b"second");
+
+        std::fs::remove_file(first).unwrap();
+        std::fs::remove_file(second).unwrap();
+    }
+}
@@ -1,112 +1,1041 @@
-use std::{error::Error, time::Duration};
+use std::{error::Error, fmt::Write, io::ErrorKind, thread, time::Duration};
+
+use cgmath::{Deg, EuclideanSpace, InnerSpace, Matrix4, Point3, SquareMatrix, Transform, Vector3};
 
 use crate::{
     alias::{self, Mdl},
-    animation::{Animation, KeyframeAnimationComponent},
+    animation::{self, Animation, KeyframeAnimationComponent},
+    bvh::{Aabb, Bvh},
     camera::Camera,
+    clock::Clock,
+    colormap::ColorRemapComponent,
     entity::Entity,
-    level::Bsp,
+    fence::FenceComponent,
+    level::{Bsp, Contents},
     material::MaterialComponent,
-    mesh::MeshComponent,
+    mesh::{InstanceComponent, MeshComponent, SubMesh, SubMeshComponent},
+    particle::ParticleSystem,
+    render_state::RenderStateComponent,
     renderer::Renderer,
     resource,
-    transform::TransformComponent,
+    shadow::ShadowComponent,
+    skin::SkinComponent,
+    transform::{self, TransformComponent},
 };
 
+// A parsed model and its first skin converted to RGBA.
+type AliasModel = (Mdl, Box<[u8]>);
+// The RGBA pixels of a skin and its fullbright mask.
+type SkinImages = (Box<[u8]>, Box<[u8]>);
+
 pub struct Scene {
+    level: Bsp,
     entities: Vec<Entity>,
+    particles: ParticleSystem,
+    bvh: Bvh,
+    bvh_entities: Vec<usize>,
+    max_draw_distance: Option<f32>,
 }
 
 impl Scene {
+    // A scene with no level geometry and no entities, useful to measure the
+    // bare cost of a frame or to build a scene programmatically.
+    pub fn empty() -> Self {
+        Self {
+            level: Bsp::empty(),
+            entities: Vec::new(),
+            particles: ParticleSystem::new(),
+            bvh: Bvh::new(),
+            bvh_entities: Vec::new(),
+            max_draw_distance: None,
+        }
+    }
+
+    // An empty name gives an empty scene instead of loading a map.
     pub fn load<S>(renderer: &Renderer, name: S) -> Result<Self, Box<dyn Error>>
     where
         S: AsRef<str>,
     {
-        Bsp::load("maps/e1m1.bsp")?;
+        if name.as_ref().is_empty() {
+            return Ok(Self::empty());
+        }
 
-        let mut entity = Self::create_alias_entity(renderer, "progs/knight.mdl")?;
-        let transform = TransformComponent::new();
-        entity.add_component(transform);
+        let names = ["progs/knight.mdl"];
 
-        Ok(Self {
-            entities: vec![entity],
-        })
+        // The level is parsed while the models are being loaded.
+        let level_name = format!("maps/{}.bsp", name.as_ref());
+        let (level, loaded_models) = thread::scope(|scope| {
+            let level = scope.spawn(|| Bsp::load(&level_name).map_err(|err| err.to_string()));
+            let loaded_models = Self::load_alias_models(&names);
+            (level.join().unwrap(), loaded_models)
+        });
+        let level = level?;
+
+        let mut entities = Vec::with_capacity(names.len());
+        for (name, loaded_model) in names.iter().zip(loaded_models) {
+            let (mdl, skin) = loaded_model?;
+            entities.push(Self::create_alias_entity_from(renderer, name, &mdl, &skin)?);
+        }
+
+        let mut scene = Self {
+            level,
+            entities,
+            ..Self::empty()
+        };
+        scene.update_bvh();
+
+        Ok(scene)
     }
 
-    pub fn update(&mut self, queue: &wgpu::Queue, time: &Duration) {
-        for entity in self.entities.iter() {
+    // Returns the id of the new entity.
+    pub fn add_entity(&mut self, entity: Entity) -> usize {
+        self.entities.push(entity);
+        self.update_bvh();
+        self.entities.len() - 1
+    }
+
+    // Motion is integrated over the clock delta while animations are sampled
+    // at its elapsed time, so a manual clock gives reproducible poses.
+    pub fn update(&mut self, queue: &wgpu::Queue, clock: &Clock) {
+        let delta_time = clock.delta();
+        let time = &clock.elapsed();
+        self.particles.update(&delta_time);
+
+        for entity in self.entities.iter_mut() {
+            if let Some(transform_component) = entity.get_component_mut::<TransformComponent>() {
+                transform_component.update(&delta_time);
+            }
+
             if let Some(animation_component) = entity.get_component::<KeyframeAnimationComponent>()
             {
                 if let Some(mesh_component) = entity.get_component::<MeshComponent>() {
-                    let vertices = animation_component.animate(time).unwrap();
-                    mesh_component.update_vertex_buffer(&queue, &vertices);
+                    Self::animate_mesh(queue, mesh_component, animation_component, time);
+                }
+            }
+
+            if let Some(sub_mesh_component) = entity.get_component::<SubMeshComponent>() {
+                for sub_mesh in sub_mesh_component.sub_meshes.iter() {
+                    Self::animate_mesh(queue, &sub_mesh.mesh, &sub_mesh.animation, time);
+                }
+            }
+        }
+
+        for entity in self.entities.iter_mut() {
+            let origin = match entity.get_component::<TransformComponent>() {
+                Some(transform_component) => {
+                    transform::to_quake_coords(transform_component.position())
                 }
+                None => continue,
+            };
+            if let Some(shadow_component) = entity.get_component_mut::<ShadowComponent>() {
+                let floor = origin - Vector3::unit_z() * ShadowComponent::MAX_FLOOR_DISTANCE;
+                shadow_component.floor_distance = self
+                    .level
+                    .trace(origin.into(), floor.into())
+                    .map(|point| origin.z - point[2]);
             }
         }
+
+        for entity in self.entities.iter_mut() {
+            if let Some(animation_component) =
+                entity.get_component_mut::<KeyframeAnimationComponent>()
+            {
+                animation_component.update_blend(time);
+                animation_component.update_frame_events(time);
+            }
+
+            if let Some(skin_component) = entity.get_component_mut::<SkinComponent>() {
+                if skin_component.update(time) {
+                    Self::upload_skin(queue, entity);
+                }
+            }
+        }
+
+        self.update_bvh();
+    }
+
+    // Blends the packed frames on the GPU when possible, otherwise uploads
+    // the animated vertices.
+    fn animate_mesh(
+        queue: &wgpu::Queue,
+        mesh_component: &MeshComponent,
+        animation_component: &KeyframeAnimationComponent,
+        time: &Duration,
+    ) {
+        if mesh_component.packed_frame_count() > 0 {
+            if let Some((current, next, t)) = animation_component.frame_blend(time) {
+                match mesh_component.set_frame_blend(queue, current, next, t) {
+                    Ok(()) => return,
+                    Err(err) => warn!("Failed to blend packed frames: {}", err),
+                }
+            }
+        }
+
+        if let Some(vertices) = animation_component.animate(time) {
+            mesh_component.update_vertex_buffer(queue, &vertices);
+        }
+    }
+
+    pub fn query_frustum(&self, camera: &Camera) -> Vec<&Entity> {
+        self.bvh
+            .query_planes(&camera.frustum_planes())
+            .into_iter()
+            .map(|i| &self.entities[self.bvh_entities[i]])
+            .collect()
+    }
+
+    // Union of all entity bounds in engine coordinates, empty scenes yield a
+    // degenerate box at the origin.
+    pub fn world_bounds(&self) -> ([f32; 3], [f32; 3]) {
+        self.entities
+            .iter()
+            .filter_map(Self::entity_bounds)
+            .reduce(|a, b| a.union(&b))
+            .map(|bounds| (bounds.min.into(), bounds.max.into()))
+            .unwrap_or(([0.0; 3], [0.0; 3]))
     }
 
-    pub fn visible_entities(&self, camera: &Camera) -> &Vec<Entity> {
-        &self.entities
+    pub fn query_ray(&self, start: Vector3<f32>, end: Vector3<f32>) -> Vec<&Entity> {
+        self.bvh
+            .query_segment(start, end)
+            .into_iter()
+            .map(|i| &self.entities[self.bvh_entities[i]])
+            .collect()
+    }
+
+    fn update_bvh(&mut self) {
+        let mut bvh_entities = Vec::with_capacity(self.entities.len());
+        let mut aabbs = Vec::with_capacity(self.entities.len());
+        for (i, entity) in self.entities.iter().enumerate() {
+            if let Some(aabb) = Self::entity_bounds(entity) {
+                bvh_entities.push(i);
+                aabbs.push(aabb);
+            }
+        }
+
+        if bvh_entities != self.bvh_entities || self.bvh.needs_rebuild(&aabbs) {
+            self.bvh = Bvh::build(&aabbs);
+            self.bvh_entities = bvh_entities;
+        }
+    }
+
+    // Instanced entities are bounded by all their copies.
+    fn entity_bounds(entity: &Entity) -> Option<Aabb> {
+        let bounds = entity.get_component::<MeshComponent>()?.bounds()?;
+        let transform_matrix = entity
+            .get_component::<TransformComponent>()
+            .map(|transform_component| transform_component.transform_matrix())
+            .unwrap_or(Matrix4::identity());
+        let instance_matrices = match entity.get_component::<InstanceComponent>() {
+            Some(instance_component) if !instance_component.transforms.is_empty() => {
+                instance_component.transforms.clone()
+            }
+            _ => vec![Matrix4::identity()],
+        };
+
+        Aabb::from_points(instance_matrices.iter().flat_map(|instance_matrix| {
+            let matrix = transform_matrix * instance_matrix;
+            bounds.corners().into_iter().map(move |corner| {
+                matrix
+                    .transform_point(Point3::from_vec(transform::from_quake_coords(corner)))
+                    .to_vec()
+            })
+        }))
+    }
+
+    // Rebuilds an entity from the model in the PAK, keeping its placement
+    // and colors, so edited models can be seen without restarting.
+    pub fn reload_entity_model<S>(
+        &mut self,
+        renderer: &Renderer,
+        id: usize,
+        name: S,
+    ) -> Result<(), Box<dyn Error>>
+    where
+        S: AsRef<str>,
+    {
+        let (mdl, skin) = Self::load_alias_model(name.as_ref())?;
+        self.replace_entity_model(renderer, id, name, &mdl, &skin)
+    }
+
+    // Every model component is replaced, as created by
+    // create_alias_entity_from, so none of the old model state is left.
+    fn replace_entity_model<S>(
+        &mut self,
+        renderer: &Renderer,
+        id: usize,
+        name: S,
+        mdl: &Mdl,
+        skin: &[u8],
+    ) -> Result<(), Box<dyn Error>>
+    where
+        S: AsRef<str>,
+    {
+        let mut reloaded = Self::create_alias_entity_from(renderer, name, mdl, skin)?;
+        let entity = self.entity_mut(id)?;
+
+        if let (Some(transform_component), Some(old_transform_component)) = (
+            reloaded.get_component_mut::<TransformComponent>(),
+            entity.get_component::<TransformComponent>(),
+        ) {
+            transform_component.set_placement(old_transform_component);
+        }
+        if let Some(color_remap_component) = entity.remove_component::<ColorRemapComponent>() {
+            reloaded.add_component(color_remap_component);
+            Self::upload_skin(&renderer.queue, &reloaded);
+        }
+        if let Some(render_state_component) = entity.remove_component::<RenderStateComponent>() {
+            reloaded.add_component(render_state_component);
+        }
+        *entity = reloaded;
+        self.update_bvh();
+
+        Ok(())
+    }
+
+    pub fn entity_skin_count(&self, id: usize) -> usize {
+        self.entities
+            .get(id)
+            .and_then(|entity| entity.get_component::<SkinComponent>())
+            .map_or(0, |skin_component| skin_component.len())
+    }
+
+    // Uploads the chosen skin of the entity model to its material.
+    pub fn set_entity_skin(
+        &mut self,
+        queue: &wgpu::Queue,
+        id: usize,
+        index: usize,
+    ) -> Result<(), Box<dyn Error>> {
+        let entity = self.entity_mut(id)?;
+        let skin_component = entity.get_component_mut::<SkinComponent>().ok_or_else(|| {
+            std::io::Error::new(ErrorKind::NotFound, format!("entity has no skins: {}", id))
+        })?;
+        skin_component.select(index)?;
+        Self::upload_skin(queue, entity);
+
+        Ok(())
+    }
+
+    // Swaps the shirt and pants ramps of the entity skins for the given
+    // colors, as done for players, and uploads the current skin again.
+    pub fn set_entity_colors(
+        &mut self,
+        queue: &wgpu::Queue,
+        id: usize,
+        top_color: u8,
+        bottom_color: u8,
+    ) -> Result<(), Box<dyn Error>> {
+        let entity = self.entity_mut(id)?;
+        entity.add_component(ColorRemapComponent::new(top_color, bottom_color));
+        Self::upload_skin(queue, entity);
+
+        Ok(())
+    }
+
+    // The current skin frame with the player colors applied.
+    fn skin_images(entity: &Entity) -> Option<SkinImages> {
+        let indices = entity.get_component::<SkinComponent>()?.current_indices()?;
+        let indices = match entity.get_component::<ColorRemapComponent>() {
+            Some(color_remap_component) => color_remap_component.remap(indices),
+            None => indices.into(),
+        };
+
+        Some((
+            resource::palette_index_to_rgba(&indices),
+            resource::fullbright_mask(&indices),
+        ))
+    }
+
+    fn upload_skin(queue: &wgpu::Queue, entity: &Entity) {
+        let Some(material_component) = entity.get_component::<MaterialComponent>() else {
+            return;
+        };
+        if let Some((rgba, fullbright_mask)) = Self::skin_images(entity) {
+            material_component.update_texture_image(queue, &rgba);
+            material_component.update_fullbright_mask(queue, &fullbright_mask);
+        }
+    }
+
+    // Sorted by name.
+    pub fn entity_animations(&self, id: usize) -> Vec<&str> {
+        let mut names: Vec<&str> = self
+            .entities
+            .get(id)
+            .and_then(|entity| entity.get_component::<KeyframeAnimationComponent>())
+            .map(|animation_component| {
+                animation_component
+                    .animations
+                    .keys()
+                    .map(String::as_str)
+                    .collect()
+            })
+            .unwrap_or_default();
+        names.sort_unstable();
+        names
+    }
+
+    pub fn set_entity_animation<S>(&mut self, id: usize, name: S) -> Result<(), Box<dyn Error>>
+    where
+        S: AsRef<str>,
+    {
+        let entity = self.entity_mut(id)?;
+        let animation_component = entity
+            .get_component_mut::<KeyframeAnimationComponent>()
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    ErrorKind::NotFound,
+                    format!("entity has no animations: {}", id),
+                )
+            })?;
+        animation_component.set_current_animation(name.as_ref())
+    }
+
+    fn entity_mut(&mut self, id: usize) -> Result<&mut Entity, Box<dyn Error>> {
+        self.entities.get_mut(id).ok_or_else(|| {
+            Box::new(std::io::Error::new(
+                ErrorKind::NotFound,
+                format!("entity not found: {}", id),
+            )) as Box<dyn Error>
+        })
+    }
+
+    // Loads a model once and draws a copy at each transform, in engine
+    // coordinates, with a single instanced draw call. The copies share the
+    // mesh, material and animation, so this suits repeated static models such
+    // as ammo boxes. Returns the id of the new entity.
+    pub fn add_instanced_entity<S>(
+        &mut self,
+        renderer: &Renderer,
+        name: S,
+        transforms: Vec<Matrix4<f32>>,
+    ) -> Result<usize, Box<dyn Error>>
+    where
+        S: AsRef<str>,
+    {
+        let mut entity = Self::create_alias_entity(renderer, name)?;
+        let mut instance_component = InstanceComponent::new();
+        instance_component.transforms = transforms;
+        entity.add_component(instance_component);
+
+        Ok(self.add_entity(entity))
+    }
+
+    // Loads a player model with its shirt and pants in the given colors, see
+    // set_entity_colors. Returns the id of the new entity.
+    pub fn add_player_entity<S>(
+        &mut self,
+        renderer: &Renderer,
+        name: S,
+        top_color: u8,
+        bottom_color: u8,
+    ) -> Result<usize, Box<dyn Error>>
+    where
+        S: AsRef<str>,
+    {
+        let mut entity = Self::create_alias_entity(renderer, name)?;
+        entity.add_component(ColorRemapComponent::new(top_color, bottom_color));
+        Self::upload_skin(&renderer.queue, &entity);
+
+        Ok(self.add_entity(entity))
+    }
+
+    // Loads another model and draws it along with the entity, sharing its
+    // transform.
+    pub fn attach_entity_model<S>(
+        &mut self,
+        renderer: &Renderer,
+        id: usize,
+        name: S,
+    ) -> Result<(), Box<dyn Error>>
+    where
+        S: AsRef<str>,
+    {
+        let mut attached = Self::create_alias_entity(renderer, name)?;
+        let entity = self.entities.get_mut(id).ok_or_else(|| {
+            std::io::Error::new(ErrorKind::NotFound, format!("entity not found: {}", id))
+        })?;
+
+        let sub_mesh = SubMesh {
+            mesh: attached.remove_component::<MeshComponent>().unwrap(),
+            material: attached.remove_component::<MaterialComponent>().unwrap(),
+            animation: attached
+                .remove_component::<KeyframeAnimationComponent>()
+                .unwrap(),
+        };
+        match entity.get_component_mut::<SubMeshComponent>() {
+            Some(sub_mesh_component) => sub_mesh_component.sub_meshes.push(sub_mesh),
+            None => {
+                let mut sub_mesh_component = SubMeshComponent::new();
+                sub_mesh_component.sub_meshes.push(sub_mesh);
+                entity.add_component(sub_mesh_component);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn debug_dump(&self) -> String {
+        let mut dump = String::new();
+        for (id, entity) in self.entities.iter().enumerate() {
+            let _ = write!(dump, "entity {}:", id);
+            for ty in entity.component_types() {
+                let _ = write!(dump, " {}", ty.name());
+            }
+            dump.push('\n');
+
+            if let Some(transform_component) = entity.get_component::<TransformComponent>() {
+                let position = transform_component.position();
+                let orientation = transform_component.orientation();
+                let _ = writeln!(
+                    dump,
+                    "  position: ({}, {}, {}) orientation: ({}, {}, {}, {})",
+                    position.x,
+                    position.y,
+                    position.z,
+                    orientation.s,
+                    orientation.v.x,
+                    orientation.v.y,
+                    orientation.v.z
+                );
+            }
+            if let Some(animation_component) = entity.get_component::<KeyframeAnimationComponent>()
+            {
+                let _ = writeln!(
+                    dump,
+                    "  animation: {} of {}",
+                    animation_component
+                        .current_animation
+                        .as_deref()
+                        .unwrap_or("none"),
+                    animation_component.animations.len()
+                );
+            }
+            match Self::entity_bounds(entity) {
+                Some(bounds) => {
+                    let _ = writeln!(
+                        dump,
+                        "  bounds: ({}, {}, {}) - ({}, {}, {})",
+                        bounds.min.x,
+                        bounds.min.y,
+                        bounds.min.z,
+                        bounds.max.x,
+                        bounds.max.y,
+                        bounds.max.z
+                    );
+                }
+                None => dump.push_str("  bounds: none\n"),
+            }
+        }
+        dump
+    }
+
+    pub fn particles(&self) -> &ParticleSystem {
+        &self.particles
+    }
+
+    pub fn spawn_burst(&mut self, origin: [f32; 3], count: usize, spread: f32) {
+        self.particles.spawn_burst(origin, count, spread);
+    }
+
+    // Where the player starts, in Quake coordinates, with the yaw in degrees.
+    pub fn spawn_point(&self) -> Option<([f32; 3], f32)> {
+        self.level.spawn_point()
+    }
+
+    pub fn camera_contents(&self, camera: &Camera) -> Contents {
+        self.level.leaf_contents(camera.quake_position())
+    }
+
+    // Entities outside the frustum are culled through the BVH, entities with
+    // no bounds are always kept. Those further than max_draw_distance from the
+    // camera are left out too, measured to the closest point of their bounds,
+    // or to their origin when they have none.
+    pub fn visible_entities(&self, camera: &Camera) -> Vec<&Entity> {
+        let mut in_frustum = vec![true; self.entities.len()];
+        for i in self.bvh_entities.iter() {
+            in_frustum[*i] = false;
+        }
+        for i in self.bvh.query_planes(&camera.frustum_planes()) {
+            in_frustum[self.bvh_entities[i]] = true;
+        }
+
+        let eye = camera.eye.to_vec();
+        self.entities
+            .iter()
+            .zip(in_frustum)
+            .filter(|(_, in_frustum)| *in_frustum)
+            .map(|(entity, _)| entity)
+            .filter(|entity| {
+                let Some(max_draw_distance) = self.max_draw_distance else {
+                    return true;
+                };
+                let closest = match Self::entity_bounds(entity) {
+                    Some(bounds) => Vector3::new(
+                        eye.x.clamp(bounds.min.x, bounds.max.x),
+                        eye.y.clamp(bounds.min.y, bounds.max.y),
+                        eye.z.clamp(bounds.min.z, bounds.max.z),
+                    ),
+                    None => match entity.get_component::<TransformComponent>() {
+                        Some(transform_component) => transform_component.position(),
+                        None => return true,
+                    },
+                };
+                (closest - eye).magnitude() <= max_draw_distance
+            })
+            .collect()
+    }
+
+    pub fn set_max_draw_distance(&mut self, max_draw_distance: Option<f32>) {
+        self.max_draw_distance = max_draw_distance;
     }
 
     fn create_alias_entity<S>(renderer: &Renderer, name: S) -> Result<Entity, Box<dyn Error>>
     where
         S: AsRef<str>,
     {
+        let (mdl, skin) = Self::load_alias_model(name.as_ref())?;
+        Self::create_alias_entity_from(renderer, name, &mdl, &skin)
+    }
+
+    // Parses the models on worker threads, GPU resources are still created by
+    // the caller on its own thread.
+    fn load_alias_models(names: &[&str]) -> Vec<Result<AliasModel, String>> {
+        let num_threads = thread::available_parallelism().map_or(1, |n| n.get());
+        let chunk_size = names.len().div_ceil(num_threads).max(1);
+
+        thread::scope(|scope| {
+            let handles: Vec<_> = names
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        chunk
+                            .iter()
+                            .map(|name| {
+                                Self::load_alias_model(name)
+                                    .map_err(|err| format!("{}: {}", name, err))
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap())
+                .collect()
+        })
+    }
+
+    fn load_alias_model(name: &str) -> Result<AliasModel, Box<dyn Error>> {
         let mdl = Mdl::load(name)?;
-        let material_component = MaterialComponent::new(
+        if mdl.num_triangles() == 0 {
+            return Err(Box::new(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("model has no triangles: {}", name),
+            )));
+        }
+
+        // Mdl::deserialize rejects models without skins.
+        let skin = resource::palette_index_to_rgba(&mdl.skins[0].indices(&Duration::ZERO));
+
+        Ok((mdl, skin))
+    }
+
+    fn create_alias_entity_from<S>(
+        renderer: &Renderer,
+        name: S,
+        mdl: &Mdl,
+        skin: &[u8],
+    ) -> Result<Entity, Box<dyn Error>>
+    where
+        S: AsRef<str>,
+    {
+        let material_component = MaterialComponent::new_fullbright(
             renderer,
             &renderer.entity_render_pipeline.texture_bind_group_layout,
             mdl.skin_width,
             mdl.skin_height,
         );
+        material_component.update_texture_image(&renderer.queue, skin);
+        if let Some(skin) = mdl.skins.first() {
+            material_component.update_fullbright_mask(
+                &renderer.queue,
+                &resource::fullbright_mask(&skin.indices(&Duration::ZERO)),
+            );
+        }
 
-        let skin = mdl.skins.first().unwrap();
-        material_component.update_texture_image(
-            &renderer.queue,
-            &resource::palette_index_to_rgba(&skin.indices(&Duration::ZERO)),
-        );
-
+        let mut bounds: Option<Aabb> = None;
+        let mut packed_frames = Vec::with_capacity(mdl.keyframes.len());
         let mut animation_component = KeyframeAnimationComponent::new();
-        for keyframe in mdl.keyframes.iter() {
-            match *keyframe {
-                alias::Keyframe::Static(ref kf) => {
-                    let k =
-                        kf.0.name
-                            .trim_end_matches(|c: char| !c.is_alphabetic())
-                            .to_string();
-                    let animation = match animation_component.animations.get_mut(&k) {
-                        Some(v) => v,
-                        None => {
-                            animation_component
-                                .animations
-                                .insert(k.to_owned(), Animation::new());
-                            animation_component.animations.get_mut(&k).unwrap()
-                        }
-                    };
-                    let vertices = mdl.vertices(&kf.0).to_vec();
-                    animation.add_keyframe(vertices, Duration::from_millis(100));
+        // Static frames are shown for the global frame duration, frame groups
+        // carry their own timing.
+        let frame_duration = animation::frame_duration();
+        let frames = mdl.keyframes.iter().flat_map(|keyframe| match keyframe {
+            alias::Keyframe::Static(kf) => vec![(&kf.0, frame_duration)],
+            alias::Keyframe::Animated(kf) => kf.subframes().collect(),
+        });
+        for (frame, duration) in frames {
+            let k = frame
+                .name
+                .trim_end_matches(|c: char| !c.is_alphabetic())
+                .to_string();
+            let animation = match animation_component.animations.get_mut(&k) {
+                Some(v) => v,
+                None => {
+                    animation_component
+                        .animations
+                        .insert(k.to_owned(), Animation::new());
+                    animation_component.animations.get_mut(&k).unwrap()
                 }
-                alias::Keyframe::Animated(_) => todo!(),
-            }
+            };
+            let vertices = mdl.vertices(frame).to_vec();
+            let frame_bounds =
+                Aabb::from_points(vertices.iter().map(|vertex| Vector3::from(vertex.position)));
+            bounds = match (bounds, frame_bounds) {
+                (Some(a), Some(b)) => Some(a.union(&b)),
+                (a, b) => a.or(b),
+            };
+            animation.add_packed_keyframe(vertices, packed_frames.len(), duration);
+            packed_frames.push(mdl.packed_vertices(frame));
         }
 
-        animation_component.current_animation = Some(
-            animation_component
-                .animations
-                .keys()
-                .next()
-                .unwrap()
-                .to_owned(),
-        );
+        let first_animation = animation_component
+            .animations
+            .keys()
+            .next()
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("model has no frames: {}", name.as_ref()),
+                )
+            })?
+            .to_owned();
+        animation_component.set_current_animation(&first_animation)?;
         let animation_vertices = animation_component.animate(&Duration::ZERO).unwrap();
-        let mesh_component = MeshComponent::new(renderer, animation_vertices.len());
+        let mut mesh_component =
+            MeshComponent::with_packed_frames(renderer, &packed_frames, mdl.scale, mdl.origin)?;
+        // Normals and texture coordinates for the packed frames.
+        mesh_component.update_vertex_buffer(&renderer.queue, &animation_vertices);
+        if let Some(bounds) = bounds {
+            mesh_component.set_bounds(bounds);
+        }
 
         let mut entity = Entity::new();
+        entity.add_component(Self::alias_transform(mdl));
         entity.add_component(animation_component);
         entity.add_component(material_component);
         entity.add_component(mesh_component);
+        entity.add_component(ShadowComponent::new());
+        entity.add_component(SkinComponent::new(mdl.skins.to_vec()));
+        if mdl.flags.is_holey() {
+            entity.add_component(FenceComponent::new());
+        }
 
         Ok(entity)
     }
+
+    // The MDL scale and origin are already baked into the vertex positions,
+    // which line up with the entity origin as they do in the original engine,
+    // so the model is placed by the entity transform alone.
+    fn alias_transform(mdl: &Mdl) -> TransformComponent {
+        let mut transform_component = TransformComponent::new();
+        // Pickups spin at 100 degrees a second like in the original engine.
+        if mdl.flags.is_rotating() {
+            transform_component.set_angular_velocity(Vector3::unit_y(), Deg(100.0));
+        }
+        transform_component
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        alias::{
+            tests::{deserialize, mdl_bytes, static_frame},
+            ModelFlags, Skin, StaticSkin,
+        },
+        material::tests::texture_rgba,
+        renderer::tests::{entity_pass_draws, headless_renderer},
+    };
+
+    fn init_palette() {
+        resource::GLOBAL_PALETTE
+            .write()
+            .unwrap()
+            .get_or_insert_with(resource::fallback_palette);
+    }
+
+    #[test]
+    fn player_colors_apply_to_the_skin() {
+        init_palette();
+        let indices: Box<[u8]> = (0..=255).collect();
+        let mut entity = Entity::new();
+        entity.add_component(SkinComponent::new(vec![Skin::Static(StaticSkin::new(
+            indices.clone(),
+        ))]));
+
+        let (rgba, _) = Scene::skin_images(&entity).unwrap();
+        assert_eq!(rgba, resource::palette_index_to_rgba(&indices));
+
+        let color_remap_component = ColorRemapComponent::new(4, 13);
+        let remapped = color_remap_component.remap(&indices);
+        entity.add_component(color_remap_component);
+        let (rgba, fullbright_mask) = Scene::skin_images(&entity).unwrap();
+        assert_eq!(rgba, resource::palette_index_to_rgba(&remapped));
+        assert_eq!(fullbright_mask, resource::fullbright_mask(&remapped));
+        assert_eq!(
+            &rgba[16 * 4..17 * 4],
+            &resource::palette_index_to_rgba(&[64])[..]
+        );
+    }
+
+    #[test]
+    fn alias_models_sit_on_the_entity_origin() {
+        let vertices = [[0, 0, 0, 0], [1, 0, 0, 0], [0, 1, 12, 0]];
+        let data = mdl_bytes(
+            [2.0, 2.0, 2.0],
+            [10.0, -4.0, -24.0],
+            3,
+            &[[0, 1, 2]],
+            &[static_frame(&vertices)],
+        );
+        let mdl = deserialize(data).unwrap();
+
+        let mut transform_component = Scene::alias_transform(&mdl);
+        let entity_origin = Vector3::new(100.0, 200.0, 300.0);
+        transform_component.translate(transform::from_quake_coords(entity_origin));
+
+        let matrix = transform_component.transform_matrix();
+        let frame = &mdl.vertices(mdl.keyframes[0].frame(&Duration::ZERO));
+        for vertex in frame.iter() {
+            let position = Vector3::from(vertex.position);
+            let placed = matrix
+                .transform_point(Point3::from_vec(transform::from_quake_coords(position)))
+                .to_vec();
+            let expected = transform::from_quake_coords(entity_origin + position);
+            assert!((placed - expected).magnitude() < 1e-4, "{:?}", placed);
+        }
+        let lowest = frame
+            .iter()
+            .map(|vertex| vertex.position[2])
+            .fold(f32::INFINITY, f32::min);
+        assert_eq!(lowest, -24.0);
+    }
+
+    #[test]
+    fn camera_in_water_reports_water() {
+        let scene = Scene {
+            level: crate::level::tests::water_bsp(),
+            ..Scene::empty()
+        };
+        let mut camera = Camera::new(1280, 720);
+
+        camera.set_quake_view([64.0, 0.0, -16.0], 0.0);
+        assert_eq!(scene.camera_contents(&camera), Contents::Water);
+
+        camera.set_quake_view([64.0, 0.0, 16.0], 0.0);
+        assert_eq!(scene.camera_contents(&camera), Contents::Empty);
+    }
+
+    #[test]
+    fn reloading_rebuilds_the_entity() {
+        let Some(renderer) = headless_renderer() else {
+            return;
+        };
+        let mut data = mdl_bytes(
+            [1.0; 3],
+            [0.0; 3],
+            3,
+            &[[0, 1, 2]],
+            &[static_frame(&[[0, 0, 0, 0], [1, 0, 0, 0], [0, 1, 0, 0]])],
+        );
+        // The flags of the header.
+        let flags = ModelFlags::HOLEY.0 | ModelFlags::ROTATE.0;
+        data[76..80].copy_from_slice(&flags.to_le_bytes());
+        let holey = deserialize(data).unwrap();
+        let solid = deserialize(mdl_bytes(
+            [1.0; 3],
+            [0.0; 3],
+            4,
+            &[[0, 1, 2], [0, 2, 3]],
+            &[static_frame(&[
+                [0, 0, 0, 0],
+                [1, 0, 0, 0],
+                [1, 1, 0, 0],
+                [0, 1, 0, 0],
+            ])],
+        ))
+        .unwrap();
+        let skin = vec![0; 2 * 2 * 4];
+
+        let mut scene = Scene::empty();
+        let mut entity =
+            Scene::create_alias_entity_from(&renderer, "holey.mdl", &holey, &skin).unwrap();
+        let position = Vector3::new(1.0, 2.0, 3.0);
+        let transform_component = entity.get_component_mut::<TransformComponent>().unwrap();
+        transform_component.translate(position);
+        transform_component.rotate(Vector3::unit_y(), Deg(90.0));
+        let orientation = transform_component.orientation();
+        assert!(entity.get_component::<FenceComponent>().is_some());
+        let id = scene.add_entity(entity);
+        // Meshes have three vertices per triangle.
+        assert_eq!(
+            scene.entities[id]
+                .get_component::<MeshComponent>()
+                .unwrap()
+                .vertex_count,
+            3
+        );
+
+        scene
+            .replace_entity_model(&renderer, id, "solid.mdl", &solid, &skin)
+            .unwrap();
+        let entity = &scene.entities[id];
+        assert_eq!(
+            entity
+                .get_component::<MeshComponent>()
+                .unwrap()
+                .vertex_count,
+            6
+        );
+        assert!(entity.get_component::<FenceComponent>().is_none());
+        assert!(entity.get_component::<ShadowComponent>().is_some());
+        let transform_component = entity.get_component::<TransformComponent>().unwrap();
+        assert_eq!(transform_component.position(), position);
+        assert_eq!(transform_component.orientation(), orientation);
+
+        // The solid model does not rotate.
+        let mut clock = Clock::manual();
+        clock.advance(Duration::from_secs(1));
+        scene.update(&renderer.queue, &clock);
+        let entity = &scene.entities[id];
+        let transform_component = entity.get_component::<TransformComponent>().unwrap();
+        assert_eq!(transform_component.orientation(), orientation);
+    }
+
+    #[test]
+    fn entities_past_the_draw_distance_are_culled() {
+        let mut camera = Camera::new(1280, 720);
+        camera.set_quake_view([0.0, 0.0, 0.0], 0.0);
+        let eye = camera.eye.to_vec();
+
+        let mut scene = Scene::empty();
+        for distance in [50.0, 150.0, 400.0] {
+            let mut transform_component = TransformComponent::new();
+            transform_component.translate(eye + Vector3::new(0.0, 0.0, -distance));
+            let mut entity = Entity::new();
+            entity.add_component(transform_component);
+            scene.add_entity(entity);
+        }
+        // Nothing tells where it is, so it is always kept.
+        scene.add_entity(Entity::new());
+
+        let positions = |scene: &Scene| -> Vec<Option<f32>> {
+            scene
+                .visible_entities(&camera)
+                .iter()
+                .map(|entity| {
+                    entity
+                        .get_component::<TransformComponent>()
+                        .map(|transform_component| {
+                            (transform_component.position() - eye).magnitude()
+                        })
+                })
+                .collect()
+        };
+        assert_eq!(
+            positions(&scene),
+            [Some(50.0), Some(150.0), Some(400.0), None]
+        );
+
+        scene.set_max_draw_distance(Some(200.0));
+        assert_eq!(positions(&scene), [Some(50.0), Some(150.0), None]);
+        scene.set_max_draw_distance(Some(150.0));
+        assert_eq!(positions(&scene), [Some(50.0), Some(150.0), None]);
+        scene.set_max_draw_distance(Some(10.0));
+        assert_eq!(positions(&scene), [None]);
+        scene.set_max_draw_distance(None);
+        assert_eq!(positions(&scene).len(), 4);
+    }
+
+    #[test]
+    fn empty_scene_issues_no_draws() {
+        let scene = Scene::empty();
+        let Some(renderer) = headless_renderer() else {
+            return;
+        };
+        let camera = Camera::new(renderer.config.width as i32, renderer.config.height as i32);
+
+        let entities = scene.visible_entities(&camera);
+        assert!(entities.is_empty());
+        assert_eq!(entity_pass_draws(&renderer, &entities), 0);
+    }
+
+    #[test]
+    fn debug_dump_lists_components_and_placement() {
+        let mut transform_component = TransformComponent::new();
+        transform_component.translate(Vector3::new(1.0, 2.0, 3.0));
+        let mut entity = Entity::new();
+        entity.add_component(transform_component);
+        let mut scene = Scene::empty();
+        scene.add_entity(entity);
+
+        let dump = scene.debug_dump();
+        assert_eq!(
+            dump.lines().collect::<Vec<_>>(),
+            [
+                "entity 0: Transform",
+                "  position: (1, 2, 3) orientation: (1, 0, 0, 0)",
+                "  bounds: none",
+            ]
+        );
+    }
+
+    #[test]
+    fn switching_skin_uploads_it_again() {
+        init_palette();
+        let Some(renderer) = headless_renderer() else {
+            return;
+        };
+        let skins: Vec<Box<[u8]>> = vec![(0..64).collect(), (64..128).collect()];
+        let mut entity = Entity::new();
+        entity.add_component(MaterialComponent::new(
+            &renderer,
+            &renderer.entity_render_pipeline.texture_bind_group_layout,
+            64,
+            1,
+        ));
+        entity.add_component(SkinComponent::new(
+            skins
+                .iter()
+                .map(|indices| Skin::Static(StaticSkin::new(indices.clone())))
+                .collect(),
+        ));
+        let mut scene = Scene::empty();
+        let id = scene.add_entity(entity);
+        let uploaded = |scene: &Scene| {
+            let material_component = scene.entities[id]
+                .get_component::<MaterialComponent>()
+                .unwrap();
+            texture_rgba(&renderer, material_component)
+        };
+
+        scene.set_entity_skin(&renderer.queue, id, 0).unwrap();
+        assert_eq!(
+            uploaded(&scene),
+            &resource::palette_index_to_rgba(&skins[0])[..]
+        );
+        scene.set_entity_skin(&renderer.queue, id, 1).unwrap();
+        assert_eq!(
+            uploaded(&scene),
+            &resource::palette_index_to_rgba(&skins[1])[..]
+        );
+        assert!(scene.set_entity_skin(&renderer.queue, id, 2).is_err());
+        assert_eq!(
+            uploaded(&scene),
+            &resource::palette_index_to_rgba(&skins[1])[..]
+        );
+    }
 }
@@ -0,0 +1,23 @@
+// Planar shadow cast straight down onto the floor below an alias model. The
+// floor distance is measured in world units from the entity origin and is
+// None when nothing was found below it.
+pub struct ShadowComponent {
+    pub floor_distance: Option<f32>,
+}
+
+impl ShadowComponent {
+    // How far below the entity the floor is searched for.
+    pub const MAX_FLOOR_DISTANCE: f32 = 4096.0;
+
+    pub fn new() -> Self {
+        Self {
+            floor_distance: None,
+        }
+    }
+}
+
+impl Default for ShadowComponent {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,48 @@
+struct Shadow {
+    matrix         : mat4x4f,
+    floor_distance : vec4<f32>,
+}
+
+@group(0) @binding(0) var<uniform> view_proj : mat4x4f;
+@group(1) @binding(0) var<uniform> shadow    : Shadow;
+
+struct VertexInput {
+    @location(0) position : vec3<f32>,
+    @location(1) normal   : vec3<f32>,
+    @location(2) texcoord : vec2<f32>,
+    @location(3) current  : vec4<u32>,
+    @location(4) next     : vec4<u32>,
+    @location(5) scale    : vec4<f32>,
+    @location(6) origin   : vec4<f32>,
+}
+
+fn from_quake_coords(coords: vec3<f32>) -> vec3<f32> {
+  return vec3<f32>(-coords.y, coords.z, -coords.x);
+}
+
+/* Lifted slightly above the floor to avoid z-fighting with it.  */
+const SHADOW_OFFSET : f32 = 1.0;
+const SHADOW_ALPHA  : f32 = 0.5;
+
+/* Packed frames hold byte positions in the model bounds, rebuilt with the
+ * model scale and origin and interpolated between the current and next frame.
+ * Meshes without them leave origin.w clear and use the vertex position.  */
+fn vertex_position(in: VertexInput) -> vec3<f32> {
+    if (in.origin.w == 0.0) {
+        return in.position;
+    }
+    let current = vec3<f32>(in.current.xyz) * in.scale.xyz + in.origin.xyz;
+    let next = vec3<f32>(in.next.xyz) * in.scale.xyz + in.origin.xyz;
+    return mix(current, next, in.scale.w);
+}
+
+@vertex fn vs_main(in: VertexInput) -> @builtin(position) vec4<f32> {
+    let position = vec3<f32>(vertex_position(in).xy, SHADOW_OFFSET - shadow.floor_distance.x);
+
+    return view_proj * shadow.matrix * vec4<f32>(from_quake_coords(position), 1.0);
+}
+
+
+@fragment fn fs_main() -> @location(0) vec4<f32> {
+    return vec4<f32>(0.0, 0.0, 0.0, SHADOW_ALPHA);
+}
@@ -0,0 +1,79 @@
+use std::{error::Error, io::ErrorKind, time::Duration};
+
+use crate::alias::Skin;
+
+// Every skin of an alias model, so the one shown by the material can be
+// switched at runtime. Animated skins cycle their frames in update.
+pub struct SkinComponent {
+    skins: Vec<Skin>,
+    current: usize,
+    current_frame: usize,
+}
+
+impl SkinComponent {
+    pub fn new(skins: Vec<Skin>) -> Self {
+        Self {
+            skins,
+            current: 0,
+            current_frame: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.skins.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.skins.is_empty()
+    }
+
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    // Palette indices of the frame shown of the current skin.
+    pub fn current_indices(&self) -> Option<&[u8]> {
+        self.skins
+            .get(self.current)
+            .map(|skin| skin.frame(self.current_frame))
+    }
+
+    // Advances an animated skin, returning whether the frame to show changed
+    // and the material needs the current_indices again.
+    pub fn update(&mut self, time: &Duration) -> bool {
+        let Some(skin) = self.skins.get(self.current) else {
+            return false;
+        };
+        let frame = skin.frame_index(time);
+        if frame == self.current_frame {
+            return false;
+        }
+        self.current_frame = frame;
+
+        true
+    }
+
+    // Makes the skin current, its first frame then shows in current_indices.
+    pub fn select(&mut self, index: usize) -> Result<(), Box<dyn Error>> {
+        self.skins.get(index).ok_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "skin {} not found, the model has {}",
+                    index,
+                    self.skins.len()
+                ),
+            )
+        })?;
+        self.current = index;
+        self.current_frame = 0;
+
+        Ok(())
+    }
+}
+
+impl Default for SkinComponent {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
@@ -0,0 +1,27 @@
+struct VertexOutput {
+    @builtin(position) clip : vec4<f32>,
+    @location(0)       ndc  : vec2<f32>,
+}
+
+/* A single triangle covering the whole screen, placed at the far plane so
+ * anything drawn by the entity pass stays in front of it.  */
+@vertex fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    var out : VertexOutput;
+
+    let ndc = vec2<f32>(f32(index / 2u) * 4.0 - 1.0, f32(index % 2u) * 4.0 - 1.0);
+    out.clip = vec4<f32>(ndc, 1.0, 1.0);
+    out.ndc = ndc;
+
+    return out;
+}
+
+
+@group(0) @binding(0) var<uniform> inverse_rotation_proj : mat4x4f;
+@group(0) @binding(1) var sky_texture : texture_cube<f32>;
+@group(0) @binding(2) var sky_sampler : sampler;
+
+@fragment fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let direction = inverse_rotation_proj * vec4<f32>(in.ndc, 1.0, 1.0);
+
+    return textureSample(sky_texture, sky_sampler, direction.xyz / direction.w);
+}
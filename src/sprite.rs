@@ -0,0 +1,208 @@
+use std::{error::Error, io::ErrorKind, time::Duration};
+
+use crate::{alias, load_resource, lump::LumpReader, resource::GLOBAL_RESOURCES};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SpriteType {
+    // Faces the view plane and stays upright, e.g. torches.
+    ParallelUpright,
+    // Faces the viewer and stays upright.
+    FacingUpright,
+    // Faces the view plane, the most common kind.
+    Parallel,
+    // Keeps the orientation of its entity.
+    Oriented,
+    // Faces the view plane rotated by the entity roll.
+    ParallelOriented,
+}
+
+impl SpriteType {
+    fn from_raw(sprite_type: i32) -> Result<Self, Box<dyn Error>> {
+        match sprite_type {
+            0 => Ok(SpriteType::ParallelUpright),
+            1 => Ok(SpriteType::FacingUpright),
+            2 => Ok(SpriteType::Parallel),
+            3 => Ok(SpriteType::Oriented),
+            4 => Ok(SpriteType::ParallelOriented),
+            ty => Err(Box::new(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid sprite type: {}", ty),
+            ))),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Spr {
+    pub sprite_type: SpriteType,
+    // Radius of a sphere around the origin enclosing every frame.
+    pub bounding_radius: f32,
+    // Largest frame size.
+    pub width: u32,
+    pub height: u32,
+    pub beam_length: f32,
+    // 0 when frames are synchronized across entities, 1 when randomized.
+    pub sync_type: i32,
+    pub frames: Box<[SpriteFrame]>,
+}
+
+impl Spr {
+    pub fn load<S>(name: S) -> Result<Self, Box<dyn Error>>
+    where
+        S: AsRef<str>,
+    {
+        debug!("Loading SPR file {}", name.as_ref());
+
+        Spr::deserialize(
+            name.as_ref(),
+            &mut LumpReader::new(load_resource!(name.as_ref())?),
+        )
+    }
+
+    const HEADER_SIZE: u64 = 36;
+
+    // The name only identifies the sprite in the log.
+    fn deserialize(name: &str, reader: &mut LumpReader) -> Result<Self, Box<dyn Error>> {
+        if reader.remaining() < Self::HEADER_SIZE {
+            return Err(Box::new(std::io::Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("truncated header: {} bytes", reader.remaining()),
+            )));
+        }
+
+        let ident = reader.read_i32()?;
+        if ident != 0x50534449 {
+            return Err(Box::new(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid signature: {:x}", ident),
+            )));
+        }
+
+        let version = reader.read_i32()?;
+        if version != 1 {
+            return Err(Box::new(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid version: {}", version),
+            )));
+        }
+
+        let sprite_type = SpriteType::from_raw(reader.read_i32()?)?;
+        let bounding_radius = reader.read_f32()?;
+        let width = reader.read_i32()?;
+        let height = reader.read_i32()?;
+        let num_frames = reader.read_i32()?;
+        let beam_length = reader.read_f32()?;
+        let sync_type = reader.read_i32()?;
+
+        debug!("{}: SPR Header:", name);
+        debug!("{}:   Type:                  {:?}", name, sprite_type);
+        debug!("{}:   Bounding Radius:       {:?}", name, bounding_radius);
+        debug!("{}:   Width:                 {:?}", name, width);
+        debug!("{}:   Height:                {:?}", name, height);
+        debug!("{}:   Number of Frames:      {:?}", name, num_frames);
+        debug!("{}:   Beam Length:           {:?}", name, beam_length);
+        debug!("{}:   Sync Type:             {:?}", name, sync_type);
+
+        if num_frames <= 0 {
+            return Err(Box::new(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid number of frames: {}", num_frames),
+            )));
+        }
+        let frames = reader
+            .read_entries(num_frames as usize, SpriteFrame::deserialize)
+            .map_err(|err| format!("frames: {}", err))?;
+
+        Ok(Self {
+            sprite_type,
+            bounding_radius,
+            width: width.max(0) as u32,
+            height: height.max(0) as u32,
+            beam_length,
+            sync_type,
+            frames,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum SpriteFrame {
+    Single(SpritePicture),
+    Group(SpriteGroup),
+}
+
+impl SpriteFrame {
+    // Picture shown at the given time, groups loop over their pictures.
+    pub fn picture(&self, time: &Duration) -> &SpritePicture {
+        match *self {
+            SpriteFrame::Single(ref picture) => picture,
+            SpriteFrame::Group(ref group) => {
+                let index = alias::looped_frame_index(
+                    group.pictures.iter().map(|(duration, _)| *duration),
+                    time,
+                );
+                &group.pictures[index].1
+            }
+        }
+    }
+
+    fn deserialize(reader: &mut LumpReader) -> Result<Self, Box<dyn Error>> {
+        match reader.read_i32()? {
+            0 => Ok(SpriteFrame::Single(SpritePicture::deserialize(reader)?)),
+            _ => Ok(SpriteFrame::Group(SpriteGroup::deserialize(reader)?)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SpriteGroup {
+    pictures: Box<[(Duration, SpritePicture)]>,
+}
+
+impl SpriteGroup {
+    fn deserialize(reader: &mut LumpReader) -> Result<Self, Box<dyn Error>> {
+        let num_pictures = alias::read_group_size(reader, "sprite group")?;
+        let durations = alias::read_durations(reader, num_pictures)?;
+        let pictures = reader.read_entries(num_pictures, SpritePicture::deserialize)?;
+
+        Ok(Self {
+            pictures: durations.iter().copied().zip(pictures.into_vec()).collect(),
+        })
+    }
+}
+
+// Palette indices, see resource::palette_index_to_rgba. Index 255 is
+// transparent.
+#[derive(Clone, Debug)]
+pub struct SpritePicture {
+    // Offset of the top left corner from the sprite origin, y up.
+    pub origin: [i32; 2],
+    pub width: u32,
+    pub height: u32,
+    pub indices: Box<[u8]>,
+}
+
+impl SpritePicture {
+    fn deserialize(reader: &mut LumpReader) -> Result<Self, Box<dyn Error>> {
+        let origin = [reader.read_i32()?, reader.read_i32()?];
+        let width = reader.read_i32()?;
+        let height = reader.read_i32()?;
+        let size = usize::try_from(width)
+            .ok()
+            .zip(usize::try_from(height).ok())
+            .and_then(|(width, height)| width.checked_mul(height))
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid picture size: {}x{}", width, height),
+                )
+            })?;
+
+        Ok(Self {
+            origin,
+            width: width as u32,
+            height: height as u32,
+            indices: reader.read_bytes(size)?,
+        })
+    }
+}
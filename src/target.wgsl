@@ -18,11 +18,93 @@ struct VertexOutput {
 }
 
 
+struct Light {
+    inverse_view_proj : mat4x4f,
+    eye               : vec4<f32>,
+    direction         : vec4<f32>,
+    color             : vec4<f32>,
+}
+
+/* Must match TargetPipeline::MAX_LIGHTS.  */
+const MAX_LIGHTS : u32 = 32u;
+
+struct PointLight {
+    position : vec4<f32>,
+    color    : vec4<f32>,
+}
+
+struct PointLights {
+    count  : vec4<u32>,
+    lights : array<PointLight, MAX_LIGHTS>,
+}
+
 @group(0) @binding(0) var albedo_texture : texture_2d<f32>;
 @group(0) @binding(1) var normal_texture : texture_2d<f32>;
-@group(0) @binding(2) var depth_texture  : texture_2d<f32>;
+@group(0) @binding(2) var depth_texture  : texture_depth_2d;
 @group(0) @binding(3) var target_sampler : sampler;
+@group(0) @binding(4) var<uniform> screen_tint : vec4<f32>;
+@group(0) @binding(5) var<uniform> ambient     : vec4<f32>;
+@group(0) @binding(6) var<uniform> light       : Light;
+@group(0) @binding(7) var<uniform> point_lights : PointLights;
+
+/* The texture coordinates grow downwards while clip space grows upwards.  */
+fn world_position(texcoord: vec2<f32>, depth: f32) -> vec3<f32> {
+    let clip = vec4<f32>(texcoord.x * 2.0 - 1.0, 1.0 - texcoord.y * 2.0, depth, 1.0);
+    let world = light.inverse_view_proj * clip;
+    return world.xyz / world.w;
+}
+
+const SPECULAR_EXPONENT : f32 = 32.0;
+
+/* Blinn-Phong with the GBuffer normals, which the alias pass writes biased
+ * and in engine coordinates.  */
+fn directional_light(texcoord: vec2<f32>, depth: f32) -> vec3<f32> {
+    let normal = normalize(textureSample(normal_texture, target_sampler, texcoord).xyz * 2.0 - 1.0);
+    let to_light = -light.direction.xyz;
+    let diffuse = max(dot(normal, to_light), 0.0);
+
+    let to_eye = normalize(light.eye.xyz - world_position(texcoord, depth));
+    let half_vector = normalize(to_light + to_eye);
+    let specular = light.color.a * pow(max(dot(normal, half_vector), 0.0), SPECULAR_EXPONENT) * f32(diffuse > 0.0);
+
+    return light.color.rgb * (diffuse + specular);
+}
+
+/* Point lights fade linearly to nothing at their radius, kept in
+ * position.w.  */
+fn point_lights_light(texcoord: vec2<f32>, depth: f32) -> vec3<f32> {
+    let normal = normalize(textureSample(normal_texture, target_sampler, texcoord).xyz * 2.0 - 1.0);
+    let position = world_position(texcoord, depth);
+
+    var lighting = vec3<f32>(0.0);
+    for (var i = 0u; i < min(point_lights.count.x, MAX_LIGHTS); i++) {
+        let point_light = point_lights.lights[i];
+        let to_light = point_light.position.xyz - position;
+        let distance = length(to_light);
+        let attenuation = max(1.0 - distance / max(point_light.position.w, 0.0001), 0.0);
+        let diffuse = max(dot(normal, to_light / max(distance, 0.0001)), 0.0);
+        lighting += point_light.color.rgb * diffuse * attenuation;
+    }
+
+    return lighting;
+}
 
 @fragment fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    return textureSample(albedo_texture, target_sampler, in.texcoord);
+    let albedo = textureSample(albedo_texture, target_sampler, in.texcoord);
+
+    let size = vec2<i32>(textureDimensions(depth_texture));
+    let texel = clamp(vec2<i32>(in.texcoord * vec2<f32>(size)), vec2<i32>(0), size - 1);
+    let depth = textureLoad(depth_texture, texel, 0);
+
+    /* Dynamic lights are added on top of the ambient floor, so nothing is
+     * ever darker than it.  Texels at the far plane hold no geometry and
+     * are left unlit.  */
+    var lighting = ambient.rgb;
+    if (depth < 1.0) {
+        lighting += directional_light(in.texcoord, depth);
+        lighting += point_lights_light(in.texcoord, depth);
+    }
+    let color = albedo.rgb * lighting;
+
+    return vec4<f32>(mix(color, screen_tint.rgb, screen_tint.a), albedo.a);
 }
@@ -0,0 +1,42 @@
+use std::sync::Mutex;
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+// Every record logged while testing, from all threads. Tests running in
+// parallel share it, so they look for the messages they expect rather than
+// counting them.
+static RECORDS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+struct CaptureLogger;
+
+impl Log for CaptureLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        RECORDS
+            .lock()
+            .unwrap()
+            .push(format!("{} {}", record.level(), record.args()));
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CaptureLogger = CaptureLogger;
+
+// Installs the capturing logger, the first call wins.
+pub fn init() {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(LevelFilter::Debug);
+    }
+}
+
+pub fn contains(pattern: &str) -> bool {
+    RECORDS
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|record| record.contains(pattern))
+}
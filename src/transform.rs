@@ -1,20 +1,43 @@
-use cgmath::{ElementWise, Matrix4, Quaternion, Rad, Rotation3, Vector3, Zero};
+use std::time::Duration;
+
+use cgmath::{ElementWise, Matrix4, One, Quaternion, Rad, Rotation3, Vector3, VectorSpace, Zero};
+
+// The Quake coordinate system defines X as the longitudinal axis, Y as the
+// lateral axis, and Z as the vertical axis; these mirror from_quake_coords in
+// the shaders.
+pub fn from_quake_coords(coords: Vector3<f32>) -> Vector3<f32> {
+    Vector3::new(-coords.y, coords.z, -coords.x)
+}
+
+pub fn to_quake_coords(coords: Vector3<f32>) -> Vector3<f32> {
+    Vector3::new(-coords.z, -coords.x, coords.y)
+}
 
 pub struct TransformComponent {
     position: Vector3<f32>,
     orientation: Quaternion<f32>,
     scale: Vector3<f32>,
+    angular_velocity: Option<(Vector3<f32>, Rad<f32>)>,
 }
 
 impl TransformComponent {
     pub fn new() -> Self {
         Self {
             position: Vector3::zero(),
-            orientation: Quaternion::zero(),
+            orientation: Quaternion::one(),
             scale: Vector3::new(1.0, 1.0, 1.0),
+            angular_velocity: None,
         }
     }
 
+    pub fn position(&self) -> Vector3<f32> {
+        self.position
+    }
+
+    pub fn orientation(&self) -> Quaternion<f32> {
+        self.orientation
+    }
+
     pub fn translate(&mut self, translation: Vector3<f32>) {
         self.position += translation;
     }
@@ -24,10 +47,45 @@ impl TransformComponent {
         self.orientation = self.orientation * rotation;
     }
 
+    pub fn set_angular_velocity<A: Into<Rad<f32>>>(&mut self, axis: Vector3<f32>, speed: A) {
+        self.angular_velocity = Some((axis, speed.into()));
+    }
+
+    pub fn update(&mut self, delta_time: &Duration) {
+        if let Some((axis, speed)) = self.angular_velocity {
+            self.rotate(axis, speed * delta_time.as_secs_f32());
+        }
+    }
+
     pub fn scale(&mut self, scale: Vector3<f32>) {
         self.scale = self.scale.mul_element_wise(scale);
     }
 
+    pub fn set_scale(&mut self, scale: Vector3<f32>) {
+        self.scale = scale;
+    }
+
+    // Takes the position, orientation and scale of other, keeping the own
+    // angular velocity.
+    pub fn set_placement(&mut self, other: &Self) {
+        self.position = other.position;
+        self.orientation = other.orientation;
+        self.scale = other.scale;
+    }
+
+    // Lerps the position and scale and slerps the orientation, t is clamped to
+    // [0, 1] so 0 yields a and 1 yields b. The angular velocity is taken from
+    // a.
+    pub fn interpolate(a: &Self, b: &Self, t: f32) -> Self {
+        let t = t.clamp(0.0, 1.0);
+        Self {
+            position: a.position.lerp(b.position, t),
+            orientation: a.orientation.slerp(b.orientation, t),
+            scale: a.scale.lerp(b.scale, t),
+            angular_velocity: a.angular_velocity,
+        }
+    }
+
     pub fn transform_matrix(&self) -> Matrix4<f32> {
         let translation_matrix = Matrix4::from_translation(self.position);
         let rotation_matrix = Matrix4::from(self.orientation);
@@ -36,3 +94,68 @@ impl TransformComponent {
         translation_matrix * rotation_matrix * scale_matrix
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{Deg, InnerSpace, SquareMatrix};
+
+    use super::*;
+
+    #[test]
+    fn new_transform_is_identity() {
+        assert_eq!(
+            TransformComponent::new().transform_matrix(),
+            Matrix4::identity()
+        );
+    }
+
+    #[test]
+    fn scale_accumulates() {
+        let mut transform_component = TransformComponent::new();
+        transform_component.scale(Vector3::new(2.0, 2.0, 2.0));
+        transform_component.scale(Vector3::new(2.0, 2.0, 2.0));
+        assert_eq!(transform_component.scale, Vector3::new(4.0, 4.0, 4.0));
+
+        transform_component.set_scale(Vector3::new(0.5, 1.0, 2.0));
+        assert_eq!(transform_component.scale, Vector3::new(0.5, 1.0, 2.0));
+    }
+
+    // Both orientations turn vectors the same way, whatever their sign.
+    fn assert_same_rotation(a: Quaternion<f32>, b: Quaternion<f32>) {
+        assert!((a.dot(b).abs() - 1.0).abs() < 1e-5, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn interpolate_slerps_the_orientation() {
+        let a = TransformComponent::new();
+        let mut b = TransformComponent::new();
+        b.rotate(Vector3::unit_y(), Deg(90.0));
+
+        let midpoint = TransformComponent::interpolate(&a, &b, 0.5);
+        assert_same_rotation(midpoint.orientation(), Quaternion::from_angle_y(Deg(45.0)));
+        // Clamped to the endpoints.
+        assert_same_rotation(
+            TransformComponent::interpolate(&a, &b, 2.0).orientation(),
+            b.orientation(),
+        );
+    }
+
+    #[test]
+    fn update_accumulates_the_angular_velocity() {
+        let mut transform_component = TransformComponent::new();
+        transform_component.set_angular_velocity(Vector3::unit_y(), Deg(90.0));
+        for _ in 0..4 {
+            transform_component.update(&Duration::from_millis(250));
+        }
+        assert_same_rotation(
+            transform_component.orientation(),
+            Quaternion::from_angle_y(Deg(90.0)),
+        );
+
+        transform_component.update(&Duration::from_secs(3));
+        assert_same_rotation(
+            transform_component.orientation(),
+            Quaternion::from_angle_y(Deg(360.0)),
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use std::sync::RwLock;
+
+use lazy_static::lazy_static;
+
+lazy_static! {
+    pub static ref GLOBAL_UNIT_SCALE: RwLock<f32> = RwLock::new(DEFAULT_UNIT_SCALE);
+}
+
+// Meters per world unit; Quake maps are built assuming one unit is an inch.
+pub const DEFAULT_UNIT_SCALE: f32 = 0.0254;
+
+// Distance at which a sound with attenuation 1 fades out completely, matching
+// Quake's nominal clip distance of 1000 units.
+pub const SOUND_CLIP_DISTANCE: f32 = 1000.0 * DEFAULT_UNIT_SCALE;
+
+pub fn unit_scale() -> f32 {
+    *GLOBAL_UNIT_SCALE.read().unwrap()
+}
+
+pub fn set_unit_scale(unit_scale: f32) {
+    *GLOBAL_UNIT_SCALE.write().unwrap() = unit_scale;
+}
+
+// Quake's sv_gravity of 800 units/s², in m/s² at the current unit scale.
+pub fn gravity() -> f32 {
+    to_meters(800.0)
+}
+
+pub fn to_meters(units: f32) -> f32 {
+    units * unit_scale()
+}
+
+pub fn from_meters(meters: f32) -> f32 {
+    meters / unit_scale()
+}
+
+// Linear falloff used by the mixer, 1 at the listener and 0 past the clip
+// distance divided by the attenuation factor.
+pub fn sound_attenuation(distance: f32, attenuation: f32) -> f32 {
+    (1.0 - to_meters(distance) * attenuation / SOUND_CLIP_DISTANCE).max(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    // The tests take turns changing the global unit scale.
+    static UNIT_SCALE: Mutex<()> = Mutex::new(());
+
+    fn assert_near(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-4, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn doubling_the_unit_scale_halves_the_attenuation_distance() {
+        let _unit_scale = UNIT_SCALE.lock().unwrap();
+
+        set_unit_scale(DEFAULT_UNIT_SCALE);
+        assert_near(from_meters(SOUND_CLIP_DISTANCE), 1000.0);
+        assert_near(sound_attenuation(500.0, 1.0), 0.5);
+        assert_near(sound_attenuation(500.0, 2.0), 0.0);
+
+        set_unit_scale(DEFAULT_UNIT_SCALE * 2.0);
+        assert_near(from_meters(SOUND_CLIP_DISTANCE), 500.0);
+        assert_near(sound_attenuation(250.0, 1.0), 0.5);
+        assert_near(sound_attenuation(500.0, 1.0), 0.0);
+
+        set_unit_scale(DEFAULT_UNIT_SCALE);
+    }
+
+    #[test]
+    fn gravity_follows_the_unit_scale() {
+        let _unit_scale = UNIT_SCALE.lock().unwrap();
+
+        set_unit_scale(DEFAULT_UNIT_SCALE);
+        assert_near(gravity(), 20.32);
+
+        // Still 800 units/s², whatever a unit measures.
+        set_unit_scale(DEFAULT_UNIT_SCALE * 2.0);
+        assert_near(gravity(), 40.64);
+        assert_near(from_meters(gravity()), 800.0);
+
+        set_unit_scale(DEFAULT_UNIT_SCALE);
+    }
+}
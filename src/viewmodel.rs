@@ -0,0 +1,24 @@
+use std::f32::consts::FRAC_PI_2;
+
+use cgmath::Rad;
+
+// Tags the first person weapon model. Its transform is relative to the eye
+// and it is drawn last with its own vertical field of view over a cleared
+// depth buffer, so it never clips into the world.
+pub struct ViewModelComponent {
+    pub field_of_view: Rad<f32>,
+}
+
+impl ViewModelComponent {
+    pub fn new() -> Self {
+        Self {
+            field_of_view: Rad(FRAC_PI_2),
+        }
+    }
+}
+
+impl Default for ViewModelComponent {
+    fn default() -> Self {
+        Self::new()
+    }
+}
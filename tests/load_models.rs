@@ -0,0 +1,49 @@
+use std::env;
+
+use quake_rs::{
+    alias::{Keyframe, Mdl},
+    resource::{self, GLOBAL_RESOURCES},
+};
+
+// Set QUAKE_RS_TEST_PAK to the path of a PAK file to run this test.
+#[test]
+fn load_every_mdl() {
+    let path = match env::var("QUAKE_RS_TEST_PAK") {
+        Ok(path) => path,
+        Err(_) => {
+            eprintln!("QUAKE_RS_TEST_PAK not set, skipping");
+            return;
+        }
+    };
+    resource::init(path).unwrap();
+
+    let names = GLOBAL_RESOURCES
+        .read()
+        .unwrap()
+        .as_ref()
+        .unwrap()
+        .glob("progs/*.mdl");
+    assert!(!names.is_empty(), "no models found");
+
+    for name in names {
+        let mdl = Mdl::load(&name).unwrap_or_else(|err| panic!("{}: {}", name, err));
+        assert!(!mdl.keyframes.is_empty(), "{}: no keyframes", name);
+
+        let frame = match &mdl.keyframes[0] {
+            Keyframe::Static(kf) => &kf.0,
+            Keyframe::Animated(kf) => kf.subframes().next().unwrap().0,
+        };
+        let mut obj = Vec::new();
+        mdl.export_obj(frame, &mut obj)
+            .unwrap_or_else(|err| panic!("{}: {}", name, err));
+        let obj = String::from_utf8(obj).unwrap();
+        let count = |prefix| obj.lines().filter(|line| line.starts_with(prefix)).count();
+        assert_eq!(
+            count("v "),
+            mdl.num_triangles() * 3,
+            "{}: OBJ vertices",
+            name
+        );
+        assert_eq!(count("f "), mdl.num_triangles(), "{}: OBJ faces", name);
+    }
+}